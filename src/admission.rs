@@ -0,0 +1,180 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::io::Write;
+use std::net::{IpAddr, TcpStream};
+
+use strum_macros::Display;
+use uuid::Uuid;
+
+use crate::config::Config;
+use crate::connection_kind::ConnectionKind;
+use crate::error;
+use crate::message::Message;
+use crate::mqtt_handler::CONNACK_SERVER_UNAVAILABLE;
+use crate::mqtt_packet::MqttPacket;
+use crate::resp_reply::RespReply;
+use crate::stomp_frame::StompFrame;
+
+// The topic of frames reporting errors to native protocol clients.
+pub const ERROR_TOPIC: &str = "$error";
+
+const SERVER_BUSY: &str = "server busy";
+
+#[derive(Clone, Copy, Debug, Display)]
+#[strum(serialize_all = "snake_case")]
+pub enum Rejection {
+    Global,
+    Listener,
+    Ip,
+    Principal,
+}
+
+#[derive(Debug)]
+struct AdmittedConnection {
+    kind: ConnectionKind,
+    ip: IpAddr,
+    principal: Option<String>,
+}
+
+// Tracks the open connections, refusing new ones once a limit is reached.
+#[derive(Debug)]
+pub struct Admission {
+    max_connections: Option<usize>,
+    max_connections_per_listener: Option<usize>,
+    max_connections_per_ip: Option<usize>,
+    max_connections_per_principal: Option<usize>,
+    connections: HashMap<Uuid, AdmittedConnection>,
+    kind_to_count: HashMap<ConnectionKind, usize>,
+    ip_to_count: HashMap<IpAddr, usize>,
+    principal_to_count: HashMap<String, usize>,
+}
+
+impl Admission {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            max_connections: config.max_connections,
+            max_connections_per_listener: config.max_connections_per_listener,
+            max_connections_per_ip: config.max_connections_per_ip,
+            max_connections_per_principal: config.max_connections_per_principal,
+            connections: HashMap::new(),
+            kind_to_count: HashMap::new(),
+            ip_to_count: HashMap::new(),
+            principal_to_count: HashMap::new(),
+        }
+    }
+
+    pub fn admit(&mut self, id: Uuid, kind: ConnectionKind, ip: IpAddr) -> Result<(), Rejection> {
+        if Self::exceeds(self.max_connections, self.connections.len()) {
+            return Err(Rejection::Global);
+        }
+        if Self::exceeds(
+            self.max_connections_per_listener,
+            self.kind_to_count.get(&kind).copied().unwrap_or_default(),
+        ) {
+            return Err(Rejection::Listener);
+        }
+        if Self::exceeds(
+            self.max_connections_per_ip,
+            self.ip_to_count.get(&ip).copied().unwrap_or_default(),
+        ) {
+            return Err(Rejection::Ip);
+        }
+
+        *self.kind_to_count.entry(kind.clone()).or_default() += 1;
+        *self.ip_to_count.entry(ip).or_default() += 1;
+        self.connections.insert(
+            id,
+            AdmittedConnection {
+                kind,
+                ip,
+                principal: None,
+            },
+        );
+
+        Ok(())
+    }
+
+    pub fn authenticate(&mut self, id: Uuid, principal: String) -> Result<(), Rejection> {
+        let connection = match self.connections.get_mut(&id) {
+            Some(connection) => connection,
+            None => return Ok(()),
+        };
+
+        // A connection counts once towards the principal it authenticated as.
+        if connection.principal.as_ref() == Some(&principal) {
+            return Ok(());
+        }
+        if Self::exceeds(
+            self.max_connections_per_principal,
+            self.principal_to_count
+                .get(&principal)
+                .copied()
+                .unwrap_or_default(),
+        ) {
+            return Err(Rejection::Principal);
+        }
+
+        if let Some(previous) = connection.principal.replace(principal.clone()) {
+            Self::decrement(&mut self.principal_to_count, &previous);
+        }
+        *self.principal_to_count.entry(principal).or_default() += 1;
+
+        Ok(())
+    }
+
+    pub fn release(&mut self, id: Uuid) {
+        if let Some(connection) = self.connections.remove(&id) {
+            Self::decrement(&mut self.kind_to_count, &connection.kind);
+            Self::decrement(&mut self.ip_to_count, &connection.ip);
+            if let Some(principal) = connection.principal {
+                Self::decrement(&mut self.principal_to_count, &principal);
+            }
+        }
+    }
+
+    pub fn write_busy_reply(kind: &ConnectionKind, stream: &mut TcpStream) -> error::Result<()> {
+        // Answer in the client's protocol, as if it was its first request.
+        match kind {
+            ConnectionKind::Publisher | ConnectionKind::Subscriber => {
+                Message::new(ERROR_TOPIC.to_owned(), SERVER_BUSY.as_bytes().to_vec())
+                    .write(stream)?
+            }
+            ConnectionKind::Http => write!(
+                stream,
+                "HTTP/1.1 503 Service Unavailable\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                SERVER_BUSY.len(),
+                SERVER_BUSY
+            )?,
+            ConnectionKind::Mqtt => MqttPacket::Connack {
+                session_present: false,
+                return_code: CONNACK_SERVER_UNAVAILABLE,
+            }
+            .write(stream)?,
+            ConnectionKind::Nats => write!(stream, "-ERR '{}'\r\n", SERVER_BUSY)?,
+            ConnectionKind::Redis => {
+                RespReply::Error(format!("ERR {}", SERVER_BUSY)).write(stream)?
+            }
+            ConnectionKind::Stomp => StompFrame::new("ERROR")
+                .with_header("message", SERVER_BUSY)
+                .write(stream)?,
+            // The QUIC listener closes refused connections itself.
+            ConnectionKind::Quic => {}
+        }
+
+        Ok(())
+    }
+
+    fn exceeds(limit: Option<usize>, count: usize) -> bool {
+        limit.is_some_and(|limit| count >= limit)
+    }
+
+    fn decrement<K: Hash + Eq>(key_to_count: &mut HashMap<K, usize>, key: &K) {
+        // Forget keys that have no connections.
+        if let Some(count) = key_to_count.get_mut(key) {
+            *count -= 1;
+            if 0 == *count {
+                key_to_count.remove(key);
+            }
+        }
+    }
+}
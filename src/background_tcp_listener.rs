@@ -1,18 +1,21 @@
 use std::io;
 use std::net::{TcpListener, TcpStream};
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
 use std::thread::{self, JoinHandle};
 
 use crossbeam::channel::Sender;
+use polling::Poller;
 
 use crate::connection_kind::ConnectionKind;
 use crate::error;
 use crate::event::Event;
+use crate::shutdown::{self, ShutdownReceiver, ShutdownSender};
+
+const LISTENER_POLL_KEY: usize = 0;
 
 pub struct BackgroundTcpListener {
     listener_thread: Option<JoinHandle<()>>,
-    address: String,
-    terminate: Arc<Mutex<bool>>,
+    shutdown: ShutdownSender,
 }
 
 impl BackgroundTcpListener {
@@ -20,35 +23,40 @@ impl BackgroundTcpListener {
         address: String,
         connection_kind: ConnectionKind,
         event_sender: Sender<Event>,
-    ) -> Self {
-        let terminate = Arc::new(Mutex::new(false));
+    ) -> error::Result<Self> {
+        let poller = Arc::new(Poller::new()?);
+        let (shutdown, shutdown_receiver) = shutdown::signal(poller.clone());
 
-        Self {
+        Ok(Self {
             listener_thread: Some(Self::start_listener_thread(
-                address.clone(),
+                address,
                 connection_kind,
                 event_sender,
-                terminate.clone(),
+                poller,
+                shutdown_receiver,
             )),
-            address,
-            terminate,
-        }
+            shutdown,
+        })
     }
 
     fn start_listener_thread(
         address: String,
         connection_kind: ConnectionKind,
         event_sender: Sender<Event>,
-        terminate: Arc<Mutex<bool>>,
+        poller: Arc<Poller>,
+        shutdown: ShutdownReceiver,
     ) -> JoinHandle<()> {
-        thread::spawn(move || Self::listen(address, connection_kind, event_sender, terminate))
+        thread::spawn(move || {
+            Self::listen(address, connection_kind, event_sender, poller, shutdown)
+        })
     }
 
     fn listen(
         address: String,
         connection_kind: ConnectionKind,
         event_sender: Sender<Event>,
-        terminate: Arc<Mutex<bool>>,
+        poller: Arc<Poller>,
+        shutdown: ShutdownReceiver,
     ) {
         log::info!(
             "Listening for [{}] connections to: [{}]",
@@ -56,8 +64,9 @@ impl BackgroundTcpListener {
             address
         );
 
-        // Create the TCP listener.
-        let listener = match TcpListener::bind(address.clone()) {
+        // Create the TCP listener, accepting connections only once the poller
+        // reports them so that shutdown requests can wake the thread.
+        let listener = match Self::bind(&address, &poller) {
             Ok(listener) => listener,
             Err(e) => {
                 log::error!(
@@ -71,53 +80,77 @@ impl BackgroundTcpListener {
         };
 
         // Listen for connections.
-        for stream in listener.incoming() {
-            // Check if listening should terminate.
-            if *terminate.lock().unwrap() {
+        let mut poll_events: Vec<polling::Event> = Vec::new();
+        while !shutdown.is_requested() {
+            // Modify the poller's interest in the listener, which is reset
+            // after each event.
+            if let Err(e) = poller.modify(&listener, polling::Event::readable(LISTENER_POLL_KEY)) {
+                log::error!(
+                    "Failed to modify the poller's interest in listener [{}]: [{}]",
+                    address,
+                    e
+                );
                 break;
             }
 
-            // Send a Connection event.
-            Self::send_connection_event(&event_sender, connection_kind.clone(), stream);
+            // Wait for connections or a shutdown request.
+            poll_events.clear();
+            if let Err(e) = poller.wait(&mut poll_events, None) {
+                log::error!("Failed polling for connections to [{}]: [{}]", address, e);
+                continue;
+            }
+
+            // Accept all the pending connections.
+            loop {
+                match listener.accept() {
+                    Ok((stream, _)) => {
+                        Self::send_connection_event(&event_sender, connection_kind.clone(), stream)
+                    }
+                    Err(e) if io::ErrorKind::WouldBlock == e.kind() => break,
+                    Err(e) => {
+                        log::error!("Failed receiving connection: [{}]", e);
+                        break;
+                    }
+                }
+            }
         }
     }
 
+    fn bind(address: &str, poller: &Poller) -> error::Result<TcpListener> {
+        let listener = TcpListener::bind(address)?;
+        listener.set_nonblocking(true)?;
+        poller.add(&listener, polling::Event::readable(LISTENER_POLL_KEY))?;
+        Ok(listener)
+    }
+
     fn send_connection_event(
         event_sender: &Sender<Event>,
         connection_kind: ConnectionKind,
-        stream: io::Result<TcpStream>,
+        stream: TcpStream,
     ) {
-        // Ensure that the stream is valid before sending the Connection event.
-        match stream {
-            Ok(stream) => {
-                if let Err(e) =
-                    event_sender.send(Event::Connection(connection_kind.clone(), stream))
-                {
-                    log::error!(
-                        "Failed sending [{}] Connection event: [{}]",
-                        connection_kind,
-                        e,
-                    );
-                }
-            }
-            Err(e) => log::error!("Failed receiving connection: [{}]", e),
+        // The handlers expect blocking streams, which some systems don't
+        // default to for connections accepted by a non-blocking listener.
+        if let Err(e) = stream.set_nonblocking(false) {
+            log::error!("Failed receiving connection: [{}]", e);
+            return;
         }
-    }
 
-    fn unblock_listener_thread(&self) -> error::Result<()> {
-        TcpStream::connect(&self.address)?;
-        Ok(())
+        if let Err(e) = event_sender.send(Event::Connection(connection_kind.clone(), stream)) {
+            log::error!(
+                "Failed sending [{}] Connection event: [{}]",
+                connection_kind,
+                e,
+            );
+        }
     }
 }
 
 impl Drop for BackgroundTcpListener {
     fn drop(&mut self) {
         if let Some(thread) = self.listener_thread.take() {
-            // Indicate the listener thread that it should terminate.
-            *self.terminate.lock().unwrap() = true;
-
-            // Unblock the listener thread.
-            self.unblock_listener_thread().unwrap();
+            // Indicate the listener thread that it should terminate, waking
+            // it up.
+            self.shutdown.request();
 
             // Join the listener thread.
             thread.join().unwrap();
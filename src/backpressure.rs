@@ -0,0 +1,90 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+use crate::topic_filter::TopicFilter;
+
+// Lossless topics never drop or endlessly buffer messages. Once a subscriber
+// of such a topic has a queue at the high-water mark, the topic is congested
+// and the publishers that published to it are paused. The topic stays
+// congested until all of its subscribers drained their queues down to the
+// low-water mark.
+pub struct Backpressure {
+    lossless_filters: Vec<TopicFilter>,
+    high_water_mark: usize,
+    low_water_mark: usize,
+    // The paused publishers of each congested topic, woken once it drained.
+    congested_topics: Mutex<HashMap<String, Vec<DrainWaker>>>,
+}
+
+// Wakes a paused publisher, whichever way its handler waits.
+pub type DrainWaker = Arc<dyn Fn() + Send + Sync>;
+
+impl Backpressure {
+    pub fn new(lossless_patterns: &[String], high_water_mark: usize) -> Self {
+        Self {
+            lossless_filters: lossless_patterns
+                .iter()
+                .map(|pattern| TopicFilter::glob(pattern))
+                .collect(),
+            high_water_mark,
+            low_water_mark: high_water_mark / 2,
+            congested_topics: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn is_lossless(&self, topic: &str) -> bool {
+        self.lossless_filters
+            .iter()
+            .any(|filter| filter.matches(topic))
+    }
+
+    // Returns whether any of the topics is congested, in which case the
+    // waker is called once one of them drained.
+    pub fn wait_for_drain(&self, topics: &HashSet<String>, waker: &DrainWaker) -> bool {
+        let mut congested_topics = self.congested_topics.lock().unwrap();
+
+        let mut congested = false;
+        for topic in topics.iter() {
+            if let Some(wakers) = congested_topics.get_mut(topic) {
+                if !wakers.iter().any(|other| Arc::ptr_eq(other, waker)) {
+                    wakers.push(waker.clone());
+                }
+                congested = true;
+            }
+        }
+
+        congested
+    }
+
+    // Returns whether the topic is congested.
+    pub fn update(&self, topic: &str, longest_queue_len: usize) -> bool {
+        let mut congested_topics = self.congested_topics.lock().unwrap();
+
+        if longest_queue_len >= self.high_water_mark {
+            if !congested_topics.contains_key(topic) {
+                log::warn!(
+                    "Lossless topic [{}] is congested, pausing its publishers",
+                    topic
+                );
+                congested_topics.insert(topic.to_owned(), Vec::new());
+            }
+            return true;
+        }
+
+        if longest_queue_len <= self.low_water_mark {
+            if let Some(wakers) = congested_topics.remove(topic) {
+                log::info!(
+                    "Lossless topic [{}] drained, resuming its publishers",
+                    topic
+                );
+                drop(congested_topics);
+                for waker in wakers.iter() {
+                    waker();
+                }
+            }
+            return false;
+        }
+
+        congested_topics.contains_key(topic)
+    }
+}
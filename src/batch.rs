@@ -0,0 +1,42 @@
+use std::io::Cursor;
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::error::{self, Error};
+use crate::message::Message;
+
+// The topic of frames carrying many messages in their data: the number of
+// messages followed by the messages themselves.
+pub const BATCH_TOPIC: &str = "$batch";
+
+pub fn encode_batch(messages: &[Message]) -> error::Result<Message> {
+    let mut data: Vec<u8> = Vec::new();
+    data.write_u32::<BigEndian>(messages.len() as u32)?;
+    for message in messages.iter() {
+        message.write(&mut data)?;
+    }
+
+    Ok(Message::new(BATCH_TOPIC.to_owned(), data))
+}
+
+pub fn decode_batch(batch: &Message) -> error::Result<Vec<Message>> {
+    let mut reader = Cursor::new(&batch.data[..]);
+    let messages_number = reader.read_u32::<BigEndian>()?;
+
+    // Don't trust the number of messages for the allocation, a message takes
+    // at least its three length prefixes.
+    let mut messages: Vec<Message> =
+        Vec::with_capacity((messages_number as usize).min(batch.data.len() / 12));
+    for _ in 0..messages_number {
+        messages.push(Message::read(&mut reader)?);
+    }
+
+    if reader.position() != batch.data.len() as u64 {
+        return Err(Error::MalformedBatch(format!(
+            "{} trailing bytes",
+            batch.data.len() as u64 - reader.position()
+        )));
+    }
+
+    Ok(messages)
+}
@@ -6,11 +6,24 @@ use clap::Parser;
 use rand::Rng;
 
 use pubsub::Message;
+use pubsub::{compress_message, encode_batch, Codec};
 
 #[derive(Parser)]
 #[command(author = "ydolev", version = "1.0.0", about = "A pubsub publisher client written in Rust", long_about = None)]
 struct Cli {
     port: u16,
+
+    /// Publish all the messages at once, as a single batch.
+    #[arg(long)]
+    batch: bool,
+
+    /// Compress the messages with "lz4" or "zstd".
+    #[arg(long)]
+    compression: Option<Codec>,
+
+    /// Add a checksum to each frame.
+    #[arg(long)]
+    checksum: bool,
 }
 
 fn main() -> anyhow::Result<()> {
@@ -28,11 +41,12 @@ fn main() -> anyhow::Result<()> {
     let mut rng = rand::thread_rng();
 
     // Send 10 messages to the different topics.
-    let topics = vec!["hello", "bye", "test"];
+    let topics = ["hello", "bye", "test"];
+    let mut batch: Vec<Message> = Vec::new();
     for message_number in 0..10 {
         // Send a message to a random topic.
         let topic_index: usize = rng.gen_range(0..topics.len());
-        let message = Message::new(
+        let mut message = Message::new(
             topics[topic_index].to_owned(),
             format!("message #{}", message_number).as_bytes().to_vec(),
         );
@@ -41,6 +55,16 @@ fn main() -> anyhow::Result<()> {
             message_number,
             message.topic,
         );
+        if let Some(codec) = cli.compression {
+            compress_message(&mut message, codec, 0)?;
+        }
+        if cli.checksum {
+            message.add_checksum();
+        }
+        if cli.batch {
+            batch.push(message);
+            continue;
+        }
         message.write(&mut stream)?;
 
         // Sleep for a random about of time between 0ms and 2000ms.
@@ -48,5 +72,13 @@ fn main() -> anyhow::Result<()> {
         thread::sleep(Duration::from_millis(sleep_time_ms));
     }
 
+    if cli.batch {
+        let mut batch = encode_batch(&batch)?;
+        if cli.checksum {
+            batch.add_checksum();
+        }
+        batch.write(&mut stream)?;
+    }
+
     Ok(())
 }
@@ -0,0 +1,260 @@
+use std::io::Cursor;
+use std::net::ToSocketAddrs;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use clap::Parser;
+use quinn::crypto::rustls::QuicClientConfig;
+use quinn::{Connection, Endpoint, RecvStream, SendStream};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{DigitallySignedStruct, SignatureScheme};
+use uuid::Uuid;
+
+use pubsub::Message;
+use pubsub::SubscriptionRequest;
+
+const ALPN_PROTOCOL: &[u8] = b"pubsub";
+const KEEP_ALIVE_INTERVAL_MS: u64 = 3000;
+const RECONNECT_DELAY_MS: u64 = 1000;
+
+#[derive(Parser)]
+#[command(author = "ydolev", version = "1.0.0", about = "A pubsub QUIC subscriber client written in Rust", long_about = None)]
+struct Cli {
+    port: u16,
+    topics: Vec<String>,
+
+    #[arg(long, default_value = "localhost")]
+    host: String,
+
+    #[arg(long)]
+    certificate: Option<PathBuf>,
+}
+
+#[derive(Debug)]
+struct SkipServerVerification(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for SkipServerVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        certificate: &CertificateDer<'_>,
+        signature: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            certificate,
+            signature,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        certificate: &CertificateDer<'_>,
+        signature: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            certificate,
+            signature,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    // Initialize the logger according to the environment.
+    env_logger::init_from_env(env_logger::Env::new().default_filter_or("debug"));
+
+    // Parse the command line arguments.
+    let cli = Cli::parse();
+
+    // Create the QUIC endpoint.
+    let mut endpoint = Endpoint::client(([0, 0, 0, 0], 0).into())?;
+    endpoint.set_default_client_config(client_config(cli.certificate)?);
+
+    let address = (cli.host.as_str(), cli.port)
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("failed resolving host: {}", cli.host))?;
+
+    // Keep the session across reconnections, so that the server doesn't need
+    // the subscription request again.
+    let mut session_id = Uuid::nil();
+    loop {
+        log::info!("Connecting to the pubsub server on: ({})", address);
+        if let Err(e) =
+            run_session(&endpoint, address, &cli.host, &cli.topics, &mut session_id).await
+        {
+            log::warn!("Connection lost: [{}]", e);
+        }
+
+        tokio::time::sleep(Duration::from_millis(RECONNECT_DELAY_MS)).await;
+    }
+}
+
+fn client_config(certificate: Option<PathBuf>) -> anyhow::Result<quinn::ClientConfig> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder = rustls::ClientConfig::builder_with_provider(provider.clone())
+        .with_protocol_versions(&[&rustls::version::TLS13])?;
+
+    // Trust the given certificate, or skip the verification of the server.
+    let mut tls_config = match certificate {
+        Some(certificate) => {
+            let mut root_store = rustls::RootCertStore::empty();
+            for certificate in CertificateDer::pem_file_iter(certificate)? {
+                root_store.add(certificate?)?;
+            }
+            builder
+                .with_root_certificates(root_store)
+                .with_no_client_auth()
+        }
+        None => {
+            log::warn!("No certificate given, skipping the server's verification");
+            builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(SkipServerVerification(provider)))
+                .with_no_client_auth()
+        }
+    };
+    tls_config.alpn_protocols = vec![ALPN_PROTOCOL.to_vec()];
+    tls_config.enable_early_data = true;
+
+    // Keep the connection alive while no messages are published.
+    let mut client_config =
+        quinn::ClientConfig::new(Arc::new(QuicClientConfig::try_from(tls_config)?));
+    let mut transport_config = quinn::TransportConfig::default();
+    transport_config.keep_alive_interval(Some(Duration::from_millis(KEEP_ALIVE_INTERVAL_MS)));
+    client_config.transport_config(Arc::new(transport_config));
+
+    Ok(client_config)
+}
+
+async fn run_session(
+    endpoint: &Endpoint,
+    address: std::net::SocketAddr,
+    host: &str,
+    topics: &[String],
+    session_id: &mut Uuid,
+) -> anyhow::Result<()> {
+    // Send the control stream as 0-RTT data when resuming a TLS session, so
+    // that reconnecting takes a single round trip.
+    let connecting = endpoint.connect(address, host)?;
+    let (connection, zero_rtt_accepted) = match connecting.into_0rtt() {
+        Ok((connection, zero_rtt_accepted)) => (connection, Some(zero_rtt_accepted)),
+        Err(connecting) => (connecting.await?, None),
+    };
+
+    // Resume the previous session, or start a new one, again if the server
+    // didn't accept the 0-RTT data.
+    let (mut control_sender, _control_receiver, id) =
+        match open_control_stream(&connection, session_id).await {
+            Ok(control) => control,
+            Err(e) => {
+                let zero_rtt_rejected = match zero_rtt_accepted {
+                    Some(zero_rtt_accepted) => !zero_rtt_accepted.await,
+                    None => false,
+                };
+                if !zero_rtt_rejected {
+                    return Err(e);
+                }
+                log::info!("0-RTT data rejected, resending the control stream");
+                open_control_stream(&connection, session_id).await?
+            }
+        };
+
+    if id == *session_id {
+        log::info!("Resumed session: [{}]", id);
+    } else {
+        log::info!("Started session: [{}]", id);
+        *session_id = id;
+
+        // Send the subscription request.
+        let mut frame: Vec<u8> = Vec::new();
+        SubscriptionRequest::new(topics.to_vec()).write(&mut frame)?;
+        write_frame(&mut control_sender, &frame).await?;
+    }
+
+    receive_messages(&connection).await
+}
+
+async fn open_control_stream(
+    connection: &Connection,
+    session_id: &Uuid,
+) -> anyhow::Result<(SendStream, RecvStream, Uuid)> {
+    let (mut control_sender, mut control_receiver) = connection.open_bi().await?;
+    control_sender.write_all(session_id.as_bytes()).await?;
+
+    let mut id = [0; 16];
+    control_receiver.read_exact(&mut id).await?;
+
+    Ok((control_sender, control_receiver, Uuid::from_bytes(id)))
+}
+
+async fn receive_messages(connection: &Connection) -> anyhow::Result<()> {
+    // The server opens a stream for each topic.
+    loop {
+        let mut stream = connection.accept_uni().await?;
+        tokio::spawn(async move {
+            if let Err(e) = receive_topic_messages(&mut stream).await {
+                log::warn!("Topic stream failed: [{}]", e);
+            }
+        });
+    }
+}
+
+async fn receive_topic_messages(stream: &mut RecvStream) -> anyhow::Result<()> {
+    let topic = String::from_utf8(read_frame(stream).await?)?;
+    log::info!("Receiving messages from topic [{}]", topic);
+
+    // Receive message from publishers.
+    loop {
+        let message = Message::read(&mut Cursor::new(read_frame(stream).await?))?;
+        let data = String::from_utf8(message.data.to_vec())?;
+
+        log::info!(
+            "Received message from topic [{}]: [{}]",
+            message.topic,
+            data
+        );
+    }
+}
+
+async fn read_frame(stream: &mut RecvStream) -> anyhow::Result<Vec<u8>> {
+    let mut size = [0; 4];
+    stream.read_exact(&mut size).await?;
+
+    let mut frame: Vec<u8> = vec![0; u32::from_be_bytes(size) as usize];
+    stream.read_exact(&mut frame).await?;
+
+    Ok(frame)
+}
+
+async fn write_frame(stream: &mut SendStream, frame: &[u8]) -> anyhow::Result<()> {
+    stream
+        .write_all(&(frame.len() as u32).to_be_bytes())
+        .await?;
+    stream.write_all(frame).await?;
+    Ok(())
+}
@@ -1,6 +1,9 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
 use clap::Parser;
 
-use pubsub::PubSub;
+use pubsub::{Config, DrainPolicy, PubSub, RateLimit, RateLimitPolicy};
 
 #[derive(Parser)]
 #[command(author = "ydolev", version = "1.0.0", about = "A pubsub server written in Rust", long_about = None)]
@@ -10,6 +13,140 @@ struct Cli {
 
     #[arg(long)]
     sub_port: u16,
+
+    #[arg(long)]
+    http_port: Option<u16>,
+
+    #[arg(long)]
+    mqtt_port: Option<u16>,
+
+    #[arg(long)]
+    nats_port: Option<u16>,
+
+    #[arg(long)]
+    redis_port: Option<u16>,
+
+    #[arg(long)]
+    stomp_port: Option<u16>,
+
+    #[arg(long)]
+    quic_port: Option<u16>,
+
+    #[arg(long, requires = "quic_private_key")]
+    quic_certificate: Option<PathBuf>,
+
+    #[arg(long, requires = "quic_certificate")]
+    quic_private_key: Option<PathBuf>,
+
+    #[arg(long, default_value_t = 1000)]
+    heartbeat_min_interval_ms: u64,
+
+    #[arg(long, default_value_t = 3)]
+    heartbeat_max_missed: u32,
+
+    #[arg(long, default_value_t = 5000)]
+    handshake_timeout_ms: u64,
+
+    #[arg(long, default_value_t = 10000)]
+    subscription_timeout_ms: u64,
+
+    #[arg(long, default_value_t = 16)]
+    max_pending_handshakes_per_ip: usize,
+
+    #[arg(long)]
+    max_connections: Option<usize>,
+
+    #[arg(long)]
+    max_connections_per_listener: Option<usize>,
+
+    #[arg(long)]
+    max_connections_per_ip: Option<usize>,
+
+    #[arg(long)]
+    max_connections_per_principal: Option<usize>,
+
+    #[arg(long, default_value = "throttle")]
+    rate_limit_policy: RateLimitPolicy,
+
+    /// IDENTITY=MESSAGES:BYTES, where IDENTITY is the publisher's
+    /// authenticated principal, the IP of an anonymous publisher, or "*".
+    #[arg(long, value_parser = parse_rate_limit)]
+    publisher_rate_limit: Vec<(String, RateLimit)>,
+
+    /// PATTERN=MESSAGES:BYTES, where PATTERN is a glob topic pattern.
+    #[arg(long, value_parser = parse_rate_limit)]
+    topic_rate_limit: Vec<(String, RateLimit)>,
+
+    /// A glob pattern of topics whose publishers are paused by slow subscribers.
+    #[arg(long)]
+    lossless_topic: Vec<String>,
+
+    #[arg(long, default_value_t = 1000)]
+    subscriber_queue_high_water_mark: usize,
+
+    /// PATTERN=MILLISECONDS, the TTL of messages to matching topics that
+    /// don't have one of their own.
+    #[arg(long, value_parser = parse_topic_ttl)]
+    topic_ttl: Vec<(String, Duration)>,
+
+    /// A glob pattern of topics whose undeliverable messages are republished
+    /// to "$dlq.<topic>".
+    #[arg(long)]
+    dead_letter_topic: Vec<String>,
+
+    /// "strict", "weighted" or "weighted:HIGH:NORMAL:LOW".
+    #[arg(long, default_value = "strict")]
+    priority_drain_policy: DrainPolicy,
+
+    /// How long idempotency keys are remembered, 0 disables deduplication.
+    #[arg(long, default_value_t = 60000)]
+    dedup_window_ms: u64,
+
+    /// The size from which messages are compressed for subscribers that
+    /// negotiated compression.
+    #[arg(long, default_value_t = 1024)]
+    compression_threshold: usize,
+
+    /// Add a checksum to the messages written to native subscribers.
+    #[arg(long)]
+    frame_checksums: bool,
+
+    /// The number of threads routing messages, each owning the topics that
+    /// hash to it.
+    #[arg(long, default_value_t = 1)]
+    router_shards: usize,
+
+    /// How far ahead messages may be scheduled.
+    #[arg(long, default_value_t = 7 * 24 * 60 * 60 * 1000)]
+    max_schedule_delay_ms: u64,
+
+    /// The total size of the scheduled messages held by the server.
+    #[arg(long, default_value_t = 256 * 1024 * 1024)]
+    max_scheduled_bytes: usize,
+
+    /// The number of messages a transaction may hold until it's committed.
+    #[arg(long, default_value_t = 10000)]
+    max_transaction_messages: usize,
+
+    /// The total size of the messages a transaction may hold until it's
+    /// committed.
+    #[arg(long, default_value_t = 64 * 1024 * 1024)]
+    max_transaction_bytes: usize,
+}
+
+fn parse_rate_limit(argument: &str) -> Result<(String, RateLimit), String> {
+    let (key, limit) = argument
+        .rsplit_once('=')
+        .ok_or_else(|| format!("expected KEY=MESSAGES:BYTES, got: {}", argument))?;
+    Ok((key.to_owned(), limit.parse()?))
+}
+
+fn parse_topic_ttl(argument: &str) -> Result<(String, Duration), String> {
+    let (pattern, ttl) = argument
+        .rsplit_once('=')
+        .ok_or_else(|| format!("expected PATTERN=MILLISECONDS, got: {}", argument))?;
+    let ttl = ttl.parse().map_err(|_| format!("invalid TTL: {}", ttl))?;
+    Ok((pattern.to_owned(), Duration::from_millis(ttl)))
 }
 
 fn main() -> anyhow::Result<()> {
@@ -20,7 +157,42 @@ fn main() -> anyhow::Result<()> {
     let cli = Cli::parse();
 
     // Initial and run the pubsub system.
-    let mut pub_sub = PubSub::new(cli.pub_port, cli.sub_port)?;
+    let mut config = Config::new(cli.pub_port, cli.sub_port);
+    config.http_port = cli.http_port;
+    config.mqtt_port = cli.mqtt_port;
+    config.nats_port = cli.nats_port;
+    config.redis_port = cli.redis_port;
+    config.stomp_port = cli.stomp_port;
+    config.quic_port = cli.quic_port;
+    config.quic_certificate_path = cli.quic_certificate;
+    config.quic_private_key_path = cli.quic_private_key;
+    config.heartbeat_min_interval_ms = cli.heartbeat_min_interval_ms;
+    config.heartbeat_max_missed = cli.heartbeat_max_missed;
+    config.handshake_timeout_ms = cli.handshake_timeout_ms;
+    config.subscription_timeout_ms = cli.subscription_timeout_ms;
+    config.max_pending_handshakes_per_ip = cli.max_pending_handshakes_per_ip;
+    config.max_connections = cli.max_connections;
+    config.max_connections_per_listener = cli.max_connections_per_listener;
+    config.max_connections_per_ip = cli.max_connections_per_ip;
+    config.max_connections_per_principal = cli.max_connections_per_principal;
+    config.rate_limit_policy = cli.rate_limit_policy;
+    config.publisher_rate_limits = cli.publisher_rate_limit;
+    config.topic_rate_limits = cli.topic_rate_limit;
+    config.lossless_topics = cli.lossless_topic;
+    config.subscriber_queue_high_water_mark = cli.subscriber_queue_high_water_mark;
+    config.topic_ttls = cli.topic_ttl;
+    config.dead_letter_topics = cli.dead_letter_topic;
+    config.priority_drain_policy = cli.priority_drain_policy;
+    config.dedup_window_ms = cli.dedup_window_ms;
+    config.compression_threshold = cli.compression_threshold;
+    config.frame_checksums = cli.frame_checksums;
+    config.router_shards = cli.router_shards;
+    config.max_schedule_delay_ms = cli.max_schedule_delay_ms;
+    config.max_scheduled_bytes = cli.max_scheduled_bytes;
+    config.max_transaction_messages = cli.max_transaction_messages;
+    config.max_transaction_bytes = cli.max_transaction_bytes;
+
+    let mut pub_sub = PubSub::new(config)?;
     pub_sub.process_events()?;
 
     Ok(())
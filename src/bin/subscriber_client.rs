@@ -1,15 +1,32 @@
+use std::collections::HashMap;
 use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
 
 use clap::Parser;
 
 use pubsub::Message;
+use pubsub::{decompress_message, CODECS_HEADER, CODEC_HEADER, COMPRESSION_TOPIC};
 use pubsub::SubscriptionRequest;
+use pubsub::{CREDIT_TOPIC, MESSAGES_HEADER};
+use pubsub::{HEARTBEAT_TOPIC, INTERVAL_HEADER, PING_TOPIC, PONG_TOPIC};
 
 #[derive(Parser)]
 #[command(author = "ydolev", version = "1.0.0", about = "A pubsub subscriber client written in Rust", long_about = None)]
 struct Cli {
     port: u16,
     topics: Vec<String>,
+
+    #[arg(long)]
+    heartbeat_ms: Option<u64>,
+
+    #[arg(long)]
+    credit: Option<u64>,
+
+    /// The codecs to accept compressed messages with, e.g. "zstd,lz4".
+    #[arg(long)]
+    compression: Option<String>,
 }
 
 fn main() -> anyhow::Result<()> {
@@ -27,15 +44,87 @@ fn main() -> anyhow::Result<()> {
     let subscription_request = SubscriptionRequest::new(cli.topics);
     subscription_request.write(&mut stream)?;
 
+    // Frames are written by both the receiving loop and the PING thread.
+    let writer = Arc::new(Mutex::new(stream.try_clone()?));
+
+    // Negotiate compression.
+    if let Some(codecs) = cli.compression {
+        let headers = HashMap::from([(CODECS_HEADER.to_owned(), codecs)]);
+        Message::with_headers(COMPRESSION_TOPIC.to_owned(), headers, Vec::new())
+            .write(&mut *writer.lock().unwrap())?;
+    }
+
+    // Grant the initial credit, if pulling messages.
+    if let Some(credit) = cli.credit {
+        write_credit(&writer, credit)?;
+    }
+
+    // Negotiate heartbeats, and keep sending PINGs in the background.
+    if let Some(heartbeat_ms) = cli.heartbeat_ms {
+        let headers = HashMap::from([(INTERVAL_HEADER.to_owned(), heartbeat_ms.to_string())]);
+        Message::with_headers(HEARTBEAT_TOPIC.to_owned(), headers, Vec::new())
+            .write(&mut *writer.lock().unwrap())?;
+
+        let ping_writer = writer.clone();
+        thread::spawn(move || loop {
+            thread::sleep(Duration::from_millis(heartbeat_ms));
+            let ping = Message::new(PING_TOPIC.to_owned(), Vec::new());
+            if let Err(e) = ping.write(&mut *ping_writer.lock().unwrap()) {
+                log::error!("Failed sending PING: [{}]", e);
+                break;
+            }
+        });
+    }
+
     // Receive message from publishers.
     loop {
-        let message = Message::read(&mut stream)?;
-        let data = String::from_utf8(message.data)?;
+        let mut message = Message::read(&mut stream)?;
+        match &*message.topic {
+            COMPRESSION_TOPIC => {
+                log::info!(
+                    "Negotiated compression codec: [{}]",
+                    message
+                        .headers
+                        .get(CODEC_HEADER)
+                        .cloned()
+                        .unwrap_or_default()
+                );
+                continue;
+            }
+            HEARTBEAT_TOPIC => {
+                log::info!(
+                    "Negotiated heartbeat interval: [{}ms]",
+                    message
+                        .headers
+                        .get(INTERVAL_HEADER)
+                        .cloned()
+                        .unwrap_or_default()
+                );
+                continue;
+            }
+            PONG_TOPIC => continue,
+            _ => {}
+        }
+
+        decompress_message(&mut message)?;
+        let data = String::from_utf8(message.data.to_vec())?;
 
         log::info!(
             "Received message from topic [{}]: [{}]",
             message.topic,
             data
         );
+
+        // Pull the next message.
+        if cli.credit.is_some() {
+            write_credit(&writer, 1)?;
+        }
     }
 }
+
+fn write_credit(writer: &Mutex<TcpStream>, messages: u64) -> anyhow::Result<()> {
+    let headers = HashMap::from([(MESSAGES_HEADER.to_owned(), messages.to_string())]);
+    Message::with_headers(CREDIT_TOPIC.to_owned(), headers, Vec::new())
+        .write(&mut *writer.lock().unwrap())?;
+    Ok(())
+}
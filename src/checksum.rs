@@ -0,0 +1,37 @@
+use std::io::{Read, Write};
+
+use crate::error;
+use crate::message::Message;
+
+pub const CHECKSUMS_TOPIC: &str = "$checksums";
+
+// Checksums are verified whenever a frame carries one, but a frame that lost
+// its checksum header on the way would still be accepted. Peers that want
+// every frame verified send a CHECKSUMS frame, after which the broker refuses
+// all frames of the connection that come without a checksum.
+#[derive(Debug, Default)]
+pub struct ChecksumPolicy {
+    required: bool,
+}
+
+impl ChecksumPolicy {
+    pub fn read(&self, reader: &mut impl Read) -> error::Result<Message> {
+        Message::read_checked(reader, self.required)
+    }
+
+    pub fn handle_control_message(
+        &mut self,
+        message: &Message,
+        writer: &mut impl Write,
+    ) -> error::Result<bool> {
+        if CHECKSUMS_TOPIC != &*message.topic {
+            return Ok(false);
+        }
+
+        // Confirm the requirement, which applies from the next frame on.
+        self.required = true;
+        Message::new(CHECKSUMS_TOPIC.to_owned(), Vec::new()).write(writer)?;
+
+        Ok(true)
+    }
+}
@@ -0,0 +1,333 @@
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::str::FromStr;
+use std::sync::{Arc, OnceLock};
+
+use bytes::Bytes;
+
+use crate::error::{self, Error};
+use crate::message::Message;
+
+pub const COMPRESSION_TOPIC: &str = "$compression";
+// The codecs the peer accepts, comma separated in order of preference.
+pub const CODECS_HEADER: &str = "codecs";
+// The codec the broker chose, or "none".
+pub const CODEC_HEADER: &str = "codec";
+// The codec a message's data is compressed with. Reserved, so that it's not
+// mistaken for the content encoding of a protocol's payload.
+pub const ENCODING_HEADER: &str = "$encoding";
+
+// Compressed data may not inflate beyond this size.
+const MAX_DECOMPRESSED_SIZE: usize = 64 * 1024 * 1024;
+const ZSTD_LEVEL: i32 = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Codec {
+    Lz4,
+    Zstd,
+}
+
+const CODECS_NUMBER: usize = 2;
+
+impl Codec {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Lz4 => "lz4",
+            Self::Zstd => "zstd",
+        }
+    }
+
+    fn compress(self, data: &[u8]) -> error::Result<Vec<u8>> {
+        match self {
+            Self::Lz4 => Ok(lz4_flex::compress_prepend_size(data)),
+            Self::Zstd => Ok(zstd::bulk::compress(data, ZSTD_LEVEL)?),
+        }
+    }
+
+    fn decompress(self, data: &[u8]) -> error::Result<Vec<u8>> {
+        match self {
+            Self::Lz4 => {
+                // Check the prepended size before allocating for it.
+                let size = data
+                    .get(..4)
+                    .map(|size| u32::from_le_bytes([size[0], size[1], size[2], size[3]]))
+                    .ok_or_else(|| Error::MalformedCompression("missing lz4 size".to_owned()))?;
+                if size as usize > MAX_DECOMPRESSED_SIZE {
+                    return Err(Error::MalformedCompression(format!(
+                        "lz4 data inflates to {} bytes",
+                        size
+                    )));
+                }
+                lz4_flex::decompress_size_prepended(data)
+                    .map_err(|e| Error::MalformedCompression(e.to_string()))
+            }
+            Self::Zstd => {
+                let mut decompressed: Vec<u8> = Vec::new();
+                zstd::stream::read::Decoder::new(data)?
+                    .take(MAX_DECOMPRESSED_SIZE as u64 + 1)
+                    .read_to_end(&mut decompressed)
+                    .map_err(|e| Error::MalformedCompression(e.to_string()))?;
+                if decompressed.len() > MAX_DECOMPRESSED_SIZE {
+                    return Err(Error::MalformedCompression(
+                        "zstd data inflates beyond the limit".to_owned(),
+                    ));
+                }
+                Ok(decompressed)
+            }
+        }
+    }
+}
+
+impl FromStr for Codec {
+    type Err = Error;
+
+    fn from_str(codec: &str) -> Result<Self, Self::Err> {
+        match codec {
+            "lz4" => Ok(Self::Lz4),
+            "zstd" => Ok(Self::Zstd),
+            _ => Err(Error::UnsupportedCodec(codec.to_owned())),
+        }
+    }
+}
+
+// Compresses the message's data in place, unless it's below the threshold or
+// wouldn't get any smaller.
+pub fn compress_message(
+    message: &mut Message,
+    codec: Codec,
+    threshold: usize,
+) -> error::Result<()> {
+    if message.data.len() < threshold || message.headers.contains_key(ENCODING_HEADER) {
+        return Ok(());
+    }
+
+    let compressed = codec.compress(&message.data)?;
+    if compressed.len() < message.data.len() {
+        message.data = compressed.into();
+        message
+            .headers_mut()
+            .insert(ENCODING_HEADER.to_owned(), codec.name().to_owned());
+    }
+
+    Ok(())
+}
+
+// The compressed data of a message, shared by its copies, so that fanning a
+// message out compresses it at most once per codec.
+#[derive(Clone, Debug, Default)]
+pub struct CompressedData {
+    forms: Arc<[OnceLock<CompressedForm>; CODECS_NUMBER]>,
+}
+
+#[derive(Debug)]
+struct CompressedForm {
+    // The data that was compressed, since a copy's data may be replaced.
+    source: Bytes,
+    // None if compressing didn't make the data any smaller.
+    compressed: Option<Bytes>,
+}
+
+impl CompressedForm {
+    fn new(source: &Bytes, codec: Codec) -> error::Result<Self> {
+        let compressed = codec.compress(source)?;
+        Ok(Self {
+            source: source.clone(),
+            compressed: (compressed.len() < source.len()).then(|| compressed.into()),
+        })
+    }
+
+    fn is_of(&self, data: &Bytes) -> bool {
+        self.source.as_ptr() == data.as_ptr() && self.source.len() == data.len()
+    }
+}
+
+// Compresses the message's data in place like compress_message, reusing the
+// data compressed for another copy of the message.
+fn compress_shared_message(
+    message: &mut Message,
+    codec: Codec,
+    threshold: usize,
+) -> error::Result<()> {
+    if message.data.len() < threshold || message.headers.contains_key(ENCODING_HEADER) {
+        return Ok(());
+    }
+
+    let slot = &message.compressed_data.forms[codec as usize];
+    let compressed = match slot.get() {
+        Some(form) if form.is_of(&message.data) => form.compressed.clone(),
+        Some(_) => CompressedForm::new(&message.data, codec)?.compressed,
+        None => {
+            let form = CompressedForm::new(&message.data, codec)?;
+            let compressed = form.compressed.clone();
+            // Another copy may have been compressed meanwhile, to the same.
+            let _ = slot.set(form);
+            compressed
+        }
+    };
+
+    if let Some(compressed) = compressed {
+        message.data = compressed;
+        message
+            .headers_mut()
+            .insert(ENCODING_HEADER.to_owned(), codec.name().to_owned());
+    }
+
+    Ok(())
+}
+
+// Restores the message's data in place, if it's compressed.
+pub fn decompress_message(message: &mut Message) -> error::Result<()> {
+    if let Some(codec) = message.headers.get(ENCODING_HEADER) {
+        let codec: Codec = codec.parse()?;
+        message.data = codec.decompress(&message.data)?.into();
+        message.headers_mut().remove(ENCODING_HEADER);
+    }
+
+    Ok(())
+}
+
+// Compression is opt-in: the peer sends a COMPRESSION frame with the codecs
+// it accepts, and the broker replies with the one it chose. From then on the
+// broker compresses the messages it writes to the peer that reach the
+// threshold.
+#[derive(Debug)]
+pub struct Compression {
+    threshold: usize,
+    codec: Option<Codec>,
+}
+
+impl Compression {
+    pub fn new(threshold: usize) -> Self {
+        Self {
+            threshold,
+            codec: None,
+        }
+    }
+
+    pub fn apply(&self, message: &mut Message) -> error::Result<()> {
+        match self.codec {
+            Some(codec) => compress_shared_message(message, codec, self.threshold),
+            None => Ok(()),
+        }
+    }
+
+    pub fn handle_control_message(
+        &mut self,
+        message: &Message,
+        writer: &mut impl Write,
+    ) -> error::Result<bool> {
+        if COMPRESSION_TOPIC != &*message.topic {
+            return Ok(false);
+        }
+
+        // Pick the first of the peer's codecs that the broker supports.
+        self.codec = message.headers.get(CODECS_HEADER).and_then(|codecs| {
+            codecs
+                .split(',')
+                .find_map(|codec| codec.trim().parse().ok())
+        });
+
+        let codec = self.codec.map(Codec::name).unwrap_or("none");
+        let headers = HashMap::from([(CODEC_HEADER.to_owned(), codec.to_owned())]);
+        Message::with_headers(COMPRESSION_TOPIC.to_owned(), headers, Vec::new()).write(writer)?;
+
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    fn compressible_data() -> Vec<u8> {
+        b"compressible ".repeat(100)
+    }
+
+    fn assert_round_trip(codec: Codec) {
+        let data = compressible_data();
+        let mut message = Message::new("topic", data.clone());
+
+        compress_message(&mut message, codec, 0).unwrap();
+        assert_eq!(
+            Some(codec.name()),
+            message.headers.get(ENCODING_HEADER).map(String::as_str)
+        );
+        assert!(message.data.len() < data.len());
+
+        decompress_message(&mut message).unwrap();
+        assert!(!message.headers.contains_key(ENCODING_HEADER));
+        assert_eq!(&data[..], &message.data[..]);
+    }
+
+    #[test]
+    fn lz4_round_trip() {
+        assert_round_trip(Codec::Lz4);
+    }
+
+    #[test]
+    fn zstd_round_trip() {
+        assert_round_trip(Codec::Zstd);
+    }
+
+    #[test]
+    fn data_under_the_threshold_is_not_compressed() {
+        let data = compressible_data();
+        let mut message = Message::new("topic", data.clone());
+
+        compress_message(&mut message, Codec::Zstd, data.len() + 1).unwrap();
+        assert!(!message.headers.contains_key(ENCODING_HEADER));
+        assert_eq!(&data[..], &message.data[..]);
+    }
+
+    #[test]
+    fn copies_share_their_compressed_data() {
+        let compression = Compression {
+            threshold: 0,
+            codec: Some(Codec::Lz4),
+        };
+        let message = Message::new("topic", compressible_data());
+        let (mut first, mut second) = (message.clone(), message);
+
+        compression.apply(&mut first).unwrap();
+        compression.apply(&mut second).unwrap();
+        assert_eq!(first.data.as_ptr(), second.data.as_ptr());
+    }
+
+    #[test]
+    fn encoding_set_by_a_client_is_rejected() {
+        let headers = HashMap::from([(ENCODING_HEADER.to_owned(), "gzip".to_owned())]);
+        let mut message = Message::with_headers("topic", headers, b"data".to_vec());
+        assert!(matches!(
+            decompress_message(&mut message),
+            Err(Error::UnsupportedCodec(_))
+        ));
+
+        let headers = HashMap::from([(ENCODING_HEADER.to_owned(), "lz4".to_owned())]);
+        let mut message = Message::with_headers("topic", headers, b"not lz4".to_vec());
+        assert!(matches!(
+            decompress_message(&mut message),
+            Err(Error::MalformedCompression(_))
+        ));
+    }
+
+    #[test]
+    fn negotiation_picks_the_first_supported_codec() {
+        let headers = HashMap::from([(CODECS_HEADER.to_owned(), "gzip, zstd, lz4".to_owned())]);
+        let request = Message::with_headers(COMPRESSION_TOPIC, headers, Vec::new());
+        let mut compression = Compression::new(0);
+        let mut reply: Vec<u8> = Vec::new();
+
+        assert!(compression
+            .handle_control_message(&request, &mut reply)
+            .unwrap());
+        assert_eq!(Some(Codec::Zstd), compression.codec);
+
+        let reply = Message::read(&mut Cursor::new(reply)).unwrap();
+        assert_eq!(
+            Some("zstd"),
+            reply.headers.get(CODEC_HEADER).map(String::as_str)
+        );
+    }
+}
@@ -0,0 +1,86 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::priority::DrainPolicy;
+use crate::rate_limiter::{RateLimit, RateLimitPolicy};
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub publisher_port: u16,
+    pub subscriber_port: u16,
+    pub http_port: Option<u16>,
+    pub mqtt_port: Option<u16>,
+    pub nats_port: Option<u16>,
+    pub redis_port: Option<u16>,
+    pub stomp_port: Option<u16>,
+    pub quic_port: Option<u16>,
+    pub quic_certificate_path: Option<PathBuf>,
+    pub quic_private_key_path: Option<PathBuf>,
+    pub heartbeat_min_interval_ms: u64,
+    pub heartbeat_max_missed: u32,
+    pub handshake_timeout_ms: u64,
+    pub subscription_timeout_ms: u64,
+    pub max_pending_handshakes_per_ip: usize,
+    pub max_connections: Option<usize>,
+    pub max_connections_per_listener: Option<usize>,
+    pub max_connections_per_ip: Option<usize>,
+    pub max_connections_per_principal: Option<usize>,
+    pub rate_limit_policy: RateLimitPolicy,
+    pub publisher_rate_limits: Vec<(String, RateLimit)>,
+    pub topic_rate_limits: Vec<(String, RateLimit)>,
+    pub lossless_topics: Vec<String>,
+    pub topic_ttls: Vec<(String, Duration)>,
+    pub dead_letter_topics: Vec<String>,
+    pub priority_drain_policy: DrainPolicy,
+    pub dedup_window_ms: u64,
+    pub compression_threshold: usize,
+    pub frame_checksums: bool,
+    pub subscriber_queue_high_water_mark: usize,
+    pub router_shards: usize,
+    pub max_schedule_delay_ms: u64,
+    pub max_scheduled_bytes: usize,
+    pub max_transaction_messages: usize,
+    pub max_transaction_bytes: usize,
+}
+
+impl Config {
+    pub fn new(publisher_port: u16, subscriber_port: u16) -> Self {
+        Self {
+            publisher_port,
+            subscriber_port,
+            http_port: None,
+            mqtt_port: None,
+            nats_port: None,
+            redis_port: None,
+            stomp_port: None,
+            quic_port: None,
+            quic_certificate_path: None,
+            quic_private_key_path: None,
+            heartbeat_min_interval_ms: 1000,
+            heartbeat_max_missed: 3,
+            handshake_timeout_ms: 5000,
+            subscription_timeout_ms: 10000,
+            max_pending_handshakes_per_ip: 16,
+            max_connections: None,
+            max_connections_per_listener: None,
+            max_connections_per_ip: None,
+            max_connections_per_principal: None,
+            rate_limit_policy: RateLimitPolicy::Throttle,
+            publisher_rate_limits: Vec::new(),
+            topic_rate_limits: Vec::new(),
+            lossless_topics: Vec::new(),
+            topic_ttls: Vec::new(),
+            dead_letter_topics: Vec::new(),
+            priority_drain_policy: DrainPolicy::Strict,
+            dedup_window_ms: 60000,
+            compression_threshold: 1024,
+            frame_checksums: false,
+            subscriber_queue_high_water_mark: 1000,
+            router_shards: 1,
+            max_schedule_delay_ms: 7 * 24 * 60 * 60 * 1000,
+            max_scheduled_bytes: 256 * 1024 * 1024,
+            max_transaction_messages: 10000,
+            max_transaction_bytes: 64 * 1024 * 1024,
+        }
+    }
+}
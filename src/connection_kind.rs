@@ -1,7 +1,13 @@
 use strum_macros::Display;
 
-#[derive(Clone, Debug, Display)]
+#[derive(Clone, Debug, Display, PartialEq, Eq, Hash)]
 pub enum ConnectionKind {
     Publisher,
     Subscriber,
+    Http,
+    Mqtt,
+    Nats,
+    Redis,
+    Stomp,
+    Quic,
 }
@@ -0,0 +1,163 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+use uuid::Uuid;
+
+// The message header marking a copy delivered on behalf of a consumer group.
+pub const GROUP_HEADER: &str = "$group";
+// The message header keeping messages with the same value in order, by
+// delivering all of them to the same member of a group.
+pub const ORDERING_KEY_HEADER: &str = "ordering-key";
+
+#[derive(Debug, Default)]
+pub struct ConsumerGroup {
+    members: Vec<Uuid>,
+    next_member: usize,
+}
+
+impl ConsumerGroup {
+    pub fn join(&mut self, id: Uuid) {
+        if !self.members.contains(&id) {
+            self.members.push(id);
+        }
+    }
+
+    pub fn leave(&mut self, id: Uuid) {
+        self.members.retain(|member| *member != id);
+    }
+
+    pub fn members(&self) -> &[Uuid] {
+        &self.members
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.members.is_empty()
+    }
+
+    // Picks the member that should receive the next message. Messages with
+    // an ordering key go to the member the key hashes to, other messages
+    // rotate through the members in order.
+    pub fn next_member(&mut self, ordering_key: Option<&str>) -> Option<Uuid> {
+        if self.members.is_empty() {
+            return None;
+        }
+
+        if let Some(ordering_key) = ordering_key {
+            return Self::member_for_key(&self.members, ordering_key);
+        }
+
+        let member = self.members[self.next_member % self.members.len()];
+        self.next_member = self.next_member.wrapping_add(1);
+
+        Some(member)
+    }
+
+    // Rendezvous hashing: a key goes to the member with the highest hash of
+    // the two. When a member joins or leaves, only the keys it gains or loses
+    // are rebalanced, and all other keys stay with their members.
+    fn member_for_key(members: &[Uuid], ordering_key: &str) -> Option<Uuid> {
+        members.iter().copied().max_by_key(|member| {
+            let mut hasher = DefaultHasher::new();
+            ordering_key.hash(&mut hasher);
+            member.hash(&mut hasher);
+            hasher.finish()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn group_of(members: &[u128]) -> ConsumerGroup {
+        let mut group = ConsumerGroup::default();
+        for member in members.iter() {
+            group.join(Uuid::from_u128(*member));
+        }
+        group
+    }
+
+    fn keys() -> Vec<String> {
+        (0..1000).map(|key| format!("key-{}", key)).collect()
+    }
+
+    fn assignments(group: &mut ConsumerGroup) -> Vec<Uuid> {
+        keys()
+            .iter()
+            .map(|key| group.next_member(Some(key)).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn keys_stay_with_their_member() {
+        let mut group = group_of(&[1, 2, 3]);
+        let first = assignments(&mut group);
+
+        // Messages without a key rotating through the members don't move
+        // the keys.
+        group.next_member(None);
+        assert_eq!(first, assignments(&mut group));
+
+        // Neither does the order the members joined in.
+        assert_eq!(first, assignments(&mut group_of(&[3, 1, 2])));
+    }
+
+    #[test]
+    fn keys_are_spread_over_the_members() {
+        let mut group = group_of(&[1, 2, 3]);
+        let assignments = assignments(&mut group);
+        for member in group.members().iter() {
+            assert!(assignments.contains(member));
+        }
+    }
+
+    #[test]
+    fn only_the_keys_of_a_leaving_member_move() {
+        let mut group = group_of(&[1, 2, 3]);
+        let before = assignments(&mut group);
+
+        let leaving = Uuid::from_u128(2);
+        group.leave(leaving);
+        let after = assignments(&mut group);
+
+        for (before, after) in before.iter().zip(after.iter()) {
+            if *before == leaving {
+                assert_ne!(leaving, *after);
+            } else {
+                assert_eq!(before, after);
+            }
+        }
+    }
+
+    #[test]
+    fn only_the_keys_of_a_joining_member_move() {
+        let mut group = group_of(&[1, 2, 3]);
+        let before = assignments(&mut group);
+
+        let joining = Uuid::from_u128(4);
+        group.join(joining);
+        let after = assignments(&mut group);
+
+        for (before, after) in before.iter().zip(after.iter()) {
+            assert!(before == after || joining == *after);
+        }
+        assert!(after.contains(&joining));
+    }
+
+    #[test]
+    fn messages_without_a_key_rotate() {
+        let mut group = group_of(&[1, 2]);
+        let members: Vec<Uuid> = (0..4).map(|_| group.next_member(None).unwrap()).collect();
+        assert_eq!(
+            vec![
+                Uuid::from_u128(1),
+                Uuid::from_u128(2),
+                Uuid::from_u128(1),
+                Uuid::from_u128(2)
+            ],
+            members
+        );
+
+        assert_eq!(None, ConsumerGroup::default().next_member(Some("key")));
+    }
+}
@@ -0,0 +1,66 @@
+use crate::error::{self, Error};
+use crate::message::Message;
+
+pub const CREDIT_TOPIC: &str = "$credit";
+pub const MESSAGES_HEADER: &str = "messages";
+pub const BYTES_HEADER: &str = "bytes";
+
+// The credit a subscriber granted the broker. Subscribers that never grant
+// credit receive messages as fast as they are published. Once a subscriber
+// grants credit in messages or in bytes, the broker only writes to it while
+// enough of that credit remains, and queues the rest.
+#[derive(Debug, Default)]
+pub struct Credit {
+    messages: Option<u64>,
+    bytes: Option<u64>,
+}
+
+impl Credit {
+    pub fn grant(&mut self, message: &Message) -> error::Result<()> {
+        let messages = Self::parse_header(message, MESSAGES_HEADER)?;
+        let bytes = Self::parse_header(message, BYTES_HEADER)?;
+        if messages.is_none() && bytes.is_none() {
+            return Err(Error::MalformedCredit(format!(
+                "neither {} nor {} granted",
+                MESSAGES_HEADER, BYTES_HEADER
+            )));
+        }
+
+        if let Some(messages) = messages {
+            self.messages = Some(self.messages.unwrap_or_default().saturating_add(messages));
+        }
+        if let Some(bytes) = bytes {
+            self.bytes = Some(self.bytes.unwrap_or_default().saturating_add(bytes));
+        }
+
+        Ok(())
+    }
+
+    pub fn allows(&self, message: &Message) -> bool {
+        self.messages.is_none_or(|messages| messages >= 1)
+            && self
+                .bytes
+                .is_none_or(|bytes| bytes >= message.data.len() as u64)
+    }
+
+    pub fn consume(&mut self, message: &Message) {
+        if let Some(messages) = self.messages.as_mut() {
+            *messages -= 1;
+        }
+        if let Some(bytes) = self.bytes.as_mut() {
+            *bytes -= message.data.len() as u64;
+        }
+    }
+
+    fn parse_header(message: &Message, header: &str) -> error::Result<Option<u64>> {
+        message
+            .headers
+            .get(header)
+            .map(|value| {
+                value.parse().map_err(|_| {
+                    Error::MalformedCredit(format!("invalid {} header: {}", header, value))
+                })
+            })
+            .transpose()
+    }
+}
@@ -0,0 +1,90 @@
+use std::sync::Arc;
+
+use crossbeam::channel::Sender;
+use strum_macros::Display;
+
+use crate::event::Event;
+use crate::expiry::{EXPIRES_AT_HEADER, TTL_HEADER};
+use crate::message::Message;
+use crate::metrics::Metrics;
+use crate::topic_filter::TopicFilter;
+
+pub const DEAD_LETTER_TOPIC_PREFIX: &str = "$dlq.";
+pub const REASON_HEADER: &str = "$dlq-reason";
+pub const ORIGINAL_TOPIC_HEADER: &str = "$dlq-original-topic";
+// The number of times the message was dead-lettered, counting the times it
+// was replayed from a dead-letter topic and failed again.
+pub const ATTEMPTS_HEADER: &str = "$dlq-attempts";
+
+#[derive(Clone, Copy, Debug, Display)]
+#[strum(serialize_all = "snake_case")]
+pub enum DeadLetterReason {
+    NoSubscribers,
+    Expired,
+    Rejected,
+    Unacknowledged,
+}
+
+// Republishes the undeliverable messages of the configured topics to their
+// dead-letter topics, where they can be inspected and replayed.
+#[derive(Debug)]
+pub struct DeadLetters {
+    filters: Vec<TopicFilter>,
+    event_sender: Sender<Event>,
+    metrics: Arc<Metrics>,
+}
+
+impl DeadLetters {
+    pub fn new(patterns: &[String], event_sender: Sender<Event>, metrics: Arc<Metrics>) -> Self {
+        Self {
+            filters: patterns
+                .iter()
+                .map(|pattern| TopicFilter::glob(pattern))
+                .collect(),
+            event_sender,
+            metrics,
+        }
+    }
+
+    pub fn route(&self, mut message: Message, reason: DeadLetterReason) {
+        // Dead letters are never dead-lettered again.
+        if message.topic.starts_with(DEAD_LETTER_TOPIC_PREFIX)
+            || !self
+                .filters
+                .iter()
+                .any(|filter| filter.matches(&message.topic))
+        {
+            return;
+        }
+
+        let attempts = message
+            .headers
+            .get(ATTEMPTS_HEADER)
+            .and_then(|attempts| attempts.parse::<u64>().ok())
+            .unwrap_or_default()
+            + 1;
+
+        // The dead letter must not expire like the original message did.
+        let original_topic = message.topic.to_string();
+        let headers = message.headers_mut();
+        headers.remove(TTL_HEADER);
+        headers.remove(EXPIRES_AT_HEADER);
+        headers.insert(REASON_HEADER.to_owned(), reason.to_string());
+        headers.insert(ORIGINAL_TOPIC_HEADER.to_owned(), original_topic);
+        headers.insert(ATTEMPTS_HEADER.to_owned(), attempts.to_string());
+        message.topic = format!("{}{}", DEAD_LETTER_TOPIC_PREFIX, message.topic).into();
+
+        log::warn!(
+            "Dead-lettering message to topic [{}]: [{}]",
+            message.topic,
+            reason
+        );
+        self.metrics.increment(&format!(
+            "dead_lettered_messages_total{{reason=\"{}\"}}",
+            reason
+        ));
+        if let Err(e) = self.event_sender.send(Event::Publish(message)) {
+            log::error!("Failed sending Publish event for a dead letter: [{}]", e);
+        }
+    }
+}
@@ -0,0 +1,55 @@
+use std::collections::{HashSet, VecDeque};
+use std::time::{Duration, Instant};
+
+use crate::message::Message;
+
+// The key publishers attach to a message so that the broker routes it once,
+// even if they publish it again.
+pub const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+
+// Remembers the idempotency keys published to each topic within the window.
+// Publishers get a new ID whenever they reconnect, so the keys are scoped by
+// topic rather than by publisher.
+#[derive(Debug)]
+pub struct Dedup {
+    window: Duration,
+    seen: HashSet<(String, String)>,
+    // The keys in the order they were seen, to forget them once they leave
+    // the window.
+    expirations: VecDeque<(Instant, (String, String))>,
+}
+
+impl Dedup {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            seen: HashSet::new(),
+            expirations: VecDeque::new(),
+        }
+    }
+
+    pub fn is_duplicate(&mut self, message: &Message) -> bool {
+        let now = Instant::now();
+        while let Some((expiration, _)) = self.expirations.front() {
+            if *expiration > now {
+                break;
+            }
+            if let Some((_, key)) = self.expirations.pop_front() {
+                self.seen.remove(&key);
+            }
+        }
+
+        let idempotency_key = match message.headers.get(IDEMPOTENCY_KEY_HEADER) {
+            Some(idempotency_key) if !self.window.is_zero() => idempotency_key,
+            _ => return false,
+        };
+
+        let key = (message.topic.to_string(), idempotency_key.clone());
+        if !self.seen.insert(key.clone()) {
+            return true;
+        }
+        self.expirations.push_back((now + self.window, key));
+
+        false
+    }
+}
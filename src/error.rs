@@ -5,6 +5,7 @@ use std::string;
 use crossbeam::channel::{RecvError, SendError};
 use thiserror::Error;
 
+use crate::event::Event;
 use crate::message::Message;
 
 #[derive(Error, Debug)]
@@ -15,12 +16,105 @@ pub enum Error {
     #[error("failed sending Message to channel: {0}")]
     ChannelSendMessage(#[from] SendError<Message>),
 
+    #[error("failed sending Event to channel: {0}")]
+    ChannelSendEvent(#[from] SendError<Event>),
+
+    #[error("corrupt frame: {0}")]
+    Corrupt(String),
+
+    #[error("frame too large: {0}")]
+    FrameTooLarge(String),
+
+    #[error("HTTP request body too large: {0} bytes")]
+    HttpBodyTooLarge(usize),
+
+    #[error("HTTP request headers too large: {0}")]
+    HttpHeadersTooLarge(String),
+
     #[error("I/O error: {0}")]
     Io(#[from] io::Error),
 
     #[error("failed converting byte vector to UTF-8 String: {0}")]
     FromUtf8(#[from] string::FromUtf8Error),
 
+    #[error("malformed batch: {0}")]
+    MalformedBatch(String),
+
+    #[error("malformed compression: {0}")]
+    MalformedCompression(String),
+
+    #[error("malformed credit: {0}")]
+    MalformedCredit(String),
+
+    #[error("malformed heartbeat: {0}")]
+    MalformedHeartbeat(String),
+
+    #[error("malformed HTTP request: {0}")]
+    MalformedHttpRequest(String),
+
+    #[error("malformed MQTT packet: {0}")]
+    MalformedMqttPacket(String),
+
+    #[error("malformed NATS command: {0}")]
+    MalformedNatsCommand(String),
+
+    #[error("malformed RESP command: {0}")]
+    MalformedRespCommand(String),
+
+    #[error("malformed schedule: {0}")]
+    MalformedSchedule(String),
+
+    #[error("malformed STOMP frame: {0}")]
+    MalformedStompFrame(String),
+
+    #[error("malformed TTL: {0}")]
+    MalformedTtl(String),
+
+    #[error("NATS payload too large: {0} bytes")]
+    NatsPayloadTooLarge(usize),
+
+    #[error("rate limit exceeded")]
+    RateLimited,
+
+    #[error("schedule rejected: {0}")]
+    ScheduleRejected(String),
+
+    #[error("transaction too large: {0}")]
+    TransactionTooLarge(String),
+
+    #[error("unsupported codec: {0}")]
+    UnsupportedCodec(String),
+
+    #[error("QUIC connection error: {0}")]
+    QuicConnection(#[from] quinn::ConnectionError),
+
+    #[error("failed accepting QUIC connection: {0}")]
+    QuicConnect(#[from] quinn::ConnectError),
+
+    #[error("QUIC configuration error: {0}")]
+    QuicConfig(#[from] quinn::crypto::rustls::NoInitialCipherSuite),
+
+    #[error("failed reading from QUIC stream: {0}")]
+    QuicRead(#[from] quinn::ReadExactError),
+
+    #[error("failed writing to QUIC stream: {0}")]
+    QuicWrite(#[from] quinn::WriteError),
+
+    #[error("QUIC frame too large: {0} bytes")]
+    QuicFrameTooLarge(u32),
+
+    #[error("QUIC session already attached to a connection: {0}")]
+    QuicSessionInUse(uuid::Uuid),
+
+    #[error("TLS error: {0}")]
+    Tls(#[from] rustls::Error),
+
+    #[error("failed reading PEM file: {0}")]
+    Pem(#[from] rustls::pki_types::pem::Error),
+
+    #[error("failed generating certificate: {0}")]
+    Certificate(#[from] rcgen::Error),
+
     #[error("failed registering ctrl-c handler: {0}")]
     Ctrlc(#[from] ctrlc::Error),
 }
@@ -1,16 +1,31 @@
-use std::net::TcpStream;
+use std::net::{IpAddr, TcpStream};
+use std::sync::{Arc, Barrier};
 
+use crossbeam::channel::Sender;
 use strum_macros::Display;
+use tokio::sync::oneshot;
 use uuid::Uuid;
 
 use crate::connection_kind::ConnectionKind;
 use crate::message::Message;
+use crate::quic_listener::QuicSubscriber;
 use crate::subscription_request::SubscriptionRequest;
+use crate::topic_filter::TopicFilter;
 
 #[derive(Debug, Display)]
 pub enum Event {
+    Authentication(Uuid, String, Sender<bool>),
+    BatchPart(Vec<Message>, Arc<Barrier>),
     Connection(ConnectionKind, TcpStream),
+    CountedPublish(Message, Sender<usize>),
+    Disconnection(Uuid),
+    GroupSubscribe(Uuid, String, TopicFilter),
+    GroupUnsubscribe(Uuid, String, TopicFilter),
     Publish(Message),
+    PublishBatch(Vec<Message>),
+    QuicSession(Uuid, IpAddr, QuicSubscriber, oneshot::Sender<bool>),
+    Subscribe(Uuid, Vec<TopicFilter>),
     SubscriptionRequest(Uuid, SubscriptionRequest),
+    Unsubscribe(Uuid, Vec<TopicFilter>),
     Termination,
 }
@@ -0,0 +1,71 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::error::{self, Error};
+use crate::message::Message;
+use crate::topic_filter::TopicFilter;
+
+// The time to live a publisher gives a message, in milliseconds.
+pub const TTL_HEADER: &str = "ttl-ms";
+// The time a message expires at, in milliseconds since the UNIX epoch.
+pub const EXPIRES_AT_HEADER: &str = "$expires-at";
+
+// Stamps published messages with the time they expire at, taken from their
+// own TTL or from the default TTL of their topic. The router dead-letters the
+// messages that expired before they were routed, and subscriber handlers
+// discard the copies that expired before they were written.
+#[derive(Debug)]
+pub struct Expiry {
+    topic_ttls: Vec<(TopicFilter, Duration)>,
+}
+
+impl Expiry {
+    pub fn new(topic_ttls: &[(String, Duration)]) -> Self {
+        Self {
+            topic_ttls: topic_ttls
+                .iter()
+                .map(|(pattern, ttl)| (TopicFilter::glob(pattern), *ttl))
+                .collect(),
+        }
+    }
+
+    pub fn stamp(&self, message: &mut Message) -> error::Result<()> {
+        // Only the broker may decide when a message expires.
+        message.headers_mut().remove(EXPIRES_AT_HEADER);
+
+        let ttl = match message.headers.get(TTL_HEADER) {
+            Some(ttl) => {
+                Duration::from_millis(ttl.parse().map_err(|_| Error::MalformedTtl(ttl.clone()))?)
+            }
+            None => match self
+                .topic_ttls
+                .iter()
+                .find(|(filter, _)| filter.matches(&message.topic))
+            {
+                Some((_, ttl)) => *ttl,
+                None => return Ok(()),
+            },
+        };
+
+        let expires_at = Self::now().saturating_add(ttl.as_millis() as u64);
+        message
+            .headers_mut()
+            .insert(EXPIRES_AT_HEADER.to_owned(), expires_at.to_string());
+
+        Ok(())
+    }
+
+    pub fn is_expired(message: &Message) -> bool {
+        message
+            .headers
+            .get(EXPIRES_AT_HEADER)
+            .and_then(|expires_at| expires_at.parse::<u64>().ok())
+            .is_some_and(|expires_at| expires_at <= Self::now())
+    }
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64
+    }
+}
@@ -0,0 +1,133 @@
+use std::collections::HashMap;
+use std::io::{self, Read};
+use std::net::{IpAddr, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// Reads a client's handshake, failing with a timeout if its first byte
+// doesn't arrive before the handshake deadline or the whole of it doesn't
+// arrive before the subscription deadline. The overall deadline keeps clients
+// that trickle bytes from holding the connection open indefinitely.
+pub struct HandshakeReader<'a> {
+    stream: &'a TcpStream,
+    handshake_deadline: Instant,
+    subscription_deadline: Instant,
+    started: bool,
+}
+
+impl Read for HandshakeReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let deadline = if self.started {
+            self.subscription_deadline
+        } else {
+            self.handshake_deadline
+        };
+
+        // A zero read timeout means blocking forever, so check for an expired
+        // deadline explicitly.
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "handshake deadline expired",
+            ));
+        }
+
+        self.stream.set_read_timeout(Some(remaining))?;
+        let mut stream = self.stream;
+        let result = stream.read(buf);
+        self.stream.set_read_timeout(None)?;
+
+        // Timed out reads fail with WouldBlock on some platforms.
+        let size = match result {
+            Ok(size) => size,
+            Err(e) if io::ErrorKind::WouldBlock == e.kind() => {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "handshake deadline expired",
+                ))
+            }
+            Err(e) => return Err(e),
+        };
+        self.started |= 0 != size;
+
+        Ok(size)
+    }
+}
+
+// Counts the connections of each client IP that didn't complete their
+// handshake yet.
+#[derive(Clone, Debug)]
+pub struct PendingHandshakes {
+    ip_to_count: Arc<Mutex<HashMap<IpAddr, usize>>>,
+    max_per_ip: usize,
+    handshake_timeout: Duration,
+    subscription_timeout: Duration,
+}
+
+#[derive(Debug)]
+pub struct PendingHandshake {
+    pending_handshakes: PendingHandshakes,
+    ip: IpAddr,
+    handshake_deadline: Instant,
+    subscription_deadline: Instant,
+}
+
+impl PendingHandshakes {
+    pub fn new(
+        max_per_ip: usize,
+        handshake_timeout: Duration,
+        subscription_timeout: Duration,
+    ) -> Self {
+        Self {
+            ip_to_count: Arc::new(Mutex::new(HashMap::new())),
+            max_per_ip,
+            handshake_timeout,
+            subscription_timeout,
+        }
+    }
+
+    pub fn admit(&self, ip: IpAddr) -> Option<PendingHandshake> {
+        let mut ip_to_count = self.ip_to_count.lock().unwrap();
+
+        let count = ip_to_count.entry(ip).or_default();
+        if *count >= self.max_per_ip {
+            return None;
+        }
+        *count += 1;
+
+        // The deadlines count from the moment the connection is admitted.
+        let now = Instant::now();
+        Some(PendingHandshake {
+            pending_handshakes: self.clone(),
+            ip,
+            handshake_deadline: now + self.handshake_timeout.min(self.subscription_timeout),
+            subscription_deadline: now + self.subscription_timeout,
+        })
+    }
+}
+
+impl PendingHandshake {
+    pub fn reader<'a>(&self, stream: &'a TcpStream) -> HandshakeReader<'a> {
+        HandshakeReader {
+            stream,
+            handshake_deadline: self.handshake_deadline,
+            subscription_deadline: self.subscription_deadline,
+            started: false,
+        }
+    }
+}
+
+impl Drop for PendingHandshake {
+    fn drop(&mut self) {
+        let mut ip_to_count = self.pending_handshakes.ip_to_count.lock().unwrap();
+
+        // Forget IPs that have no pending handshakes.
+        if let Some(count) = ip_to_count.get_mut(&self.ip) {
+            *count -= 1;
+            if 0 == *count {
+                ip_to_count.remove(&self.ip);
+            }
+        }
+    }
+}
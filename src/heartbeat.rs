@@ -0,0 +1,87 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+use crate::error::{self, Error};
+use crate::message::Message;
+
+pub const HEARTBEAT_TOPIC: &str = "$heartbeat";
+pub const PING_TOPIC: &str = "$ping";
+pub const PONG_TOPIC: &str = "$pong";
+pub const INTERVAL_HEADER: &str = "interval-ms";
+
+// Heartbeats are opt-in: the peer sends a HEARTBEAT frame with the interval
+// it wants, and the broker replies with the negotiated one. From then on the
+// peer must send a frame, a PING if it has nothing else to send, at least
+// once per interval, and is considered dead after missing too many of them.
+#[derive(Debug)]
+pub struct Heartbeat {
+    min_interval: Duration,
+    max_missed: u32,
+    interval: Option<Duration>,
+    last_received: Instant,
+}
+
+impl Heartbeat {
+    pub fn new(min_interval: Duration, max_missed: u32) -> Self {
+        Self {
+            min_interval,
+            max_missed,
+            interval: None,
+            last_received: Instant::now(),
+        }
+    }
+
+    pub fn received(&mut self) {
+        self.last_received = Instant::now();
+    }
+
+    pub fn is_expired(&self) -> bool {
+        match self.interval {
+            Some(interval) => self.last_received.elapsed() > interval * self.max_missed,
+            None => false,
+        }
+    }
+
+    pub fn handle_control_message(
+        &mut self,
+        message: &Message,
+        writer: &mut impl Write,
+    ) -> error::Result<bool> {
+        match &*message.topic {
+            HEARTBEAT_TOPIC => {
+                let requested = message
+                    .headers
+                    .get(INTERVAL_HEADER)
+                    .and_then(|interval| interval.parse().ok())
+                    .ok_or_else(|| {
+                        Error::MalformedHeartbeat(format!("invalid {} header", INTERVAL_HEADER))
+                    })?;
+                let interval = self.negotiate(Duration::from_millis(requested));
+
+                let headers =
+                    HashMap::from([(INTERVAL_HEADER.to_owned(), interval.as_millis().to_string())]);
+                Message::with_headers(HEARTBEAT_TOPIC.to_owned(), headers, Vec::new())
+                    .write(writer)?;
+            }
+            PING_TOPIC => Message::new(PONG_TOPIC.to_owned(), Vec::new()).write(writer)?,
+            PONG_TOPIC => {}
+            _ => return Ok(false),
+        }
+
+        Ok(true)
+    }
+
+    fn negotiate(&mut self, requested: Duration) -> Duration {
+        // Zero disables heartbeats, otherwise the peer may not send them more
+        // often than the broker allows.
+        self.interval = if requested.is_zero() {
+            None
+        } else {
+            Some(requested.max(self.min_interval))
+        };
+        self.last_received = Instant::now();
+
+        self.interval.unwrap_or_default()
+    }
+}
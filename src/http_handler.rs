@@ -0,0 +1,382 @@
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crossbeam::channel::{self, Receiver, Sender};
+use polling::Poller;
+use uuid::Uuid;
+
+use crate::error::{self, Error};
+use crate::event::Event;
+use crate::http_request::HttpRequest;
+use crate::message::Message;
+use crate::metrics::Metrics;
+use crate::publish_gate::{self, PublishControl, PublishGate};
+use crate::shutdown::{self, ShutdownReceiver, ShutdownSender};
+use crate::subscriber::Subscriber;
+use crate::subscription_request::SubscriptionRequest;
+
+const HTTP_STREAM_POLL_KEY: usize = 0;
+
+// HTTP headers that describe the transport rather than the published message.
+// Reserved "$" headers are the broker's, and aren't taken from requests either.
+const TRANSPORT_HEADERS: [&str; 6] = [
+    "connection",
+    "content-length",
+    "expect",
+    "host",
+    "keep-alive",
+    "transfer-encoding",
+];
+
+pub struct HttpHandler {
+    message_sender: Sender<Message>,
+    poller: Arc<Poller>,
+    handler_thread: Option<JoinHandle<()>>,
+    shutdown: ShutdownSender,
+}
+
+impl HttpHandler {
+    pub fn new(
+        id: Uuid,
+        stream: TcpStream,
+        event_sender: Sender<Event>,
+        publish_control: PublishControl,
+        metrics: Arc<Metrics>,
+    ) -> error::Result<Self> {
+        let (message_sender, message_receiver): (Sender<Message>, Receiver<Message>) =
+            channel::unbounded();
+
+        let poller = Arc::new(Poller::new()?);
+        poller.add(&stream, polling::Event::readable(HTTP_STREAM_POLL_KEY))?;
+        let gate = publish_control.gate(
+            stream.peer_addr()?.ip().to_string(),
+            publish_gate::poller_waker(poller.clone()),
+        );
+
+        let (shutdown, shutdown_receiver) = shutdown::signal(poller.clone());
+        let shutdown = shutdown.with_stream(stream.try_clone()?);
+
+        Ok(Self {
+            message_sender,
+            poller: poller.clone(),
+            handler_thread: Some(Self::start_handler_thread(
+                id,
+                stream,
+                event_sender,
+                message_receiver,
+                gate,
+                metrics,
+                poller,
+                shutdown_receiver,
+            )),
+            shutdown,
+        })
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn start_handler_thread(
+        id: Uuid,
+        stream: TcpStream,
+        event_sender: Sender<Event>,
+        message_receiver: Receiver<Message>,
+        gate: PublishGate,
+        metrics: Arc<Metrics>,
+        poller: Arc<Poller>,
+        shutdown: ShutdownReceiver,
+    ) -> JoinHandle<()> {
+        thread::spawn(move || {
+            Self::handle_client(
+                id,
+                stream,
+                &event_sender,
+                message_receiver,
+                gate,
+                &metrics,
+                poller,
+                shutdown,
+            );
+
+            // Let the PubSub forget about this client.
+            if let Err(e) = event_sender.send(Event::Disconnection(id)) {
+                log::error!("Failed sending Disconnection event for [{}]: [{}]", id, e);
+            }
+        })
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn handle_client(
+        id: Uuid,
+        stream: TcpStream,
+        event_sender: &Sender<Event>,
+        message_receiver: Receiver<Message>,
+        mut gate: PublishGate,
+        metrics: &Metrics,
+        poller: Arc<Poller>,
+        shutdown: ShutdownReceiver,
+    ) {
+        // Connections reset right after being accepted have no peer address.
+        let address = match stream.peer_addr() {
+            Ok(address) => address,
+            Err(e) => {
+                log::error!("Failed handling HTTP client [{}]: [{}]", id, e);
+                return;
+            }
+        };
+        log::info!("Handling HTTP client: id=[{}], address=[{}]", id, address);
+
+        let mut reader = BufReader::new(&stream);
+        let mut streaming = false;
+        let mut poll_events: Vec<polling::Event> = Vec::new();
+        while !shutdown.is_requested() {
+            // Stop reading requests while the client is held back by its
+            // publications.
+            let paused = gate.is_paused();
+            let interest = if paused {
+                polling::Event::none(HTTP_STREAM_POLL_KEY)
+            } else {
+                polling::Event::readable(HTTP_STREAM_POLL_KEY)
+            };
+
+            // Modify the poller's interest in the client's stream.
+            // This is required to receive multiple read events on macOS.
+            if let Err(e) = poller.modify(&stream, interest) {
+                log::error!(
+                    "Failed to modify the poller's interest in HTTP client [{}]: [{}]",
+                    id,
+                    e
+                );
+                break;
+            }
+
+            // Clear all previous poll events.
+            poll_events.clear();
+
+            // Wait for an I/O event, a published message or a shutdown
+            // request. Requests already buffered are handled without waiting.
+            let timeout = if reader.buffer().is_empty() || paused {
+                None
+            } else {
+                Some(Duration::ZERO)
+            };
+            if let Err(e) = poller.wait(&mut poll_events, gate.timeout(timeout)) {
+                log::error!("Failed polling for events from [{}]: [{}]", id, e);
+                continue;
+            }
+
+            // Forward published messages to a streaming client.
+            if streaming {
+                if let Err(e) = Self::write_messages(&stream, &message_receiver) {
+                    log::error!("Error writing messages to [{}]: [{}]", id, e);
+                    break;
+                }
+            }
+
+            // Check if the client sent anything.
+            if paused || (poll_events.is_empty() && reader.buffer().is_empty()) {
+                continue;
+            }
+
+            // A streaming client is not expected to send anything, only
+            // watch for it closing the connection.
+            if streaming {
+                match reader.fill_buf() {
+                    Ok(buffer) if !buffer.is_empty() => {
+                        let length = buffer.len();
+                        reader.consume(length);
+                        continue;
+                    }
+                    _ => break,
+                }
+            }
+
+            // Receive a request from the client.
+            let request = match HttpRequest::read(&mut reader) {
+                Ok(Some(request)) => request,
+                Ok(None) => break,
+                Err(e) => {
+                    log::error!("Error receiving HTTP request from [{}]: [{}]", id, e);
+                    let status = match e {
+                        Error::HttpBodyTooLarge(_) => "413 Payload Too Large",
+                        Error::HttpHeadersTooLarge(_) => "431 Request Header Fields Too Large",
+                        _ => "400 Bad Request",
+                    };
+                    let _ = Self::write_response(&stream, status, &e.to_string());
+                    break;
+                }
+            };
+
+            // Handle the request.
+            streaming = match Self::handle_request(
+                id,
+                &stream,
+                event_sender,
+                &mut gate,
+                metrics,
+                request,
+            ) {
+                Ok(streaming) => streaming,
+                Err(e) => {
+                    log::error!("Error handling HTTP request from [{}]: [{}]", id, e);
+                    break;
+                }
+            };
+        }
+    }
+
+    fn handle_request(
+        id: Uuid,
+        stream: &TcpStream,
+        event_sender: &Sender<Event>,
+        gate: &mut PublishGate,
+        metrics: &Metrics,
+        request: HttpRequest,
+    ) -> error::Result<bool> {
+        log::info!(
+            "HTTP request from [{}]: [{} {}]",
+            id,
+            request.method,
+            request.path
+        );
+
+        let segments: Vec<&str> = request.segments.iter().map(String::as_str).collect();
+        match (request.method.as_str(), segments.as_slice()) {
+            ("POST", ["topics", topic]) => {
+                let message = Self::request_to_message(topic, &request);
+                match gate.pass_one(message) {
+                    Ok(message) => {
+                        event_sender.send(Event::Publish(message))?;
+                        Self::write_response(stream, "202 Accepted", "")?;
+                    }
+                    Err(Error::RateLimited) => Self::write_response(
+                        stream,
+                        "429 Too Many Requests",
+                        &Error::RateLimited.to_string(),
+                    )?,
+                    Err(e) => Self::write_response(stream, "400 Bad Request", &e.to_string())?,
+                }
+            }
+            ("GET", ["topics", topic, "stream"]) => {
+                Self::start_stream(id, stream, event_sender, vec![(*topic).to_owned()])?;
+                return Ok(true);
+            }
+            ("GET", ["stream"]) => {
+                let topics = request.query_values("topic");
+                if topics.is_empty() {
+                    Self::write_response(stream, "400 Bad Request", "no topic requested")?;
+                } else {
+                    Self::start_stream(id, stream, event_sender, topics)?;
+                    return Ok(true);
+                }
+            }
+            ("GET", ["metrics"]) => Self::write_response(stream, "200 OK", &metrics.render())?,
+            (_, ["topics", _])
+            | (_, ["topics", _, "stream"])
+            | (_, ["stream"])
+            | (_, ["metrics"]) => {
+                Self::write_response(stream, "405 Method Not Allowed", "")?;
+            }
+            _ => Self::write_response(stream, "404 Not Found", "")?,
+        }
+
+        Ok(false)
+    }
+
+    fn request_to_message(topic: &str, request: &HttpRequest) -> Message {
+        let headers: HashMap<String, String> = request
+            .headers
+            .iter()
+            .filter(|(name, _)| {
+                !TRANSPORT_HEADERS.contains(&name.as_str()) && !name.starts_with('$')
+            })
+            .cloned()
+            .collect();
+
+        Message::with_headers(topic.to_owned(), headers, request.body.clone())
+    }
+
+    fn start_stream(
+        id: Uuid,
+        mut stream: &TcpStream,
+        event_sender: &Sender<Event>,
+        topics: Vec<String>,
+    ) -> error::Result<()> {
+        log::info!("Streaming topics [{}] to [{}]", topics.join(", "), id);
+
+        stream.write_all(
+            b"HTTP/1.1 200 OK\r\n\
+              Content-Type: text/event-stream\r\n\
+              Cache-Control: no-cache\r\n\
+              Connection: keep-alive\r\n\
+              \r\n",
+        )?;
+
+        event_sender.send(Event::SubscriptionRequest(
+            id,
+            SubscriptionRequest::new(topics),
+        ))?;
+
+        Ok(())
+    }
+
+    fn write_messages(
+        mut stream: &TcpStream,
+        message_receiver: &Receiver<Message>,
+    ) -> error::Result<()> {
+        for message in message_receiver.try_iter() {
+            // Write the message as a server-sent event named after its topic,
+            // whose line breaks would start other fields.
+            let mut event = format!("event: {}\n", message.topic.replace(['\r', '\n'], ""));
+            for line in String::from_utf8_lossy(&message.data).split('\n') {
+                event.push_str("data: ");
+                event.push_str(line.trim_end_matches('\r'));
+                event.push('\n');
+            }
+            event.push('\n');
+
+            stream.write_all(event.as_bytes())?;
+        }
+
+        Ok(())
+    }
+
+    fn write_response(mut stream: &TcpStream, status: &str, body: &str) -> error::Result<()> {
+        write!(
+            stream,
+            "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\n\r\n{}",
+            status,
+            body.len(),
+            body
+        )?;
+
+        Ok(())
+    }
+}
+
+impl Subscriber for HttpHandler {
+    fn publish(&self, message: Message) -> error::Result<()> {
+        self.message_sender.send(message)?;
+        self.poller.notify()?;
+        Ok(())
+    }
+
+    fn queue_len(&self) -> usize {
+        self.message_sender.len()
+    }
+}
+
+impl Drop for HttpHandler {
+    fn drop(&mut self) {
+        if let Some(thread) = self.handler_thread.take() {
+            // Indicate the handler thread that it should terminate, waking
+            // it up.
+            self.shutdown.request();
+
+            // Join the handler thread.
+            thread.join().unwrap();
+        }
+    }
+}
@@ -0,0 +1,173 @@
+use std::io::{BufRead, Read};
+
+use crate::error::{self, Error};
+
+const MAX_HEADERS_NUMBER: usize = 100;
+const MAX_LINE_SIZE: usize = 8 * 1024;
+const MAX_BODY_SIZE: usize = 1024 * 1024;
+
+#[derive(Debug)]
+pub struct HttpRequest {
+    pub method: String,
+    pub path: String,
+    // The percent-decoded segments of the path, which may contain encoded
+    // slashes.
+    pub segments: Vec<String>,
+    pub query: Vec<(String, String)>,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl HttpRequest {
+    pub fn read(reader: &mut impl BufRead) -> error::Result<Option<Self>> {
+        // Read the request line, stopping if the peer closed the connection.
+        let request_line = match Self::read_line(reader)? {
+            Some(line) => line,
+            None => return Ok(None),
+        };
+
+        let mut parts = request_line.split_whitespace();
+        let (method, target) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(method), Some(target), Some(_version)) => (method.to_owned(), target),
+            _ => return Err(Error::MalformedHttpRequest(request_line)),
+        };
+
+        // Split the request target into its path and query.
+        let (raw_path, raw_query) = target.split_once('?').unwrap_or((target, ""));
+        let segments = raw_path
+            .trim_matches('/')
+            .split('/')
+            .map(|segment| Self::percent_decode(segment, false))
+            .collect::<error::Result<Vec<String>>>()?;
+        let query = Self::parse_query(raw_query)?;
+
+        // Read the headers.
+        let mut headers: Vec<(String, String)> = Vec::new();
+        loop {
+            let line = match Self::read_line(reader)? {
+                Some(line) => line,
+                None => return Err(Error::MalformedHttpRequest("truncated headers".to_owned())),
+            };
+
+            if line.is_empty() {
+                break;
+            }
+
+            if headers.len() == MAX_HEADERS_NUMBER {
+                return Err(Error::HttpHeadersTooLarge(format!(
+                    "more than {} headers",
+                    MAX_HEADERS_NUMBER
+                )));
+            }
+
+            match line.split_once(':') {
+                Some((name, value)) => {
+                    headers.push((name.trim().to_ascii_lowercase(), value.trim().to_owned()))
+                }
+                None => return Err(Error::MalformedHttpRequest(line)),
+            }
+        }
+
+        // Read the body.
+        let mut request = Self {
+            method,
+            path: raw_path.to_owned(),
+            segments,
+            query,
+            headers,
+            body: Vec::new(),
+        };
+
+        if let Some(content_length) = request.header("content-length") {
+            let content_length: usize = content_length.parse().map_err(|_| {
+                Error::MalformedHttpRequest(format!("invalid content-length: {}", content_length))
+            })?;
+            if content_length > MAX_BODY_SIZE {
+                return Err(Error::HttpBodyTooLarge(content_length));
+            }
+
+            reader
+                .take(content_length as u64)
+                .read_to_end(&mut request.body)?;
+            if request.body.len() != content_length {
+                return Err(Error::MalformedHttpRequest("truncated body".to_owned()));
+            }
+        }
+
+        Ok(Some(request))
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn query_values(&self, name: &str) -> Vec<String> {
+        self.query
+            .iter()
+            .filter(|(key, _)| key == name)
+            .map(|(_, value)| value.clone())
+            .collect()
+    }
+
+    fn read_line(reader: &mut impl BufRead) -> error::Result<Option<String>> {
+        // Read one byte beyond the limit to tell long lines apart.
+        let mut line = String::new();
+        if 0 == reader.take(MAX_LINE_SIZE as u64 + 1).read_line(&mut line)? {
+            return Ok(None);
+        }
+        if line.len() > MAX_LINE_SIZE {
+            return Err(Error::HttpHeadersTooLarge(format!(
+                "a line exceeds {} bytes",
+                MAX_LINE_SIZE
+            )));
+        }
+
+        Ok(Some(line.trim_end_matches(['\r', '\n']).to_owned()))
+    }
+
+    fn parse_query(raw_query: &str) -> error::Result<Vec<(String, String)>> {
+        raw_query
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| {
+                let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+                Ok((
+                    Self::percent_decode(key, true)?,
+                    Self::percent_decode(value, true)?,
+                ))
+            })
+            .collect()
+    }
+
+    fn percent_decode(encoded: &str, plus_as_space: bool) -> error::Result<String> {
+        let bytes = encoded.as_bytes();
+
+        let mut decoded: Vec<u8> = Vec::with_capacity(bytes.len());
+        let mut index = 0;
+        while index < bytes.len() {
+            match bytes[index] {
+                b'%' => {
+                    let hex = encoded
+                        .get(index + 1..index + 3)
+                        .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                        .ok_or_else(|| Error::MalformedHttpRequest(encoded.to_owned()))?;
+                    decoded.push(hex);
+                    index += 3;
+                }
+                b'+' if plus_as_space => {
+                    decoded.push(b' ');
+                    index += 1;
+                }
+                byte => {
+                    decoded.push(byte);
+                    index += 1;
+                }
+            }
+        }
+
+        Ok(String::from_utf8(decoded)?)
+    }
+}
@@ -1,13 +1,62 @@
+mod admission;
 mod background_tcp_listener;
+mod backpressure;
+mod batch;
+mod checksum;
+mod compression;
+mod config;
 mod connection_kind;
+mod consumer_group;
+mod credit;
+mod dead_letter;
+mod dedup;
 mod error;
 mod event;
+mod expiry;
+mod handshake;
+mod heartbeat;
+mod http_handler;
+mod http_request;
 mod message;
+mod metrics;
+mod mqtt_handler;
+mod mqtt_packet;
+mod nats_command;
+mod nats_handler;
+mod priority;
+mod publish_gate;
 mod publisher_handler;
 mod pubsub;
+mod quic_listener;
+mod rate_limiter;
+mod redis_handler;
+mod resp_command;
+mod resp_reply;
+mod router;
+mod scheduler;
+mod shutdown;
+mod stomp_frame;
+mod stomp_handler;
+mod subscriber;
 mod subscriber_handler;
 mod subscription_request;
+mod topic_filter;
+mod transaction;
 
-pub use message::Message;
+pub use batch::{encode_batch, BATCH_TOPIC};
+pub use checksum::CHECKSUMS_TOPIC;
+pub use compression::{compress_message, decompress_message, Codec};
+pub use compression::{CODECS_HEADER, CODEC_HEADER, COMPRESSION_TOPIC, ENCODING_HEADER};
+pub use config::Config;
+pub use consumer_group::ORDERING_KEY_HEADER;
+pub use credit::{BYTES_HEADER, CREDIT_TOPIC, MESSAGES_HEADER};
+pub use dedup::IDEMPOTENCY_KEY_HEADER;
+pub use expiry::TTL_HEADER;
+pub use heartbeat::{HEARTBEAT_TOPIC, INTERVAL_HEADER, PING_TOPIC, PONG_TOPIC};
+pub use message::{Message, CHECKSUM_HEADER};
+pub use priority::{DrainPolicy, PRIORITY_HEADER};
 pub use pubsub::PubSub;
+pub use rate_limiter::{RateLimit, RateLimitPolicy};
+pub use scheduler::{DELAY_HEADER, DELIVER_AT_HEADER};
 pub use subscription_request::SubscriptionRequest;
+pub use transaction::{TransactionLimits, ABORT_TOPIC, BEGIN_TOPIC, COMMIT_TOPIC};
@@ -1,46 +1,291 @@
-use std::io::{Read, Write};
+use std::collections::HashMap;
+use std::io::{self, IoSlice, Read, Write};
+use std::sync::Arc;
 
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use bytes::{Buf, Bytes};
 
-use crate::error;
+use crate::compression::CompressedData;
+use crate::error::{self, Error};
+use crate::scheduler::ScheduleReservation;
 
+// The optional CRC32 of a frame, in hex, covering its topic, its other
+// headers ordered by key and its data. Frames carrying it are verified on read.
+pub const CHECKSUM_HEADER: &str = "$crc32";
+
+// Frames beyond these limits are refused before anything is allocated for
+// them, since their lengths come from the peer.
+pub const MAX_HEADERS: u32 = 1024;
+pub const MAX_FRAME_SIZE: usize = 64 * 1024 * 1024;
+
+// The topic, the headers and the data are shared between the copies of a
+// message, so fanning it out to many subscribers doesn't copy them. Copies
+// whose headers are modified get headers of their own.
 #[derive(Clone, Debug)]
 pub struct Message {
-    pub topic: String,
-    pub data: Vec<u8>,
+    pub topic: Arc<str>,
+    pub headers: Arc<HashMap<String, String>>,
+    pub data: Bytes,
+    pub(crate) compressed_data: CompressedData,
+    // The part of the scheduling budget held while the message is scheduled.
+    pub(crate) schedule_reservation: Option<Arc<ScheduleReservation>>,
 }
 
 impl Message {
-    pub fn new(topic: String, data: Vec<u8>) -> Self {
-        Self { topic, data }
+    pub fn new(topic: impl Into<Arc<str>>, data: impl Into<Bytes>) -> Self {
+        Self::with_headers(topic, HashMap::new(), data)
+    }
+
+    pub fn with_headers(
+        topic: impl Into<Arc<str>>,
+        headers: HashMap<String, String>,
+        data: impl Into<Bytes>,
+    ) -> Self {
+        Self {
+            topic: topic.into(),
+            headers: Arc::new(headers),
+            data: data.into(),
+            compressed_data: CompressedData::default(),
+            schedule_reservation: None,
+        }
     }
 
     pub fn read(reader: &mut impl Read) -> error::Result<Self> {
+        Self::read_checked(reader, false)
+    }
+
+    // Reads a frame, refusing it if it has no checksum but one is required.
+    pub fn read_checked(reader: &mut impl Read, checksum_required: bool) -> error::Result<Self> {
+        // The topic, the headers and the data all count against the frame's
+        // size.
+        let mut remaining = MAX_FRAME_SIZE;
+
         // Read the topic.
-        let topic_size = reader.read_u32::<BigEndian>()?;
+        let topic = Self::read_string(reader, &mut remaining)?;
 
-        let mut topic_bytes: Vec<u8> = vec![0; topic_size as usize];
-        reader.read_exact(&mut topic_bytes)?;
-        let topic = String::from_utf8(topic_bytes)?;
+        // Read the headers.
+        let headers_number = reader.read_u32::<BigEndian>()?;
+        if headers_number > MAX_HEADERS {
+            return Err(Error::FrameTooLarge(format!(
+                "{} headers, at most {} are allowed",
+                headers_number, MAX_HEADERS
+            )));
+        }
+
+        let mut headers: HashMap<String, String> = HashMap::new();
+        for _ in 0..headers_number {
+            let key = Self::read_string(reader, &mut remaining)?;
+            let value = Self::read_string(reader, &mut remaining)?;
+            headers.insert(key, value);
+        }
 
         // Read the data.
-        let data_size = reader.read_u32::<BigEndian>()?;
+        let data = Self::read_bytes(reader, &mut remaining)?;
+
+        let mut message = Self::with_headers(topic, headers, data);
 
-        let mut data: Vec<u8> = vec![0; data_size as usize];
-        reader.read_exact(&mut data)?;
+        // Refuse frames that were corrupted on their way, or that were read
+        // from a desynchronised stream.
+        match message.headers_mut().remove(CHECKSUM_HEADER) {
+            Some(expected) => {
+                let actual = message.checksum();
+                if u32::from_str_radix(&expected, 16).ok() != Some(actual) {
+                    return Err(Error::Corrupt(format!(
+                        "expected checksum {}, got {:08x}",
+                        expected, actual
+                    )));
+                }
+            }
+            None if checksum_required => {
+                return Err(Error::Corrupt("missing checksum".to_owned()));
+            }
+            None => {}
+        }
+
+        Ok(message)
+    }
 
-        Ok(Self { topic, data })
+    // The message's own headers, copied first if they're shared with other
+    // copies of the message.
+    pub fn headers_mut(&mut self) -> &mut HashMap<String, String> {
+        Arc::make_mut(&mut self.headers)
+    }
+
+    pub fn add_checksum(&mut self) {
+        self.headers_mut().remove(CHECKSUM_HEADER);
+        let checksum = self.checksum();
+        self.headers_mut()
+            .insert(CHECKSUM_HEADER.to_owned(), format!("{:08x}", checksum));
+    }
+
+    fn checksum(&self) -> u32 {
+        let mut headers: Vec<(&String, &String)> = self
+            .headers
+            .iter()
+            .filter(|(key, _)| CHECKSUM_HEADER != key.as_str())
+            .collect();
+        headers.sort();
+
+        let mut hasher = crc32fast::Hasher::new();
+        let mut update = |bytes: &[u8]| {
+            hasher.update(&(bytes.len() as u32).to_be_bytes());
+            hasher.update(bytes);
+        };
+        update(self.topic.as_bytes());
+        for (key, value) in headers.into_iter() {
+            update(key.as_bytes());
+            update(value.as_bytes());
+        }
+        update(&self.data);
+
+        hasher.finalize()
     }
 
     pub fn write(&self, writer: &mut impl Write) -> error::Result<()> {
+        let mut chunks: Vec<Bytes> = Vec::with_capacity(2);
+        self.encode(&mut chunks)?;
+        write_all_vectored(writer, chunks)
+    }
+
+    // Appends the frame to the chunks, sharing the data rather than copying it.
+    pub fn encode(&self, chunks: &mut Vec<Bytes>) -> error::Result<()> {
+        let mut prefix: Vec<u8> = Vec::new();
+
         // Write the topic.
-        writer.write_u32::<BigEndian>(self.topic.len() as u32)?;
-        writer.write_all(self.topic.as_bytes())?;
+        Self::write_string(&mut prefix, &self.topic)?;
+
+        // Write the headers.
+        prefix.write_u32::<BigEndian>(self.headers.len() as u32)?;
+        for (key, value) in self.headers.iter() {
+            Self::write_string(&mut prefix, key)?;
+            Self::write_string(&mut prefix, value)?;
+        }
 
         // Write the data.
-        writer.write_u32::<BigEndian>(self.data.len() as u32)?;
-        writer.write_all(&self.data)?;
+        prefix.write_u32::<BigEndian>(self.data.len() as u32)?;
+        chunks.push(Bytes::from(prefix));
+        if !self.data.is_empty() {
+            chunks.push(self.data.clone());
+        }
 
         Ok(())
     }
+
+    fn read_string(reader: &mut impl Read, remaining: &mut usize) -> error::Result<String> {
+        Ok(String::from_utf8(Self::read_bytes(reader, remaining)?)?)
+    }
+
+    fn read_bytes(reader: &mut impl Read, remaining: &mut usize) -> error::Result<Vec<u8>> {
+        let size = reader.read_u32::<BigEndian>()? as usize;
+        if size > *remaining {
+            return Err(Error::FrameTooLarge(format!(
+                "the frame exceeds {} bytes",
+                MAX_FRAME_SIZE
+            )));
+        }
+        *remaining -= size;
+
+        // Let the buffer grow with the bytes actually received rather than
+        // trusting the length for the allocation.
+        let mut bytes: Vec<u8> = Vec::new();
+        reader.take(size as u64).read_to_end(&mut bytes)?;
+        if bytes.len() != size {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+
+        Ok(bytes)
+    }
+
+    fn write_string(writer: &mut impl Write, string: &str) -> error::Result<()> {
+        writer.write_u32::<BigEndian>(string.len() as u32)?;
+        writer.write_all(string.as_bytes())?;
+
+        Ok(())
+    }
+}
+
+// Writes all the chunks with as few vectored writes as possible.
+pub fn write_all_vectored(writer: &mut impl Write, mut chunks: Vec<Bytes>) -> error::Result<()> {
+    // Most systems don't take more slices than this in a single write.
+    const MAX_SLICES: usize = 1024;
+
+    let mut first = 0;
+    while first < chunks.len() {
+        let slices: Vec<IoSlice> = chunks[first..]
+            .iter()
+            .take(MAX_SLICES)
+            .map(|chunk| IoSlice::new(chunk))
+            .collect();
+        let mut written = match writer.write_vectored(&slices) {
+            Ok(0) => return Err(io::Error::from(io::ErrorKind::WriteZero).into()),
+            Ok(written) => written,
+            Err(e) if io::ErrorKind::Interrupted == e.kind() => continue,
+            Err(e) => return Err(e.into()),
+        };
+
+        // Skip the chunks that were written, and what was written of the next.
+        while first < chunks.len() && written >= chunks[first].len() {
+            written -= chunks[first].len();
+            first += 1;
+        }
+        if first < chunks.len() {
+            chunks[first].advance(written);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    fn checked_frame() -> Vec<u8> {
+        let mut headers: HashMap<String, String> = HashMap::new();
+        headers.insert("key".to_owned(), "value".to_owned());
+        let mut message = Message::with_headers("topic", headers, b"data".to_vec());
+        message.add_checksum();
+
+        let mut frame: Vec<u8> = Vec::new();
+        message.write(&mut frame).unwrap();
+        frame
+    }
+
+    #[test]
+    fn checksum_round_trip() {
+        let message = Message::read_checked(&mut Cursor::new(checked_frame()), true).unwrap();
+
+        assert_eq!("topic", &*message.topic);
+        assert_eq!(
+            Some("value"),
+            message.headers.get("key").map(String::as_str)
+        );
+        assert!(!message.headers.contains_key(CHECKSUM_HEADER));
+        assert_eq!(&b"data"[..], &message.data[..]);
+    }
+
+    #[test]
+    fn corrupted_byte_is_rejected() {
+        let mut frame = checked_frame();
+        let last = frame.len() - 1;
+        frame[last] ^= 0xff;
+
+        let result = Message::read_checked(&mut Cursor::new(frame), false);
+        assert!(matches!(result, Err(Error::Corrupt(_))));
+    }
+
+    #[test]
+    fn missing_checksum_is_rejected_when_required() {
+        let mut frame: Vec<u8> = Vec::new();
+        Message::new("topic", b"data".to_vec())
+            .write(&mut frame)
+            .unwrap();
+
+        let result = Message::read_checked(&mut Cursor::new(frame.clone()), true);
+        assert!(matches!(result, Err(Error::Corrupt(_))));
+
+        assert!(Message::read_checked(&mut Cursor::new(frame), false).is_ok());
+    }
 }
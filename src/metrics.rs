@@ -0,0 +1,29 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+// Counters shared by the router and the connection handlers, exposed in the
+// Prometheus text format.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    counters: Mutex<BTreeMap<String, u64>>,
+}
+
+impl Metrics {
+    pub fn increment(&self, name: &str) {
+        *self
+            .counters
+            .lock()
+            .unwrap()
+            .entry(name.to_owned())
+            .or_default() += 1;
+    }
+
+    pub fn render(&self) -> String {
+        self.counters
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, value)| format!("{} {}\n", name, value))
+            .collect()
+    }
+}
@@ -0,0 +1,663 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::{BufReader, Write};
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crossbeam::channel::{self, Receiver, Sender};
+use polling::Poller;
+use uuid::Uuid;
+
+use crate::error::{self, Error};
+use crate::event::Event;
+use crate::message::Message;
+use crate::mqtt_packet::{MqttPacket, MqttWill};
+use crate::publish_gate::{self, PublishControl, PublishGate};
+use crate::shutdown::{self, ShutdownReceiver, ShutdownSender};
+use crate::subscriber::Subscriber;
+use crate::topic_filter::TopicFilter;
+
+const MQTT_STREAM_POLL_KEY: usize = 0;
+const POLL_TIMEOUT_MS: u64 = 300;
+
+const PROTOCOL_NAME: &str = "MQTT";
+const PROTOCOL_LEVEL: u8 = 4;
+
+const CONNACK_ACCEPTED: u8 = 0x00;
+const CONNACK_UNACCEPTABLE_PROTOCOL_VERSION: u8 = 0x01;
+const CONNACK_IDENTIFIER_REJECTED: u8 = 0x02;
+pub const CONNACK_SERVER_UNAVAILABLE: u8 = 0x03;
+const SUBACK_FAILURE: u8 = 0x80;
+
+// The highest QoS publishers may use and subscriptions are granted. QoS 1
+// messages are acknowledged once the broker took them, and written to
+// subscribers again until they acknowledge them. Sessions aren't persisted,
+// so the messages a subscriber didn't acknowledge are lost with its
+// connection.
+const MAX_QOS: u8 = 1;
+// The number of messages written to a subscriber and not acknowledged yet,
+// past which its messages wait in its queue.
+const MAX_INFLIGHT_MESSAGES: usize = 64;
+// How long a message written to a subscriber waits for its acknowledgement
+// before it's written again.
+const REDELIVERY_INTERVAL_MS: u64 = 5000;
+
+// The message header carrying the QoS a message was published with.
+pub const QOS_HEADER: &str = "qos";
+
+pub struct MqttHandler {
+    message_sender: Sender<Message>,
+    poller: Arc<Poller>,
+    handler_thread: Option<JoinHandle<()>>,
+    shutdown: ShutdownSender,
+}
+
+impl MqttHandler {
+    pub fn new(
+        id: Uuid,
+        stream: TcpStream,
+        event_sender: Sender<Event>,
+        publish_control: PublishControl,
+    ) -> error::Result<Self> {
+        let (message_sender, message_receiver): (Sender<Message>, Receiver<Message>) =
+            channel::unbounded();
+
+        let poller = Arc::new(Poller::new()?);
+        poller.add(&stream, polling::Event::readable(MQTT_STREAM_POLL_KEY))?;
+        let gate = publish_control.gate(
+            stream.peer_addr()?.ip().to_string(),
+            publish_gate::poller_waker(poller.clone()),
+        );
+
+        let (shutdown, shutdown_receiver) = shutdown::signal(poller.clone());
+        let shutdown = shutdown.with_stream(stream.try_clone()?);
+
+        Ok(Self {
+            message_sender,
+            poller: poller.clone(),
+            handler_thread: Some(Self::start_handler_thread(
+                MqttSession::new(id, stream, event_sender, shutdown_receiver.clone(), gate),
+                message_receiver,
+                poller,
+                shutdown_receiver,
+            )),
+            shutdown,
+        })
+    }
+
+    fn start_handler_thread(
+        session: MqttSession,
+        message_receiver: Receiver<Message>,
+        poller: Arc<Poller>,
+        shutdown: ShutdownReceiver,
+    ) -> JoinHandle<()> {
+        thread::spawn(move || {
+            let id = session.id;
+            let event_sender = session.event_sender.clone();
+
+            Self::handle_client(session, message_receiver, poller, shutdown);
+
+            // Let the PubSub forget about this client.
+            if let Err(e) = event_sender.send(Event::Disconnection(id)) {
+                log::error!("Failed sending Disconnection event for [{}]: [{}]", id, e);
+            }
+        })
+    }
+
+    fn handle_client(
+        mut session: MqttSession,
+        message_receiver: Receiver<Message>,
+        poller: Arc<Poller>,
+        shutdown: ShutdownReceiver,
+    ) {
+        // Connections reset right after being accepted have no peer address.
+        let address = match session.stream.peer_addr() {
+            Ok(address) => address,
+            Err(e) => {
+                log::error!("Failed handling MQTT client [{}]: [{}]", session.id, e);
+                return;
+            }
+        };
+        log::info!(
+            "Handling MQTT client: id=[{}], address=[{}]",
+            session.id,
+            address
+        );
+
+        let stream = session.stream.try_clone().unwrap();
+        let mut reader = BufReader::new(&stream);
+        let mut poll_events: Vec<polling::Event> = Vec::new();
+        let graceful = loop {
+            if shutdown.is_requested() {
+                break true;
+            }
+
+            // Stop reading packets while the client is held back by its
+            // publications. Paused clients can't be heard from.
+            let paused = session.gate.is_paused();
+            let interest = if paused {
+                session.last_packet = Instant::now();
+                polling::Event::none(MQTT_STREAM_POLL_KEY)
+            } else {
+                polling::Event::readable(MQTT_STREAM_POLL_KEY)
+            };
+
+            // Modify the poller's interest in the client's stream.
+            // This is required to receive multiple read events on macOS.
+            if let Err(e) = poller.modify(&session.stream, interest) {
+                log::error!(
+                    "Failed to modify the poller's interest in MQTT client [{}]: [{}]",
+                    session.id,
+                    e
+                );
+                break false;
+            }
+
+            // Clear all previous poll events.
+            poll_events.clear();
+
+            // Wait for an I/O event, a published message, a shutdown request
+            // or a timeout to check the keep alive. Packets already buffered
+            // are handled without waiting.
+            let timeout = if (reader.buffer().is_empty() || paused)
+                && (message_receiver.is_empty() || !session.can_write_messages())
+            {
+                Duration::from_millis(POLL_TIMEOUT_MS)
+            } else {
+                Duration::ZERO
+            };
+            if let Err(e) = poller.wait(&mut poll_events, session.gate.timeout(Some(timeout))) {
+                log::error!("Failed polling for events from [{}]: [{}]", session.id, e);
+                continue;
+            }
+
+            // Forward published messages to the client.
+            if let Err(e) = session.write_messages(&message_receiver) {
+                log::error!("Error writing messages to [{}]: [{}]", session.id, e);
+                break false;
+            }
+
+            // Disconnect clients that exceeded their keep alive.
+            if session.keep_alive_expired() {
+                log::warn!("MQTT client [{}] exceeded its keep alive", session.id);
+                break false;
+            }
+
+            // Check if the client sent anything.
+            if paused || (poll_events.is_empty() && reader.buffer().is_empty()) {
+                continue;
+            }
+
+            // Receive a packet from the client.
+            let packet = match MqttPacket::read(&mut reader) {
+                Ok(packet) => packet,
+                Err(e) => {
+                    log::error!("Error receiving MQTT packet from [{}]: [{}]", session.id, e);
+                    break false;
+                }
+            };
+
+            // Handle the packet.
+            match session.handle_packet(packet) {
+                Ok(true) => {}
+                Ok(false) => break true,
+                Err(e) => {
+                    log::error!("Error handling MQTT packet from [{}]: [{}]", session.id, e);
+                    break false;
+                }
+            }
+        };
+
+        // Publish the client's will if it didn't disconnect gracefully.
+        if !graceful {
+            session.publish_will();
+        }
+    }
+}
+
+impl Subscriber for MqttHandler {
+    fn publish(&self, message: Message) -> error::Result<()> {
+        self.message_sender.send(message)?;
+        self.poller.notify()?;
+        Ok(())
+    }
+
+    fn queue_len(&self) -> usize {
+        self.message_sender.len()
+    }
+}
+
+impl Drop for MqttHandler {
+    fn drop(&mut self) {
+        if let Some(thread) = self.handler_thread.take() {
+            // Indicate the handler thread that it should terminate, waking
+            // it up.
+            self.shutdown.request();
+
+            // Join the handler thread.
+            thread.join().unwrap();
+        }
+    }
+}
+
+struct MqttSubscription {
+    topic_filter: TopicFilter,
+    qos: u8,
+}
+
+// A message written to the client and not acknowledged yet.
+struct InflightMessage {
+    message: Message,
+    qos: u8,
+    written_at: Instant,
+}
+
+struct MqttSession {
+    id: Uuid,
+    stream: TcpStream,
+    event_sender: Sender<Event>,
+    shutdown: ShutdownReceiver,
+    connected: bool,
+    keep_alive: Option<Duration>,
+    last_packet: Instant,
+    will: Option<MqttWill>,
+    subscriptions: HashMap<String, MqttSubscription>,
+    inflight: BTreeMap<u16, InflightMessage>,
+    last_packet_id: u16,
+    gate: PublishGate,
+}
+
+impl MqttSession {
+    fn new(
+        id: Uuid,
+        stream: TcpStream,
+        event_sender: Sender<Event>,
+        shutdown: ShutdownReceiver,
+        gate: PublishGate,
+    ) -> Self {
+        Self {
+            id,
+            stream,
+            event_sender,
+            shutdown,
+            connected: false,
+            keep_alive: None,
+            last_packet: Instant::now(),
+            will: None,
+            subscriptions: HashMap::new(),
+            inflight: BTreeMap::new(),
+            last_packet_id: 0,
+            gate,
+        }
+    }
+
+    // Returns whether the session should keep running.
+    fn handle_packet(&mut self, packet: MqttPacket) -> error::Result<bool> {
+        self.last_packet = Instant::now();
+
+        // The first packet must be a CONNECT, and it may only be sent once.
+        match (&packet, self.connected) {
+            (MqttPacket::Connect { .. }, false) => {}
+            (MqttPacket::Connect { .. }, true) | (_, false) => {
+                return Err(Error::MalformedMqttPacket(format!(
+                    "unexpected packet: [{:?}]",
+                    packet
+                )))
+            }
+            _ => {}
+        }
+
+        match packet {
+            MqttPacket::Connect {
+                protocol_name,
+                protocol_level,
+                clean_session,
+                keep_alive,
+                client_id,
+                will,
+                username,
+            } => {
+                log::info!(
+                    "MQTT CONNECT from [{}]: client_id=[{}], username=[{:?}], clean_session=({})",
+                    self.id,
+                    client_id,
+                    username,
+                    clean_session
+                );
+
+                // Refuse principals that reached their connection limit.
+                if let Some(username) = username {
+                    if !self.authenticate(username.clone())? {
+                        MqttPacket::Connack {
+                            session_present: false,
+                            return_code: CONNACK_SERVER_UNAVAILABLE,
+                        }
+                        .write(&mut self.stream)?;
+                        return Ok(false);
+                    }
+                    self.gate.authenticate(&username);
+                }
+
+                return self.handle_connect(
+                    &protocol_name,
+                    protocol_level,
+                    clean_session,
+                    keep_alive,
+                    &client_id,
+                    will,
+                );
+            }
+            MqttPacket::Publish {
+                qos,
+                retain,
+                topic,
+                packet_id,
+                payload,
+                ..
+            } => self.handle_publish(qos, retain, topic, packet_id, payload)?,
+            MqttPacket::Puback { packet_id } => {
+                if self.inflight.remove(&packet_id).is_none() {
+                    log::debug!(
+                        "MQTT client [{}] acknowledged unknown packet ({})",
+                        self.id,
+                        packet_id
+                    );
+                }
+            }
+            MqttPacket::Subscribe { packet_id, filters } => {
+                self.handle_subscribe(packet_id, filters)?
+            }
+            MqttPacket::Unsubscribe { packet_id, filters } => {
+                self.handle_unsubscribe(packet_id, filters)?
+            }
+            MqttPacket::Pingreq => MqttPacket::Pingresp.write(&mut self.stream)?,
+            MqttPacket::Disconnect => {
+                log::info!("MQTT client [{}] disconnected", self.id);
+                self.will = None;
+                return Ok(false);
+            }
+            packet => {
+                return Err(Error::MalformedMqttPacket(format!(
+                    "unexpected packet: [{:?}]",
+                    packet
+                )))
+            }
+        }
+
+        Ok(true)
+    }
+
+    fn handle_connect(
+        &mut self,
+        protocol_name: &str,
+        protocol_level: u8,
+        clean_session: bool,
+        keep_alive: u16,
+        client_id: &str,
+        will: Option<MqttWill>,
+    ) -> error::Result<bool> {
+        // Validate the connection request.
+        let return_code = if PROTOCOL_NAME != protocol_name || PROTOCOL_LEVEL != protocol_level {
+            CONNACK_UNACCEPTABLE_PROTOCOL_VERSION
+        } else if client_id.is_empty() && !clean_session {
+            CONNACK_IDENTIFIER_REJECTED
+        } else {
+            CONNACK_ACCEPTED
+        };
+
+        // Sessions are never persisted, so there is no session to resume.
+        MqttPacket::Connack {
+            session_present: false,
+            return_code,
+        }
+        .write(&mut self.stream)?;
+
+        if CONNACK_ACCEPTED != return_code {
+            log::warn!(
+                "Rejected MQTT connection from [{}]: return_code=({})",
+                self.id,
+                return_code
+            );
+            return Ok(false);
+        }
+
+        self.connected = true;
+        self.will = will;
+        if keep_alive > 0 {
+            self.keep_alive = Some(Duration::from_secs(keep_alive as u64));
+        }
+
+        Ok(true)
+    }
+
+    fn authenticate(&self, principal: String) -> error::Result<bool> {
+        let (accepted_sender, accepted_receiver): (Sender<bool>, Receiver<bool>) =
+            channel::bounded(1);
+        self.event_sender
+            .send(Event::Authentication(self.id, principal, accepted_sender))?;
+
+        Ok(self.shutdown.recv(&accepted_receiver)?)
+    }
+
+    fn handle_publish(
+        &mut self,
+        qos: u8,
+        retain: bool,
+        topic: String,
+        packet_id: Option<u16>,
+        payload: Vec<u8>,
+    ) -> error::Result<()> {
+        if qos > MAX_QOS {
+            return Err(Error::MalformedMqttPacket(format!(
+                "unsupported PUBLISH QoS ({})",
+                qos
+            )));
+        }
+
+        if topic.is_empty() || topic.contains(['+', '#']) {
+            return Err(Error::MalformedMqttPacket(format!(
+                "invalid PUBLISH topic: [{}]",
+                topic
+            )));
+        }
+
+        if retain {
+            log::warn!(
+                "Ignoring retain flag of MQTT client [{}], retained messages aren't supported",
+                self.id
+            );
+        }
+
+        // Send a Publish event. MQTT 3.1.1 has no way to report rejected
+        // messages, they are dropped.
+        let mut message = Message::new(topic, payload);
+        message
+            .headers_mut()
+            .insert(QOS_HEADER.to_owned(), qos.to_string());
+        if let Ok(message) = self.gate.pass_one(message) {
+            self.event_sender.send(Event::Publish(message))?;
+        }
+
+        // Acknowledge QoS 1 messages.
+        if let Some(packet_id) = packet_id {
+            MqttPacket::Puback { packet_id }.write(&mut self.stream)?;
+        }
+
+        Ok(())
+    }
+
+    fn handle_subscribe(
+        &mut self,
+        packet_id: u16,
+        filters: Vec<(String, u8)>,
+    ) -> error::Result<()> {
+        let mut return_codes: Vec<u8> = Vec::with_capacity(filters.len());
+        let mut topic_filters: Vec<TopicFilter> = Vec::with_capacity(filters.len());
+        for (filter, qos) in filters.into_iter() {
+            match TopicFilter::mqtt(&filter) {
+                Some(topic_filter) if qos <= 2 => {
+                    let qos = qos.min(MAX_QOS);
+                    return_codes.push(qos);
+                    topic_filters.push(topic_filter.clone());
+                    self.subscriptions
+                        .insert(filter, MqttSubscription { topic_filter, qos });
+                }
+                _ => {
+                    log::warn!("Invalid MQTT subscription from [{}]: [{}]", self.id, filter);
+                    return_codes.push(SUBACK_FAILURE);
+                }
+            }
+        }
+
+        // Register the subscriptions before acknowledging them.
+        self.event_sender
+            .send(Event::Subscribe(self.id, topic_filters))?;
+
+        MqttPacket::Suback {
+            packet_id,
+            return_codes,
+        }
+        .write(&mut self.stream)?;
+
+        Ok(())
+    }
+
+    fn handle_unsubscribe(&mut self, packet_id: u16, filters: Vec<String>) -> error::Result<()> {
+        let topic_filters: Vec<TopicFilter> = filters
+            .iter()
+            .filter_map(|filter| self.subscriptions.remove(filter))
+            .map(|subscription| subscription.topic_filter)
+            .collect();
+
+        self.event_sender
+            .send(Event::Unsubscribe(self.id, topic_filters))?;
+
+        MqttPacket::Unsuback { packet_id }.write(&mut self.stream)?;
+
+        Ok(())
+    }
+
+    fn write_messages(&mut self, message_receiver: &Receiver<Message>) -> error::Result<()> {
+        // Write the messages the client didn't acknowledge in time again.
+        for (packet_id, inflight) in self.inflight.iter_mut() {
+            if inflight.written_at.elapsed() < Duration::from_millis(REDELIVERY_INTERVAL_MS) {
+                continue;
+            }
+
+            log::debug!(
+                "Redelivering message ({}) to MQTT client [{}]",
+                packet_id,
+                self.id
+            );
+            Self::publish_packet(&inflight.message, inflight.qos, Some(*packet_id), true)
+                .write(&mut self.stream)?;
+            inflight.written_at = Instant::now();
+        }
+
+        while self.can_write_messages() {
+            let message = match message_receiver.try_recv() {
+                Ok(message) => message,
+                Err(_) => break,
+            };
+
+            // Only deliver messages matching a current subscription, with
+            // the highest QoS of the matching subscriptions, up to the QoS
+            // the message was published with. Messages of other protocols
+            // were taken by the broker like QoS 1 messages.
+            let subscription_qos = self
+                .subscriptions
+                .values()
+                .filter(|subscription| subscription.topic_filter.matches(&message.topic))
+                .map(|subscription| subscription.qos)
+                .max();
+            let qos = match subscription_qos {
+                Some(subscription_qos) => subscription_qos.min(
+                    message
+                        .headers
+                        .get(QOS_HEADER)
+                        .and_then(|qos| qos.parse().ok())
+                        .unwrap_or(MAX_QOS),
+                ),
+                None => continue,
+            };
+
+            // Keep the message until the client acknowledges it.
+            let packet_id = if qos > 0 {
+                let packet_id = self.next_packet_id();
+                self.inflight.insert(
+                    packet_id,
+                    InflightMessage {
+                        message: message.clone(),
+                        qos,
+                        written_at: Instant::now(),
+                    },
+                );
+                Some(packet_id)
+            } else {
+                None
+            };
+
+            Self::publish_packet(&message, qos, packet_id, false).write(&mut self.stream)?;
+        }
+
+        self.stream.flush()?;
+
+        Ok(())
+    }
+
+    fn can_write_messages(&self) -> bool {
+        self.inflight.len() < MAX_INFLIGHT_MESSAGES
+    }
+
+    fn next_packet_id(&mut self) -> u16 {
+        // Packet IDs are non-zero, and those of unacknowledged messages are
+        // still taken.
+        loop {
+            self.last_packet_id = self.last_packet_id.wrapping_add(1);
+            if 0 != self.last_packet_id && !self.inflight.contains_key(&self.last_packet_id) {
+                return self.last_packet_id;
+            }
+        }
+    }
+
+    fn publish_packet(message: &Message, qos: u8, packet_id: Option<u16>, dup: bool) -> MqttPacket {
+        MqttPacket::Publish {
+            dup,
+            qos,
+            retain: false,
+            topic: message.topic.to_string(),
+            packet_id,
+            payload: message.data.to_vec(),
+        }
+    }
+
+    fn keep_alive_expired(&self) -> bool {
+        // The client is allowed one and a half keep alive periods of silence.
+        match self.keep_alive {
+            Some(keep_alive) => self.last_packet.elapsed() > keep_alive * 3 / 2,
+            None => false,
+        }
+    }
+
+    fn publish_will(&mut self) {
+        if let Some(will) = self.will.take() {
+            log::info!(
+                "Publishing will of MQTT client [{}] to topic: [{}]",
+                self.id,
+                will.topic
+            );
+
+            let mut message = Message::new(will.topic, will.message);
+            message
+                .headers_mut()
+                .insert(QOS_HEADER.to_owned(), will.qos.min(MAX_QOS).to_string());
+
+            if let Ok(message) = self.gate.pass_one(message) {
+                if let Err(e) = self.event_sender.send(Event::Publish(message)) {
+                    log::error!("Failed sending will of [{}]: [{}]", self.id, e);
+                }
+            }
+        }
+    }
+}
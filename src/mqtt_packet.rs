@@ -0,0 +1,343 @@
+use std::io::{self, Cursor, Read, Write};
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::error::{self, Error};
+use crate::message::MAX_FRAME_SIZE;
+
+const CONNECT: u8 = 1;
+const CONNACK: u8 = 2;
+const PUBLISH: u8 = 3;
+const PUBACK: u8 = 4;
+const SUBSCRIBE: u8 = 8;
+const SUBACK: u8 = 9;
+const UNSUBSCRIBE: u8 = 10;
+const UNSUBACK: u8 = 11;
+const PINGREQ: u8 = 12;
+const PINGRESP: u8 = 13;
+const DISCONNECT: u8 = 14;
+
+const MAX_REMAINING_LENGTH: u32 = 268_435_455;
+
+#[derive(Debug)]
+pub struct MqttWill {
+    pub topic: String,
+    pub message: Vec<u8>,
+    pub qos: u8,
+}
+
+#[derive(Debug)]
+pub enum MqttPacket {
+    Connect {
+        protocol_name: String,
+        protocol_level: u8,
+        clean_session: bool,
+        keep_alive: u16,
+        client_id: String,
+        will: Option<MqttWill>,
+        username: Option<String>,
+    },
+    Connack {
+        session_present: bool,
+        return_code: u8,
+    },
+    Publish {
+        dup: bool,
+        qos: u8,
+        retain: bool,
+        topic: String,
+        packet_id: Option<u16>,
+        payload: Vec<u8>,
+    },
+    Puback {
+        packet_id: u16,
+    },
+    Subscribe {
+        packet_id: u16,
+        filters: Vec<(String, u8)>,
+    },
+    Suback {
+        packet_id: u16,
+        return_codes: Vec<u8>,
+    },
+    Unsubscribe {
+        packet_id: u16,
+        filters: Vec<String>,
+    },
+    Unsuback {
+        packet_id: u16,
+    },
+    Pingreq,
+    Pingresp,
+    Disconnect,
+}
+
+impl MqttPacket {
+    pub fn read(reader: &mut impl Read) -> error::Result<Self> {
+        // Read the fixed header.
+        let first_byte = reader.read_u8()?;
+        let packet_type = first_byte >> 4;
+        let flags = first_byte & 0x0f;
+        let remaining_length = Self::read_remaining_length(reader)?;
+
+        if remaining_length as usize > MAX_FRAME_SIZE {
+            return Err(Error::FrameTooLarge(format!(
+                "remaining length {} exceeds {} bytes",
+                remaining_length, MAX_FRAME_SIZE
+            )));
+        }
+
+        // Read the rest of the packet through a limit rather than allocating
+        // the announced length up front.
+        let mut body: Vec<u8> = Vec::new();
+        reader
+            .take(remaining_length as u64)
+            .read_to_end(&mut body)?;
+        if body.len() != remaining_length as usize {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        let mut body = Cursor::new(body);
+
+        let packet = match packet_type {
+            CONNECT => Self::read_connect(&mut body)?,
+            PUBLISH => Self::read_publish(flags, &mut body)?,
+            PUBACK => Self::Puback {
+                packet_id: body.read_u16::<BigEndian>()?,
+            },
+            SUBSCRIBE if 0b0010 == flags => Self::read_subscribe(&mut body)?,
+            UNSUBSCRIBE if 0b0010 == flags => Self::read_unsubscribe(&mut body)?,
+            PINGREQ => Self::Pingreq,
+            DISCONNECT => Self::Disconnect,
+            _ => {
+                return Err(Error::MalformedMqttPacket(format!(
+                    "unsupported packet type ({}) with flags ({:#06b})",
+                    packet_type, flags
+                )))
+            }
+        };
+
+        Ok(packet)
+    }
+
+    pub fn write(&self, writer: &mut impl Write) -> error::Result<()> {
+        // Encode the variable header and payload first, since their length
+        // is part of the fixed header.
+        let mut body: Vec<u8> = Vec::new();
+        let first_byte = match self {
+            Self::Connack {
+                session_present,
+                return_code,
+            } => {
+                body.write_u8(*session_present as u8)?;
+                body.write_u8(*return_code)?;
+                CONNACK << 4
+            }
+            Self::Publish {
+                dup,
+                qos,
+                retain,
+                topic,
+                packet_id,
+                payload,
+            } => {
+                Self::write_string(&mut body, topic)?;
+                if let Some(packet_id) = packet_id {
+                    body.write_u16::<BigEndian>(*packet_id)?;
+                }
+                body.write_all(payload)?;
+                (PUBLISH << 4) | ((*dup as u8) << 3) | (qos << 1) | (*retain as u8)
+            }
+            Self::Puback { packet_id } => {
+                body.write_u16::<BigEndian>(*packet_id)?;
+                PUBACK << 4
+            }
+            Self::Suback {
+                packet_id,
+                return_codes,
+            } => {
+                body.write_u16::<BigEndian>(*packet_id)?;
+                body.write_all(return_codes)?;
+                SUBACK << 4
+            }
+            Self::Unsuback { packet_id } => {
+                body.write_u16::<BigEndian>(*packet_id)?;
+                UNSUBACK << 4
+            }
+            Self::Pingresp => PINGRESP << 4,
+            _ => {
+                return Err(Error::MalformedMqttPacket(format!(
+                    "packet is not sent by the server: [{:?}]",
+                    self
+                )))
+            }
+        };
+
+        // Write the fixed header.
+        writer.write_u8(first_byte)?;
+        Self::write_remaining_length(writer, body.len() as u32)?;
+
+        // Write the rest of the packet.
+        writer.write_all(&body)?;
+
+        Ok(())
+    }
+
+    fn read_connect(body: &mut Cursor<Vec<u8>>) -> error::Result<Self> {
+        // Read the variable header.
+        let protocol_name = Self::read_string(body)?;
+        let protocol_level = body.read_u8()?;
+        let connect_flags = body.read_u8()?;
+        let keep_alive = body.read_u16::<BigEndian>()?;
+
+        // Read the payload.
+        let client_id = Self::read_string(body)?;
+
+        let will = if 0 != connect_flags & 0b0000_0100 {
+            let topic = Self::read_string(body)?;
+            let message_size = body.read_u16::<BigEndian>()?;
+            let mut message: Vec<u8> = vec![0; message_size as usize];
+            body.read_exact(&mut message)?;
+
+            Some(MqttWill {
+                topic,
+                message,
+                qos: (connect_flags >> 3) & 0b11,
+            })
+        } else {
+            None
+        };
+
+        let username = if 0 != connect_flags & 0b1000_0000 {
+            Some(Self::read_string(body)?)
+        } else {
+            None
+        };
+
+        Ok(Self::Connect {
+            protocol_name,
+            protocol_level,
+            clean_session: 0 != connect_flags & 0b0000_0010,
+            keep_alive,
+            client_id,
+            will,
+            username,
+        })
+    }
+
+    fn read_publish(flags: u8, body: &mut Cursor<Vec<u8>>) -> error::Result<Self> {
+        let qos = (flags >> 1) & 0b11;
+        let topic = Self::read_string(body)?;
+        let packet_id = if qos > 0 {
+            Some(body.read_u16::<BigEndian>()?)
+        } else {
+            None
+        };
+
+        let mut payload: Vec<u8> = Vec::new();
+        body.read_to_end(&mut payload)?;
+
+        Ok(Self::Publish {
+            dup: 0 != flags & 0b1000,
+            qos,
+            retain: 0 != flags & 0b0001,
+            topic,
+            packet_id,
+            payload,
+        })
+    }
+
+    fn read_subscribe(body: &mut Cursor<Vec<u8>>) -> error::Result<Self> {
+        let packet_id = body.read_u16::<BigEndian>()?;
+
+        let mut filters: Vec<(String, u8)> = Vec::new();
+        while (body.position() as usize) < body.get_ref().len() {
+            let filter = Self::read_string(body)?;
+            let qos = body.read_u8()?;
+            filters.push((filter, qos));
+        }
+
+        if filters.is_empty() {
+            return Err(Error::MalformedMqttPacket(
+                "SUBSCRIBE without topic filters".to_owned(),
+            ));
+        }
+
+        Ok(Self::Subscribe { packet_id, filters })
+    }
+
+    fn read_unsubscribe(body: &mut Cursor<Vec<u8>>) -> error::Result<Self> {
+        let packet_id = body.read_u16::<BigEndian>()?;
+
+        let mut filters: Vec<String> = Vec::new();
+        while (body.position() as usize) < body.get_ref().len() {
+            filters.push(Self::read_string(body)?);
+        }
+
+        if filters.is_empty() {
+            return Err(Error::MalformedMqttPacket(
+                "UNSUBSCRIBE without topic filters".to_owned(),
+            ));
+        }
+
+        Ok(Self::Unsubscribe { packet_id, filters })
+    }
+
+    fn read_remaining_length(reader: &mut impl Read) -> error::Result<u32> {
+        let mut remaining_length: u32 = 0;
+        let mut shift = 0;
+        loop {
+            let byte = reader.read_u8()?;
+            remaining_length |= ((byte & 0x7f) as u32) << shift;
+
+            if 0 == byte & 0x80 {
+                return Ok(remaining_length);
+            }
+
+            shift += 7;
+            if shift > 21 {
+                return Err(Error::MalformedMqttPacket(
+                    "remaining length exceeds four bytes".to_owned(),
+                ));
+            }
+        }
+    }
+
+    fn write_remaining_length(writer: &mut impl Write, mut length: u32) -> error::Result<()> {
+        if length > MAX_REMAINING_LENGTH {
+            return Err(Error::MalformedMqttPacket(format!(
+                "remaining length ({}) is too large",
+                length
+            )));
+        }
+
+        loop {
+            let mut byte = (length % 128) as u8;
+            length /= 128;
+            if length > 0 {
+                byte |= 0x80;
+            }
+
+            writer.write_u8(byte)?;
+
+            if 0 == length {
+                return Ok(());
+            }
+        }
+    }
+
+    fn read_string(reader: &mut impl Read) -> error::Result<String> {
+        let size = reader.read_u16::<BigEndian>()?;
+
+        let mut bytes: Vec<u8> = vec![0; size as usize];
+        reader.read_exact(&mut bytes)?;
+
+        Ok(String::from_utf8(bytes)?)
+    }
+
+    fn write_string(writer: &mut impl Write, string: &str) -> error::Result<()> {
+        writer.write_u16::<BigEndian>(string.len() as u16)?;
+        writer.write_all(string.as_bytes())?;
+
+        Ok(())
+    }
+}
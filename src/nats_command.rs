@@ -0,0 +1,117 @@
+use std::io::{BufRead, Read};
+
+use crate::error::{self, Error};
+
+const MAX_LINE_SIZE: usize = 64 * 1024;
+
+#[derive(Debug)]
+pub enum NatsCommand {
+    Connect {
+        options: String,
+    },
+    Pub {
+        subject: String,
+        reply_to: Option<String>,
+        payload: Vec<u8>,
+    },
+    Sub {
+        subject: String,
+        queue_group: Option<String>,
+        sid: String,
+    },
+    Unsub {
+        sid: String,
+        max_messages: Option<u64>,
+    },
+    Ping,
+    Pong,
+}
+
+impl NatsCommand {
+    pub fn read(reader: &mut impl BufRead, max_payload: usize) -> error::Result<Option<Self>> {
+        // Read the control line, stopping if the peer closed the connection.
+        let mut line = String::new();
+        if 0 == reader.take(MAX_LINE_SIZE as u64 + 1).read_line(&mut line)? {
+            return Ok(None);
+        }
+        if line.len() > MAX_LINE_SIZE {
+            return Err(Error::FrameTooLarge(format!(
+                "control line exceeds {} bytes",
+                MAX_LINE_SIZE
+            )));
+        }
+        let line = line.trim_end_matches(['\r', '\n']);
+
+        // The operation name is case insensitive, and CONNECT's options may
+        // contain whitespace.
+        let (operation, arguments) = line.split_once([' ', '\t']).unwrap_or((line, ""));
+        let operation = operation.to_ascii_uppercase();
+        let tokens: Vec<&str> = arguments.split_whitespace().collect();
+
+        let command = match (operation.as_str(), tokens.as_slice()) {
+            ("CONNECT", _) => Self::Connect {
+                options: arguments.trim().to_owned(),
+            },
+            ("PUB", [subject, size]) => Self::Pub {
+                subject: (*subject).to_owned(),
+                reply_to: None,
+                payload: Self::read_payload(reader, size, max_payload)?,
+            },
+            ("PUB", [subject, reply_to, size]) => Self::Pub {
+                subject: (*subject).to_owned(),
+                reply_to: Some((*reply_to).to_owned()),
+                payload: Self::read_payload(reader, size, max_payload)?,
+            },
+            ("SUB", [subject, sid]) => Self::Sub {
+                subject: (*subject).to_owned(),
+                queue_group: None,
+                sid: (*sid).to_owned(),
+            },
+            ("SUB", [subject, queue_group, sid]) => Self::Sub {
+                subject: (*subject).to_owned(),
+                queue_group: Some((*queue_group).to_owned()),
+                sid: (*sid).to_owned(),
+            },
+            ("UNSUB", [sid]) => Self::Unsub {
+                sid: (*sid).to_owned(),
+                max_messages: None,
+            },
+            ("UNSUB", [sid, max_messages]) => Self::Unsub {
+                sid: (*sid).to_owned(),
+                max_messages: Some(max_messages.parse().map_err(|_| {
+                    Error::MalformedNatsCommand(format!("invalid max_msgs: {}", max_messages))
+                })?),
+            },
+            ("PING", []) => Self::Ping,
+            ("PONG", []) => Self::Pong,
+            _ => return Err(Error::MalformedNatsCommand(line.to_owned())),
+        };
+
+        Ok(Some(command))
+    }
+
+    fn read_payload(
+        reader: &mut impl BufRead,
+        size: &str,
+        max_payload: usize,
+    ) -> error::Result<Vec<u8>> {
+        let size: usize = size
+            .parse()
+            .map_err(|_| Error::MalformedNatsCommand(format!("invalid payload size: {}", size)))?;
+        if size > max_payload {
+            return Err(Error::NatsPayloadTooLarge(size));
+        }
+
+        // Read the payload and its terminating CRLF.
+        let mut payload: Vec<u8> = vec![0; size + 2];
+        reader.read_exact(&mut payload)?;
+        if !payload.ends_with(b"\r\n") {
+            return Err(Error::MalformedNatsCommand(
+                "payload is not terminated by CRLF".to_owned(),
+            ));
+        }
+        payload.truncate(size);
+
+        Ok(payload)
+    }
+}
@@ -0,0 +1,485 @@
+use std::collections::HashMap;
+use std::io::{BufReader, Write};
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crossbeam::channel::{self, Receiver, Sender};
+use polling::Poller;
+use uuid::Uuid;
+
+use crate::consumer_group::GROUP_HEADER;
+use crate::error::{self, Error};
+use crate::event::Event;
+use crate::message::Message;
+use crate::nats_command::NatsCommand;
+use crate::publish_gate::{self, PublishControl, PublishGate};
+use crate::shutdown::{self, ShutdownReceiver, ShutdownSender};
+use crate::subscriber::Subscriber;
+use crate::topic_filter::TopicFilter;
+
+const NATS_STREAM_POLL_KEY: usize = 0;
+
+const MAX_PAYLOAD: usize = 1024 * 1024;
+
+// The message header carrying the subject replies should be published to.
+pub const REPLY_TO_HEADER: &str = "reply-to";
+
+pub struct NatsHandler {
+    message_sender: Sender<Message>,
+    poller: Arc<Poller>,
+    handler_thread: Option<JoinHandle<()>>,
+    shutdown: ShutdownSender,
+}
+
+impl NatsHandler {
+    pub fn new(
+        id: Uuid,
+        stream: TcpStream,
+        event_sender: Sender<Event>,
+        publish_control: PublishControl,
+    ) -> error::Result<Self> {
+        let (message_sender, message_receiver): (Sender<Message>, Receiver<Message>) =
+            channel::unbounded();
+
+        let poller = Arc::new(Poller::new()?);
+        poller.add(&stream, polling::Event::readable(NATS_STREAM_POLL_KEY))?;
+        let gate = publish_control.gate(
+            stream.peer_addr()?.ip().to_string(),
+            publish_gate::poller_waker(poller.clone()),
+        );
+
+        let (shutdown, shutdown_receiver) = shutdown::signal(poller.clone());
+        let shutdown = shutdown.with_stream(stream.try_clone()?);
+
+        Ok(Self {
+            message_sender,
+            poller: poller.clone(),
+            handler_thread: Some(Self::start_handler_thread(
+                NatsSession::new(id, stream, event_sender, gate),
+                message_receiver,
+                poller,
+                shutdown_receiver,
+            )),
+            shutdown,
+        })
+    }
+
+    fn start_handler_thread(
+        session: NatsSession,
+        message_receiver: Receiver<Message>,
+        poller: Arc<Poller>,
+        shutdown: ShutdownReceiver,
+    ) -> JoinHandle<()> {
+        thread::spawn(move || {
+            let id = session.id;
+            let event_sender = session.event_sender.clone();
+
+            Self::handle_client(session, message_receiver, poller, shutdown);
+
+            // Let the PubSub forget about this client.
+            if let Err(e) = event_sender.send(Event::Disconnection(id)) {
+                log::error!("Failed sending Disconnection event for [{}]: [{}]", id, e);
+            }
+        })
+    }
+
+    fn handle_client(
+        mut session: NatsSession,
+        message_receiver: Receiver<Message>,
+        poller: Arc<Poller>,
+        shutdown: ShutdownReceiver,
+    ) {
+        // Connections reset right after being accepted have no peer address.
+        let address = match session.stream.peer_addr() {
+            Ok(address) => address,
+            Err(e) => {
+                log::error!("Failed handling NATS client [{}]: [{}]", session.id, e);
+                return;
+            }
+        };
+        log::info!(
+            "Handling NATS client: id=[{}], address=[{}]",
+            session.id,
+            address
+        );
+
+        // Greet the client with the server's information.
+        if let Err(e) = session.write_info() {
+            log::error!("Error writing INFO to [{}]: [{}]", session.id, e);
+            return;
+        }
+
+        let stream = session.stream.try_clone().unwrap();
+        let mut reader = BufReader::new(&stream);
+        let mut poll_events: Vec<polling::Event> = Vec::new();
+        while !shutdown.is_requested() {
+            // Stop reading commands while the client is held back by its
+            // publications.
+            let paused = session.gate.is_paused();
+            let interest = if paused {
+                polling::Event::none(NATS_STREAM_POLL_KEY)
+            } else {
+                polling::Event::readable(NATS_STREAM_POLL_KEY)
+            };
+
+            // Modify the poller's interest in the client's stream.
+            // This is required to receive multiple read events on macOS.
+            if let Err(e) = poller.modify(&session.stream, interest) {
+                log::error!(
+                    "Failed to modify the poller's interest in NATS client [{}]: [{}]",
+                    session.id,
+                    e
+                );
+                break;
+            }
+
+            // Clear all previous poll events.
+            poll_events.clear();
+
+            // Wait for an I/O event, a published message or a shutdown
+            // request. Commands already buffered are handled without waiting.
+            let timeout = if reader.buffer().is_empty() || paused {
+                None
+            } else {
+                Some(Duration::ZERO)
+            };
+            if let Err(e) = poller.wait(&mut poll_events, session.gate.timeout(timeout)) {
+                log::error!("Failed polling for events from [{}]: [{}]", session.id, e);
+                continue;
+            }
+
+            // Forward published messages to the client.
+            if let Err(e) = session.write_messages(&message_receiver) {
+                log::error!("Error writing messages to [{}]: [{}]", session.id, e);
+                break;
+            }
+
+            // Check if the client sent anything.
+            if paused || (poll_events.is_empty() && reader.buffer().is_empty()) {
+                continue;
+            }
+
+            // Receive a command from the client.
+            let command = match NatsCommand::read(&mut reader, MAX_PAYLOAD) {
+                Ok(Some(command)) => command,
+                Ok(None) => break,
+                Err(e) => {
+                    log::error!(
+                        "Error receiving NATS command from [{}]: [{}]",
+                        session.id,
+                        e
+                    );
+                    let error = match e {
+                        Error::MalformedNatsCommand(_) => Some("Unknown Protocol Operation"),
+                        Error::NatsPayloadTooLarge(_) => Some("Maximum Payload Violation"),
+                        Error::FrameTooLarge(_) => Some("Maximum Control Line Exceeded"),
+                        _ => None,
+                    };
+                    if let Some(error) = error {
+                        let _ = session.write_error(error);
+                    }
+                    break;
+                }
+            };
+
+            // Handle the command.
+            if let Err(e) = session.handle_command(command) {
+                log::error!("Error handling NATS command from [{}]: [{}]", session.id, e);
+                break;
+            }
+        }
+    }
+}
+
+impl Subscriber for NatsHandler {
+    fn publish(&self, message: Message) -> error::Result<()> {
+        self.message_sender.send(message)?;
+        self.poller.notify()?;
+        Ok(())
+    }
+
+    fn queue_len(&self) -> usize {
+        self.message_sender.len()
+    }
+}
+
+impl Drop for NatsHandler {
+    fn drop(&mut self) {
+        if let Some(thread) = self.handler_thread.take() {
+            // Indicate the handler thread that it should terminate, waking
+            // it up.
+            self.shutdown.request();
+
+            // Join the handler thread.
+            thread.join().unwrap();
+        }
+    }
+}
+
+struct NatsSubscription {
+    subject: String,
+    filter: TopicFilter,
+    queue_group: Option<String>,
+    max_messages: Option<u64>,
+    delivered: u64,
+}
+
+struct NatsSession {
+    id: Uuid,
+    stream: TcpStream,
+    event_sender: Sender<Event>,
+    verbose: bool,
+    subscriptions: HashMap<String, NatsSubscription>,
+    gate: PublishGate,
+}
+
+impl NatsSession {
+    fn new(id: Uuid, stream: TcpStream, event_sender: Sender<Event>, gate: PublishGate) -> Self {
+        Self {
+            id,
+            stream,
+            event_sender,
+            verbose: false,
+            subscriptions: HashMap::new(),
+            gate,
+        }
+    }
+
+    fn handle_command(&mut self, command: NatsCommand) -> error::Result<()> {
+        log::debug!("NATS command from [{}]: [{:?}]", self.id, command);
+
+        match command {
+            NatsCommand::Connect { options } => {
+                // Only the verbose option affects the broker's behavior.
+                let options: String = options.split_whitespace().collect();
+                self.verbose = options.contains("\"verbose\":true");
+                self.write_ok()?;
+            }
+            NatsCommand::Pub {
+                subject,
+                reply_to,
+                payload,
+            } => self.handle_pub(subject, reply_to, payload)?,
+            NatsCommand::Sub {
+                subject,
+                queue_group,
+                sid,
+            } => self.handle_sub(subject, queue_group, sid)?,
+            NatsCommand::Unsub { sid, max_messages } => self.handle_unsub(sid, max_messages)?,
+            NatsCommand::Ping => self.stream.write_all(b"PONG\r\n")?,
+            NatsCommand::Pong => {}
+        }
+
+        Ok(())
+    }
+
+    fn handle_pub(
+        &mut self,
+        subject: String,
+        reply_to: Option<String>,
+        payload: Vec<u8>,
+    ) -> error::Result<()> {
+        // Messages may only be published to concrete subjects.
+        if !matches!(TopicFilter::nats(&subject), Some(TopicFilter::Exact(_))) {
+            return self.write_error("Invalid Publish Subject");
+        }
+
+        let mut message = Message::new(subject, payload);
+        if let Some(reply_to) = reply_to {
+            message
+                .headers_mut()
+                .insert(REPLY_TO_HEADER.to_owned(), reply_to);
+        }
+        match self.gate.pass_one(message) {
+            Ok(message) => self.event_sender.send(Event::Publish(message))?,
+            Err(e) => return self.write_error(&e.to_string()),
+        }
+
+        self.write_ok()
+    }
+
+    fn handle_sub(
+        &mut self,
+        subject: String,
+        queue_group: Option<String>,
+        sid: String,
+    ) -> error::Result<()> {
+        let filter = match TopicFilter::nats(&subject) {
+            Some(filter) => filter,
+            None => return self.write_error("Invalid Subject"),
+        };
+
+        // Register the subscription, either directly or as a member of its
+        // queue group.
+        match &queue_group {
+            Some(queue_group) => self.event_sender.send(Event::GroupSubscribe(
+                self.id,
+                queue_group.clone(),
+                filter.clone(),
+            ))?,
+            None => self
+                .event_sender
+                .send(Event::Subscribe(self.id, vec![filter.clone()]))?,
+        }
+
+        // Resubscribing with an existing sid replaces its subscription.
+        let previous = self.subscriptions.insert(
+            sid,
+            NatsSubscription {
+                subject,
+                filter,
+                queue_group,
+                max_messages: None,
+                delivered: 0,
+            },
+        );
+        if let Some(previous) = previous {
+            self.unregister(previous)?;
+        }
+
+        self.write_ok()
+    }
+
+    fn handle_unsub(&mut self, sid: String, max_messages: Option<u64>) -> error::Result<()> {
+        match (self.subscriptions.get_mut(&sid), max_messages) {
+            // Unsubscribe once the subscription received the maximum number
+            // of messages.
+            (Some(subscription), Some(max_messages)) if subscription.delivered < max_messages => {
+                subscription.max_messages = Some(max_messages);
+            }
+            (Some(_), _) => {
+                let subscription = self.subscriptions.remove(&sid).unwrap();
+                self.unregister(subscription)?;
+            }
+            (None, _) => {}
+        }
+
+        self.write_ok()
+    }
+
+    fn write_messages(&mut self, message_receiver: &Receiver<Message>) -> error::Result<()> {
+        for mut message in message_receiver.try_iter() {
+            // Copies sent on behalf of a queue group go to a single
+            // subscription of that group, other copies go to all plain
+            // subscriptions.
+            let queue_group = message.headers_mut().remove(GROUP_HEADER);
+            let mut sids: Vec<String> = self
+                .subscriptions
+                .iter()
+                .filter(|(_, subscription)| {
+                    subscription.queue_group == queue_group
+                        && subscription.filter.matches(&message.topic)
+                })
+                .map(|(sid, _)| sid.clone())
+                .collect();
+            if queue_group.is_some() {
+                sids.truncate(1);
+            }
+
+            for sid in sids.into_iter() {
+                self.write_message(&sid, &message)?;
+
+                // Remove subscriptions that reached their maximum.
+                let subscription = self.subscriptions.get_mut(&sid).unwrap();
+                subscription.delivered += 1;
+                if subscription
+                    .max_messages
+                    .is_some_and(|max_messages| subscription.delivered >= max_messages)
+                {
+                    let subscription = self.subscriptions.remove(&sid).unwrap();
+                    self.unregister(subscription)?;
+                }
+            }
+        }
+
+        self.stream.flush()?;
+
+        Ok(())
+    }
+
+    fn write_message(&mut self, sid: &str, message: &Message) -> error::Result<()> {
+        match message.headers.get(REPLY_TO_HEADER) {
+            Some(reply_to) => write!(
+                self.stream,
+                "MSG {} {} {} {}\r\n",
+                message.topic,
+                sid,
+                reply_to,
+                message.data.len()
+            )?,
+            None => write!(
+                self.stream,
+                "MSG {} {} {}\r\n",
+                message.topic,
+                sid,
+                message.data.len()
+            )?,
+        }
+        self.stream.write_all(&message.data)?;
+        self.stream.write_all(b"\r\n")?;
+
+        Ok(())
+    }
+
+    fn write_info(&mut self) -> error::Result<()> {
+        let local_address = self.stream.local_addr()?;
+        write!(
+            self.stream,
+            "INFO {{\"server_id\":\"{}\",\"server_name\":\"pubsub\",\"version\":\"{}\",\
+             \"proto\":1,\"host\":\"{}\",\"port\":{},\"headers\":false,\"max_payload\":{},\
+             \"client_id\":{}}}\r\n",
+            Uuid::new_v4().simple(),
+            env!("CARGO_PKG_VERSION"),
+            local_address.ip(),
+            local_address.port(),
+            MAX_PAYLOAD,
+            self.id.as_u128() as u64,
+        )?;
+
+        Ok(())
+    }
+
+    fn write_ok(&mut self) -> error::Result<()> {
+        if self.verbose {
+            self.stream.write_all(b"+OK\r\n")?;
+        }
+
+        Ok(())
+    }
+
+    fn write_error(&mut self, error: &str) -> error::Result<()> {
+        write!(self.stream, "-ERR '{}'\r\n", error)?;
+        Ok(())
+    }
+
+    fn unregister(&mut self, subscription: NatsSubscription) -> error::Result<()> {
+        log::debug!(
+            "Unregistering NATS subscription of [{}] to: [{}]",
+            self.id,
+            subscription.subject
+        );
+
+        // Keep the registration while other subscriptions depend on it.
+        let shared = self.subscriptions.values().any(|other| {
+            other.queue_group == subscription.queue_group && other.filter == subscription.filter
+        });
+        if shared {
+            return Ok(());
+        }
+
+        match subscription.queue_group {
+            Some(queue_group) => self.event_sender.send(Event::GroupUnsubscribe(
+                self.id,
+                queue_group,
+                subscription.filter,
+            ))?,
+            None => self
+                .event_sender
+                .send(Event::Unsubscribe(self.id, vec![subscription.filter]))?,
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,152 @@
+use std::str::FromStr;
+
+use crossbeam::channel::{self, Receiver, SendError, Sender};
+
+use crate::message::Message;
+
+// The priority of a message: "high", "normal" or "low".
+pub const PRIORITY_HEADER: &str = "priority";
+
+const LANES: usize = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Priority {
+    High,
+    Normal,
+    Low,
+}
+
+impl Priority {
+    // Messages without a valid priority are normal.
+    pub fn of(message: &Message) -> Self {
+        message
+            .headers
+            .get(PRIORITY_HEADER)
+            .and_then(|priority| priority.parse().ok())
+            .unwrap_or(Self::Normal)
+    }
+
+    fn lane(self) -> usize {
+        match self {
+            Self::High => 0,
+            Self::Normal => 1,
+            Self::Low => 2,
+        }
+    }
+}
+
+impl FromStr for Priority {
+    type Err = String;
+
+    fn from_str(priority: &str) -> Result<Self, Self::Err> {
+        match priority {
+            "high" => Ok(Self::High),
+            "normal" => Ok(Self::Normal),
+            "low" => Ok(Self::Low),
+            _ => Err(format!("unknown priority: {}", priority)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DrainPolicy {
+    // Drain a lane only once all higher priority lanes are empty.
+    Strict,
+    // Drain up to the weight of each lane in turn, highest priority first,
+    // so that lower priority lanes are never starved.
+    Weighted([u32; LANES]),
+}
+
+impl FromStr for DrainPolicy {
+    type Err = String;
+
+    // Written as "strict", "weighted" or "weighted:HIGH:NORMAL:LOW".
+    fn from_str(policy: &str) -> Result<Self, Self::Err> {
+        match policy.split_once(':') {
+            None if "strict" == policy => Ok(Self::Strict),
+            None if "weighted" == policy => Ok(Self::Weighted([4, 2, 1])),
+            Some(("weighted", weights)) => {
+                let weights: Vec<u32> = weights
+                    .split(':')
+                    .map(|weight| match weight.parse() {
+                        Ok(weight) if weight > 0 => Ok(weight),
+                        _ => Err(format!("invalid weight: {}", weight)),
+                    })
+                    .collect::<Result<_, _>>()?;
+                let weights: [u32; LANES] = weights
+                    .try_into()
+                    .map_err(|_| format!("expected {} weights, got: {}", LANES, policy))?;
+                Ok(Self::Weighted(weights))
+            }
+            _ => Err(format!("unknown drain policy: {}", policy)),
+        }
+    }
+}
+
+pub fn lanes(policy: DrainPolicy) -> (LaneSender, LaneReceiver) {
+    let (senders, receivers): (Vec<Sender<Message>>, Vec<Receiver<Message>>) =
+        (0..LANES).map(|_| channel::unbounded()).unzip();
+
+    (
+        LaneSender { senders },
+        LaneReceiver {
+            receivers,
+            policy,
+            budgets: [0; LANES],
+        },
+    )
+}
+
+// Queues each message in the lane of its priority.
+#[derive(Clone, Debug)]
+pub struct LaneSender {
+    senders: Vec<Sender<Message>>,
+}
+
+impl LaneSender {
+    pub fn send(&self, message: Message) -> Result<(), SendError<Message>> {
+        self.senders[Priority::of(&message).lane()].send(message)
+    }
+
+    pub fn queue_len(&self) -> usize {
+        self.senders.iter().map(|sender| sender.len()).sum()
+    }
+}
+
+#[derive(Debug)]
+pub struct LaneReceiver {
+    receivers: Vec<Receiver<Message>>,
+    policy: DrainPolicy,
+    // The messages each lane may still send in the current weighted round.
+    budgets: [u32; LANES],
+}
+
+impl LaneReceiver {
+    pub fn try_recv(&mut self) -> Option<Message> {
+        let weights = match self.policy {
+            DrainPolicy::Strict => {
+                return self
+                    .receivers
+                    .iter()
+                    .find_map(|receiver| receiver.try_recv().ok())
+            }
+            DrainPolicy::Weighted(weights) => weights,
+        };
+
+        // Start a new round once no lane with messages has budget left.
+        for _ in 0..2 {
+            for (lane, receiver) in self.receivers.iter().enumerate() {
+                if 0 == self.budgets[lane] {
+                    continue;
+                }
+                if let Ok(message) = receiver.try_recv() {
+                    self.budgets[lane] -= 1;
+                    return Some(message);
+                }
+            }
+            self.budgets = weights;
+        }
+
+        None
+    }
+}
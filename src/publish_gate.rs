@@ -0,0 +1,165 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use polling::Poller;
+
+use crate::backpressure::{Backpressure, DrainWaker};
+use crate::error::{self, Error};
+use crate::message::Message;
+use crate::rate_limiter::{RateDecision, RateLimiter};
+use crate::scheduler::ScheduleLimits;
+
+// The checks every publication goes through, whichever protocol it arrived
+// with. Shared by all handlers, each publisher gets a gate of its own.
+#[derive(Clone)]
+pub struct PublishControl {
+    rate_limiter: RateLimiter,
+    backpressure: Arc<Backpressure>,
+    schedule_limits: Arc<ScheduleLimits>,
+}
+
+impl PublishControl {
+    pub fn new(
+        rate_limiter: RateLimiter,
+        backpressure: Arc<Backpressure>,
+        schedule_limits: Arc<ScheduleLimits>,
+    ) -> Self {
+        Self {
+            rate_limiter,
+            backpressure,
+            schedule_limits,
+        }
+    }
+
+    // Publishers are identified by their IP until they authenticate. The
+    // waker is called when a congested topic the publisher is paused by
+    // drained.
+    pub fn gate(&self, identity: String, waker: DrainWaker) -> PublishGate {
+        PublishGate {
+            rate_limiter: self.rate_limiter.clone(),
+            backpressure: self.backpressure.clone(),
+            schedule_limits: self.schedule_limits.clone(),
+            identity,
+            waker,
+            throttled_until: None,
+            lossless_topics: HashSet::new(),
+        }
+    }
+}
+
+// Wakes a handler waiting on the poller.
+pub fn poller_waker(poller: Arc<Poller>) -> DrainWaker {
+    Arc::new(move || {
+        if let Err(e) = poller.notify() {
+            log::error!("Failed notifying a paused publisher: [{}]", e);
+        }
+    })
+}
+
+// Lets a publisher's messages through, and holds the publisher back while
+// it's throttled or while a lossless topic it published to is congested.
+// Handlers stop reading from paused publishers, but keep delivering messages
+// to them.
+pub struct PublishGate {
+    rate_limiter: RateLimiter,
+    backpressure: Arc<Backpressure>,
+    schedule_limits: Arc<ScheduleLimits>,
+    identity: String,
+    waker: DrainWaker,
+    throttled_until: Option<Instant>,
+    lossless_topics: HashSet<String>,
+}
+
+impl PublishGate {
+    // Reserves the scheduling budget of the messages and applies the rate
+    // limits of the publisher and of the topics, returning the messages let
+    // through and the reasons the other messages were rejected for.
+    pub fn pass(&mut self, messages: Vec<Message>) -> (Vec<Message>, Vec<Error>) {
+        let mut delay = Duration::ZERO;
+        let mut rejections: Vec<Error> = Vec::new();
+        let mut accepted: Vec<Message> = Vec::with_capacity(messages.len());
+        for mut message in messages.into_iter() {
+            let decision = match self.schedule_limits.reserve(&mut message) {
+                Ok(()) => {
+                    match self.rate_limiter.acquire(
+                        &self.identity,
+                        &message.topic,
+                        message.data.len(),
+                    ) {
+                        RateDecision::Allowed => Ok(()),
+                        RateDecision::Delayed(message_delay) => {
+                            delay = delay.max(message_delay);
+                            Ok(())
+                        }
+                        RateDecision::Rejected => Err(Error::RateLimited),
+                    }
+                }
+                Err(e) => Err(e),
+            };
+            if let Err(e) = decision {
+                log::warn!(
+                    "Rejecting message to topic [{}] from [{}]: [{}]",
+                    message.topic,
+                    self.identity,
+                    e
+                );
+                rejections.push(e);
+                continue;
+            }
+            if self.backpressure.is_lossless(&message.topic)
+                && !self.lossless_topics.contains(&*message.topic)
+            {
+                self.lossless_topics.insert(message.topic.to_string());
+            }
+            accepted.push(message);
+        }
+
+        // Throttled messages are let through, the publisher pays for them
+        // by being paused until it's back within its limits.
+        if !delay.is_zero() {
+            self.throttled_until = Some(Instant::now() + delay);
+        }
+
+        (accepted, rejections)
+    }
+
+    // Authenticated publishers are identified by their principal, so that
+    // its limits hold across addresses, and publishers behind one address
+    // don't share limits.
+    pub fn authenticate(&mut self, principal: &str) {
+        self.identity = principal.to_owned();
+    }
+
+    pub fn pass_one(&mut self, message: Message) -> error::Result<Message> {
+        let (mut accepted, mut rejections) = self.pass(vec![message]);
+        match rejections.pop() {
+            Some(e) => Err(e),
+            None => Ok(accepted.remove(0)),
+        }
+    }
+
+    pub fn is_paused(&mut self) -> bool {
+        match self.throttled_until {
+            Some(throttled_until) if Instant::now() < throttled_until => return true,
+            _ => self.throttled_until = None,
+        }
+
+        !self.lossless_topics.is_empty()
+            && self
+                .backpressure
+                .wait_for_drain(&self.lossless_topics, &self.waker)
+    }
+
+    // Shortens the wait of a throttled publisher, so that it resumes in time.
+    // Publishers paused by congestion are woken up instead.
+    pub fn timeout(&self, timeout: Option<Duration>) -> Option<Duration> {
+        match self.throttled_until {
+            Some(throttled_until) => {
+                let remaining = throttled_until.saturating_duration_since(Instant::now());
+                Some(timeout.map_or(remaining, |timeout| timeout.min(remaining)))
+            }
+            None => timeout,
+        }
+    }
+}
@@ -1,85 +1,135 @@
 use std::net::TcpStream;
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
 use std::thread::{self, JoinHandle};
 use std::time::Duration;
 
 use crossbeam::channel::Sender;
 use polling::Poller;
+use uuid::Uuid;
 
+use crate::admission::ERROR_TOPIC;
+use crate::batch::{self, BATCH_TOPIC};
+use crate::checksum::ChecksumPolicy;
+use crate::compression;
+use crate::error::{self, Error};
 use crate::event::Event;
+use crate::heartbeat::Heartbeat;
 use crate::message::Message;
+use crate::publish_gate::{self, PublishControl};
+use crate::shutdown::{self, ShutdownReceiver, ShutdownSender};
+use crate::transaction::{Transaction, TransactionLimits};
 
 const PUBLISHER_STREAM_POLL_KEY: usize = 0;
 const POLL_TIMEOUT_MS: u64 = 300;
 
 pub struct PublisherHandler {
     handler_thread: Option<JoinHandle<()>>,
-    terminate: Arc<Mutex<bool>>,
+    shutdown: ShutdownSender,
 }
 
 impl PublisherHandler {
-    pub fn new(stream: TcpStream, event_sender: Sender<Event>) -> Self {
-        let terminate = Arc::new(Mutex::new(false));
-        Self {
+    pub fn new(
+        id: Uuid,
+        stream: TcpStream,
+        event_sender: Sender<Event>,
+        heartbeat: Heartbeat,
+        publish_control: PublishControl,
+        transaction_limits: TransactionLimits,
+    ) -> error::Result<Self> {
+        let poller = Arc::new(Poller::new()?);
+        poller.add(&stream, polling::Event::readable(PUBLISHER_STREAM_POLL_KEY))?;
+
+        let (shutdown, shutdown_receiver) = shutdown::signal(poller.clone());
+        let shutdown = shutdown.with_stream(stream.try_clone()?);
+
+        Ok(Self {
             handler_thread: Some(Self::start_handler_thread(
+                id,
                 stream,
                 event_sender,
-                terminate.clone(),
+                heartbeat,
+                publish_control,
+                transaction_limits,
+                poller,
+                shutdown_receiver,
             )),
-            terminate,
-        }
+            shutdown,
+        })
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn start_handler_thread(
+        id: Uuid,
         stream: TcpStream,
         event_sender: Sender<Event>,
-        terminate: Arc<Mutex<bool>>,
+        heartbeat: Heartbeat,
+        publish_control: PublishControl,
+        transaction_limits: TransactionLimits,
+        poller: Arc<Poller>,
+        shutdown: ShutdownReceiver,
     ) -> JoinHandle<()> {
-        thread::spawn(move || Self::handle_publisher(stream, event_sender, terminate))
+        thread::spawn(move || {
+            Self::handle_publisher(
+                stream,
+                &event_sender,
+                heartbeat,
+                publish_control,
+                transaction_limits,
+                poller,
+                shutdown,
+            );
+
+            // Let the PubSub forget about this publisher.
+            if let Err(e) = event_sender.send(Event::Disconnection(id)) {
+                log::error!("Failed sending Disconnection event for [{}]: [{}]", id, e);
+            }
+        })
     }
 
     fn handle_publisher(
         mut stream: TcpStream,
-        event_sender: Sender<Event>,
-        terminate: Arc<Mutex<bool>>,
+        event_sender: &Sender<Event>,
+        mut heartbeat: Heartbeat,
+        publish_control: PublishControl,
+        transaction_limits: TransactionLimits,
+        poller: Arc<Poller>,
+        shutdown: ShutdownReceiver,
     ) {
-        log::info!("Handling publisher: [{}]", stream.peer_addr().unwrap());
-
-        // Setup polling.
-        let poller = match Poller::new() {
-            Ok(poller) => poller,
+        // Connections reset right after being accepted have no peer address.
+        let address = match stream.peer_addr() {
+            Ok(address) => address,
             Err(e) => {
-                log::error!(
-                    "Failed to create a Poller for publisher [{}]: [{}]",
-                    stream.peer_addr().unwrap(),
-                    e
-                );
+                log::error!("Failed handling publisher: [{}]", e);
                 return;
             }
         };
-
-        // Add the publisher stream to the poller.
-        if let Err(e) = poller.add(&stream, polling::Event::readable(PUBLISHER_STREAM_POLL_KEY)) {
-            log::error!(
-                "Failed to add the publisher stream [{}] to the poller: [{}]",
-                stream.peer_addr().unwrap(),
-                e
-            );
-            return;
-        }
+        log::info!("Handling publisher: [{}]", address);
+        let mut gate = publish_control.gate(
+            address.ip().to_string(),
+            publish_gate::poller_waker(poller.clone()),
+        );
 
         // Receive messages from the publisher.
-        log::info!("Receiving messages from: [{}]", stream.peer_addr().unwrap());
+        log::info!("Receiving messages from: [{}]", address);
         let mut poll_events: Vec<polling::Event> = Vec::new();
-        while !(*terminate.lock().unwrap()) {
+        let mut transaction = Transaction::new(transaction_limits);
+        let mut checksum_policy = ChecksumPolicy::default();
+        while !shutdown.is_requested() {
+            // Stop reading from the publisher while it's held back by its
+            // publications.
+            let paused = gate.is_paused();
+            let interest = if paused {
+                polling::Event::none(PUBLISHER_STREAM_POLL_KEY)
+            } else {
+                polling::Event::readable(PUBLISHER_STREAM_POLL_KEY)
+            };
+
             // Modify the poller's interest in the publisher's stream.
             // This is required to receive multiple read events on macOS.
-            if let Err(e) =
-                poller.modify(&stream, polling::Event::readable(PUBLISHER_STREAM_POLL_KEY))
-            {
+            if let Err(e) = poller.modify(&stream, interest) {
                 log::error!(
                     "Failed to modify the poller's interest in publisher stream [{}]: [{}]",
-                    stream.peer_addr().unwrap(),
+                    address,
                     e
                 );
                 break;
@@ -88,57 +138,156 @@ impl PublisherHandler {
             // Clear all previous poll events.
             poll_events.clear();
 
-            // Wait for at least one I/O event or a timeout.
+            // Wait for at least one I/O event, a shutdown request or a
+            // timeout.
             let poll_events_number = match poller.wait(
                 &mut poll_events,
-                Some(Duration::from_millis(POLL_TIMEOUT_MS)),
+                gate.timeout(Some(Duration::from_millis(POLL_TIMEOUT_MS))),
             ) {
                 Ok(number) => number,
                 Err(e) => {
-                    log::error!(
-                        "Failed polling for events from [{}]: [{}]",
-                        stream.peer_addr().unwrap(),
-                        e,
-                    );
+                    log::error!("Failed polling for events from [{}]: [{}]", address, e,);
                     continue;
                 }
             };
 
             // Check if timeout has been reached.
             if 0 == poll_events_number {
+                // Paused publishers can't be heard from.
+                if paused {
+                    heartbeat.received();
+                }
+
+                // Disconnect publishers that stopped sending heartbeats.
+                if heartbeat.is_expired() {
+                    log::warn!("Publisher [{}] missed too many heartbeats", address);
+                    break;
+                }
+
                 continue;
             }
 
             // Receive a message from the publisher.
-            let message = match Message::read(&mut stream) {
+            let message = match checksum_policy.read(&mut stream) {
                 Ok(message) => message,
                 Err(e) => {
-                    log::error!(
-                        "Error receiving message from [{}]: [{}]",
-                        stream.peer_addr().unwrap(),
-                        e,
-                    );
+                    log::error!("Error receiving message from [{}]: [{}]", address, e,);
                     break;
                 }
             };
+            heartbeat.received();
 
-            // Send a Publish event.
-            if let Err(e) = event_sender.send(Event::Publish(message)) {
-                log::error!(
-                    "Failed sending Publish event from [{}]: [{}]",
-                    stream.peer_addr().unwrap(),
-                    e,
-                )
+            // Answer heartbeat frames instead of publishing them.
+            match heartbeat.handle_control_message(&message, &mut stream) {
+                Ok(true) => continue,
+                Ok(false) => {}
+                Err(e) => {
+                    log::error!("Error handling heartbeat from [{}]: [{}]", address, e,);
+                    break;
+                }
             }
+
+            // Require checksums on the following frames if asked to.
+            match checksum_policy.handle_control_message(&message, &mut stream) {
+                Ok(true) => continue,
+                Ok(false) => {}
+                Err(e) => {
+                    log::error!("Error handling checksums from [{}]: [{}]", address, e,);
+                    break;
+                }
+            }
+
+            // Handle the transaction control frames.
+            match transaction.handle_control_message(&message, &mut stream, event_sender) {
+                Ok(true) => continue,
+                Ok(false) => {}
+                Err(e) => {
+                    log::error!("Error handling transaction from [{}]: [{}]", address, e,);
+                    break;
+                }
+            }
+
+            // Unpack batches, which are published as a single event.
+            let messages = match Self::unpack(message) {
+                Ok(messages) => messages,
+                Err(e) => {
+                    log::error!("Error unpacking message from [{}]: [{}]", address, e,);
+                    break;
+                }
+            };
+
+            // Apply the scheduling limits and the rate limits of the
+            // publisher and of the topics.
+            let (mut accepted, rejections) = gate.pass(messages);
+
+            if !rejections.is_empty() {
+                transaction.roll_back();
+                if let Err(e) = Self::write_rejections(&mut stream, &rejections) {
+                    log::error!("Error writing error frame to [{}]: [{}]", address, e);
+                    break;
+                }
+            }
+
+            // Send a Publish event, or leave it to the commit.
+            let event = if transaction.is_open() {
+                let overflow = accepted
+                    .into_iter()
+                    .find_map(|message| transaction.buffer(message).err());
+                if let Some(e) = overflow {
+                    log::warn!("Rolling back transaction of [{}]: [{}]", address, e);
+                    if let Err(e) = Self::write_rejections(&mut stream, &[e]) {
+                        log::error!("Error writing error frame to [{}]: [{}]", address, e);
+                        break;
+                    }
+                }
+                None
+            } else if 1 == accepted.len() {
+                accepted.pop().map(Event::Publish)
+            } else if !accepted.is_empty() {
+                Some(Event::PublishBatch(accepted))
+            } else {
+                None
+            };
+            if let Some(event) = event {
+                if let Err(e) = event_sender.send(event) {
+                    log::error!("Failed sending Publish event from [{}]: [{}]", address, e,)
+                }
+            }
+        }
+    }
+
+    fn unpack(mut message: Message) -> error::Result<Vec<Message>> {
+        // Publishers may compress both batches and the messages in them.
+        compression::decompress_message(&mut message)?;
+        if BATCH_TOPIC != &*message.topic {
+            return Ok(vec![message]);
         }
+
+        let mut messages = batch::decode_batch(&message)?;
+        for message in messages.iter_mut() {
+            compression::decompress_message(message)?;
+        }
+
+        Ok(messages)
+    }
+
+    fn write_rejections(stream: &mut TcpStream, rejections: &[Error]) -> error::Result<()> {
+        // Report each rejected message.
+        for rejection in rejections.iter() {
+            Message::new(ERROR_TOPIC.to_owned(), rejection.to_string().into_bytes())
+                .write(stream)?;
+        }
+
+        Ok(())
     }
 }
 
 impl Drop for PublisherHandler {
     fn drop(&mut self) {
         if let Some(thread) = self.handler_thread.take() {
-            // Indicate the handler thread that it should terminate.
-            *self.terminate.lock().unwrap() = true;
+            // Indicate the handler thread that it should terminate, waking
+            // it up.
+            self.shutdown.request();
 
             // Join the handler thread.
             thread.join().unwrap();
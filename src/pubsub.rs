@@ -1,35 +1,63 @@
 use std::collections::HashMap;
-use std::net::TcpStream;
+use std::net::{IpAddr, TcpStream};
+use std::ops::Range;
+use std::sync::{Arc, Barrier, RwLock};
+use std::time::Duration;
 
 use crossbeam::channel::{self, Receiver, Sender};
 use uuid::Uuid;
 
+use crate::admission::Admission;
 use crate::background_tcp_listener::BackgroundTcpListener;
+use crate::backpressure::Backpressure;
+use crate::compression::Compression;
+use crate::config::Config;
 use crate::connection_kind::ConnectionKind;
+use crate::dead_letter::DeadLetters;
 use crate::error;
 use crate::event::Event;
+use crate::handshake::PendingHandshakes;
+use crate::heartbeat::Heartbeat;
+use crate::http_handler::HttpHandler;
 use crate::message::Message;
+use crate::metrics::Metrics;
+use crate::mqtt_handler::MqttHandler;
+use crate::nats_handler::NatsHandler;
+use crate::publish_gate::PublishControl;
 use crate::publisher_handler::PublisherHandler;
+use crate::quic_listener::{QuicListener, QuicSubscriber};
+use crate::rate_limiter::RateLimiter;
+use crate::redis_handler::RedisHandler;
+use crate::router::{self, RouterShard};
+use crate::scheduler::ScheduleLimits;
+use crate::stomp_handler::StompHandler;
+use crate::subscriber::{Subscriber, SubscriberHandlers};
 use crate::subscriber_handler::SubscriberHandler;
 use crate::subscription_request::SubscriptionRequest;
+use crate::topic_filter::TopicFilter;
+use crate::transaction::TransactionLimits;
 
 pub struct PubSub {
     _publisher_listener: BackgroundTcpListener,
     _subscriber_listener: BackgroundTcpListener,
-    publisher_handlers: Vec<PublisherHandler>,
-    subscriber_to_handler: HashMap<Uuid, SubscriberHandler>,
-    topic_to_subscribers: HashMap<String, Vec<Uuid>>,
+    _protocol_listeners: Vec<BackgroundTcpListener>,
+    _quic_listener: Option<QuicListener>,
+    publisher_handlers: HashMap<Uuid, PublisherHandler>,
+    subscriber_to_handler: SubscriberHandlers,
+    shards: Vec<RouterShard>,
+    pending_handshakes: PendingHandshakes,
+    admission: Admission,
+    metrics: Arc<Metrics>,
+    publish_control: PublishControl,
+    dead_letters: Arc<DeadLetters>,
+    config: Config,
     event_sender: Sender<Event>,
     event_receiver: Receiver<Event>,
 }
 
 impl PubSub {
-    pub fn new(publisher_port: u16, subscriber_port: u16) -> error::Result<Self> {
-        log::info!(
-            "PubSub: publisher_port=({}), subscriber_port=({})",
-            publisher_port,
-            subscriber_port
-        );
+    pub fn new(config: Config) -> error::Result<Self> {
+        log::info!("PubSub: config=({:?})", config);
 
         // Create a channel that will be used for communication between threads.
         log::info!("Creating the communication channel");
@@ -41,32 +69,127 @@ impl PubSub {
         // Start the publisher TCP listener.
         log::info!(
             "Starting the publisher TCP listener on port: ({})",
-            publisher_port
+            config.publisher_port
         );
         let publisher_listener = Self::start_background_tcp_listener(
-            publisher_port,
+            config.publisher_port,
             ConnectionKind::Publisher,
             event_sender.clone(),
-        );
+        )?;
 
         // Start the subscriber TCP listener.
         log::info!(
             "Starting the subscriber TCP listener on port: ({})",
-            subscriber_port
+            config.subscriber_port
         );
         let subscriber_listener = Self::start_background_tcp_listener(
-            subscriber_port,
+            config.subscriber_port,
             ConnectionKind::Subscriber,
             event_sender.clone(),
+        )?;
+
+        // Start the TCP listeners of the optional protocol front-ends.
+        let protocol_ports = [
+            (config.http_port, ConnectionKind::Http),
+            (config.mqtt_port, ConnectionKind::Mqtt),
+            (config.nats_port, ConnectionKind::Nats),
+            (config.redis_port, ConnectionKind::Redis),
+            (config.stomp_port, ConnectionKind::Stomp),
+        ];
+
+        let mut protocol_listeners: Vec<BackgroundTcpListener> = Vec::new();
+        for (port, connection_kind) in protocol_ports.into_iter() {
+            if let Some(port) = port {
+                log::info!(
+                    "Starting the [{}] TCP listener on port: ({})",
+                    connection_kind,
+                    port
+                );
+                protocol_listeners.push(Self::start_background_tcp_listener(
+                    port,
+                    connection_kind,
+                    event_sender.clone(),
+                )?);
+            }
+        }
+
+        // Create the metrics and the publication checks shared with the
+        // handlers.
+        let metrics = Arc::new(Metrics::default());
+        let backpressure = Arc::new(Backpressure::new(
+            &config.lossless_topics,
+            config.subscriber_queue_high_water_mark,
+        ));
+        let schedule_limits = Arc::new(ScheduleLimits::new(
+            Duration::from_millis(config.max_schedule_delay_ms),
+            config.max_scheduled_bytes,
+        ));
+        let publish_control = PublishControl::new(
+            RateLimiter::new(
+                config.rate_limit_policy,
+                &config.publisher_rate_limits,
+                &config.topic_rate_limits,
+                metrics.clone(),
+            ),
+            backpressure.clone(),
+            schedule_limits.clone(),
         );
 
+        // Start the QUIC listener.
+        let quic_listener = match config.quic_port {
+            Some(port) => Some(QuicListener::new(
+                port,
+                config.quic_certificate_path.clone(),
+                config.quic_private_key_path.clone(),
+                event_sender.clone(),
+                publish_control.clone(),
+            )?),
+            None => None,
+        };
+
+        // Start the router shards, which route the messages of the topics
+        // that hash to them in parallel.
+        let shards_number = config.router_shards.max(1);
+        log::info!("Starting [{}] router shards", shards_number);
+        let subscriber_to_handler: SubscriberHandlers = Arc::new(RwLock::new(HashMap::new()));
+        let dead_letters = Arc::new(DeadLetters::new(
+            &config.dead_letter_topics,
+            event_sender.clone(),
+            metrics.clone(),
+        ));
+        let shards = (0..shards_number)
+            .map(|index| {
+                RouterShard::new(
+                    index,
+                    &config,
+                    subscriber_to_handler.clone(),
+                    metrics.clone(),
+                    dead_letters.clone(),
+                    backpressure.clone(),
+                    schedule_limits.clone(),
+                )
+            })
+            .collect();
+
         // Create the PubSub instance.
         Ok(Self {
             _publisher_listener: publisher_listener,
             _subscriber_listener: subscriber_listener,
-            subscriber_to_handler: HashMap::new(),
-            topic_to_subscribers: HashMap::new(),
-            publisher_handlers: Vec::new(),
+            _protocol_listeners: protocol_listeners,
+            _quic_listener: quic_listener,
+            subscriber_to_handler,
+            shards,
+            publisher_handlers: HashMap::new(),
+            pending_handshakes: PendingHandshakes::new(
+                config.max_pending_handshakes_per_ip,
+                Duration::from_millis(config.handshake_timeout_ms),
+                Duration::from_millis(config.subscription_timeout_ms),
+            ),
+            admission: Admission::new(&config),
+            publish_control,
+            dead_letters,
+            metrics,
+            config,
             event_sender,
             event_receiver,
         })
@@ -103,17 +226,59 @@ impl PubSub {
         port: u16,
         connection_kind: ConnectionKind,
         event_sender: Sender<Event>,
-    ) -> BackgroundTcpListener {
+    ) -> error::Result<BackgroundTcpListener> {
         let address = format!("0.0.0.0:{}", port);
         BackgroundTcpListener::new(address, connection_kind, event_sender)
     }
 
     fn handle_event(&mut self, event: Event) -> error::Result<bool> {
+        // Routing events are dispatched to the shards, in the order they
+        // were received, so that each shard sees its topics' subscriptions
+        // and messages in order.
         match event {
-            Event::Connection(kind, stream) => self.handle_connection(kind, stream)?,
-            Event::Publish(message) => self.handle_publish(message),
+            Event::Connection(kind, stream) => self.handle_connection(kind, stream),
+            Event::Disconnection(id) => self.handle_disconnection(id)?,
+            Event::GroupSubscribe(id, group, filter) => {
+                let shards = self.shards_of(&filter);
+                self.dispatch(shards, || {
+                    Event::GroupSubscribe(id, group.clone(), filter.clone())
+                })?
+            }
+            Event::GroupUnsubscribe(id, group, filter) => {
+                let shards = self.shards_of(&filter);
+                self.dispatch(shards, || {
+                    Event::GroupUnsubscribe(id, group.clone(), filter.clone())
+                })?
+            }
+            Event::Authentication(id, principal, accepted_sender) => {
+                let accepted = self.handle_authentication(id, principal);
+                if let Err(e) = accepted_sender.send(accepted) {
+                    log::error!("Failed sending the authentication result: [{}]", e);
+                }
+            }
+            Event::CountedPublish(message, receivers_sender) => self
+                .shard_of_topic(&message.topic)
+                .send(Event::CountedPublish(message, receivers_sender))?,
+            Event::Publish(message) => self
+                .shard_of_topic(&message.topic)
+                .send(Event::Publish(message))?,
+            Event::PublishBatch(messages) | Event::BatchPart(messages, _) => {
+                self.handle_publish_batch(messages)?
+            }
+            Event::QuicSession(id, ip, subscriber, accepted_sender) => {
+                let accepted = self.handle_quic_session(id, ip, subscriber);
+                if accepted_sender.send(accepted).is_err() {
+                    log::error!("Failed sending the admission result of [{}]", id);
+                }
+            }
+            Event::Subscribe(id, filters) => {
+                self.dispatch_filters(filters, |filters| Event::Subscribe(id, filters))?
+            }
             Event::SubscriptionRequest(id, request) => {
-                self.handle_subscription_request(id, request)
+                self.handle_subscription_request(id, request)?
+            }
+            Event::Unsubscribe(id, filters) => {
+                self.dispatch_filters(filters, |filters| Event::Unsubscribe(id, filters))?
             }
             Event::Termination => return Ok(false),
         }
@@ -121,83 +286,315 @@ impl PubSub {
         Ok(true)
     }
 
-    fn handle_connection(&mut self, kind: ConnectionKind, stream: TcpStream) -> error::Result<()> {
-        match kind {
-            ConnectionKind::Publisher => self.handle_publisher_connection(stream)?,
-            ConnectionKind::Subscriber => self.handle_subscriber_connection(stream)?,
+    fn handle_connection(&mut self, kind: ConnectionKind, mut stream: TcpStream) {
+        // Generate a unique ID for the client. Failing connections are
+        // dropped, leaving the others be.
+        let id = Uuid::new_v4();
+        let address = match stream.peer_addr() {
+            Ok(address) => address,
+            Err(e) => {
+                log::error!("Failed accepting [{}] connection: [{}]", kind, e);
+                return;
+            }
+        };
+        log::info!("Generated id [{}] for [{}] client [{}]", id, kind, address);
+
+        // Refuse the connection if a limit was reached.
+        if !self.admit(id, &kind, address.ip()) {
+            if let Err(e) = Admission::write_busy_reply(&kind, &mut stream) {
+                log::error!("Failed rejecting [{}]: [{}]", address, e);
+            }
+            return;
+        }
+
+        let metrics = self.metrics.clone();
+        let dead_letters = self.dead_letters.clone();
+        let publish_control = self.publish_control.clone();
+        let result = match kind {
+            ConnectionKind::Publisher => self.handle_publisher_connection(id, stream),
+            ConnectionKind::Subscriber => self.handle_subscriber_connection(id, stream),
+            ConnectionKind::Http => {
+                self.handle_protocol_connection(id, stream, |id, stream, event_sender| {
+                    HttpHandler::new(id, stream, event_sender, publish_control, metrics)
+                })
+            }
+            ConnectionKind::Mqtt => {
+                self.handle_protocol_connection(id, stream, |id, stream, event_sender| {
+                    MqttHandler::new(id, stream, event_sender, publish_control)
+                })
+            }
+            ConnectionKind::Nats => {
+                self.handle_protocol_connection(id, stream, |id, stream, event_sender| {
+                    NatsHandler::new(id, stream, event_sender, publish_control)
+                })
+            }
+            ConnectionKind::Redis => {
+                self.handle_protocol_connection(id, stream, |id, stream, event_sender| {
+                    RedisHandler::new(id, stream, event_sender, publish_control)
+                })
+            }
+            ConnectionKind::Stomp => {
+                self.handle_protocol_connection(id, stream, |id, stream, event_sender| {
+                    StompHandler::new(id, stream, event_sender, publish_control, dead_letters)
+                })
+            }
+            ConnectionKind::Quic => {
+                // QUIC sessions are registered by QuicSession events instead.
+                log::error!("Unexpected QUIC Connection event from [{}]", address);
+                self.admission.release(id);
+                Ok(())
+            }
+        };
+
+        // Forget connections whose handler couldn't be started.
+        if let Err(e) = result {
+            log::error!("Failed handling [{}] client [{}]: [{}]", kind, address, e);
+            self.admission.release(id);
+        }
+    }
+
+    fn admit(&mut self, id: Uuid, kind: &ConnectionKind, ip: IpAddr) -> bool {
+        match self.admission.admit(id, kind.clone(), ip) {
+            Ok(()) => {
+                self.metrics.increment("connections_accepted_total");
+                true
+            }
+            Err(rejection) => {
+                log::warn!(
+                    "Rejecting [{}] connection [{}] from [{}]: [{}] limit reached",
+                    kind,
+                    id,
+                    ip,
+                    rejection
+                );
+                self.metrics.increment(&format!(
+                    "connections_rejected_total{{reason=\"{}\"}}",
+                    rejection
+                ));
+                false
+            }
+        }
+    }
+
+    fn handle_authentication(&mut self, id: Uuid, principal: String) -> bool {
+        log::info!("Authentication of [{}] as: [{}]", id, principal);
+
+        match self.admission.authenticate(id, principal) {
+            Ok(()) => true,
+            Err(rejection) => {
+                log::warn!("Rejecting [{}]: [{}] limit reached", id, rejection);
+                self.metrics.increment(&format!(
+                    "connections_rejected_total{{reason=\"{}\"}}",
+                    rejection
+                ));
+                false
+            }
         }
+    }
+
+    fn handle_disconnection(&mut self, id: Uuid) -> error::Result<()> {
+        log::info!("Disconnection of: [{}]", id);
+
+        // Unregister the subscriber from all topics and filters.
+        self.dispatch(0..self.shards.len(), || Event::Disconnection(id))?;
+
+        // Drop the subscriber's or publisher's handler, without holding the
+        // shards back while its thread is joined.
+        let subscriber_handler = self.subscriber_to_handler.write().unwrap().remove(&id);
+        drop(subscriber_handler);
+        self.publisher_handlers.remove(&id);
+        self.admission.release(id);
 
         Ok(())
     }
 
-    fn handle_publish(&self, message: Message) {
-        log::info!("Publishing message to topic: [{}]", message.topic);
+    fn handle_publish_batch(&self, messages: Vec<Message>) -> error::Result<()> {
+        // Each shard routes its part of a batch at once, keeping the order of
+        // each topic's messages.
+        let mut shard_messages: Vec<Vec<Message>> = vec![Vec::new(); self.shards.len()];
+        for message in messages.into_iter() {
+            shard_messages[router::shard_of(&message.topic, self.shards.len())].push(message);
+        }
 
-        match self.topic_to_subscribers.get(&message.topic) {
-            Some(subscribers) => {
-                self.publish_message_to_subscribers(message, subscribers);
+        // The shards of the parts meet before delivering them, so that the
+        // batch is routed at one point of all of their event streams, and
+        // goes out whole. Parts are queued in the same order on all shards,
+        // so the shards never wait for each other's next batch.
+        let parts = shard_messages
+            .iter()
+            .filter(|messages| !messages.is_empty())
+            .count();
+        let barrier = Arc::new(Barrier::new(parts));
+        for (shard, messages) in self.shards.iter().zip(shard_messages) {
+            if !messages.is_empty() {
+                shard.send(Event::BatchPart(messages, barrier.clone()))?;
             }
-            None => log::warn!("No subscribers registered to topic: [{}]", message.topic),
         }
+
+        Ok(())
     }
 
-    fn handle_subscription_request(&mut self, id: Uuid, request: SubscriptionRequest) {
+    fn handle_subscription_request(
+        &self,
+        id: Uuid,
+        request: SubscriptionRequest,
+    ) -> error::Result<()> {
         log::info!("Subscription request from: [{}]", id);
 
         // Register the subscriber to all requested topics.
-        for topic in request.topics.into_iter() {
-            self.topic_to_subscribers.entry(topic).or_default().push(id);
+        let filters = request.topics.into_iter().map(TopicFilter::Exact).collect();
+        self.dispatch_filters(filters, |filters| Event::Subscribe(id, filters))
+    }
+
+    fn shard_of_topic(&self, topic: &str) -> &RouterShard {
+        &self.shards[router::shard_of(topic, self.shards.len())]
+    }
+
+    // Exact topics belong to a single shard, while other filters may match
+    // topics of any shard.
+    fn shards_of(&self, filter: &TopicFilter) -> Range<usize> {
+        match filter {
+            TopicFilter::Exact(topic) => {
+                let shard = router::shard_of(topic, self.shards.len());
+                shard..shard + 1
+            }
+            _ => 0..self.shards.len(),
         }
     }
 
-    fn handle_publisher_connection(&mut self, stream: TcpStream) -> error::Result<()> {
-        log::info!("Publisher connection: [{}]", stream.peer_addr().unwrap());
+    fn dispatch(
+        &self,
+        shards: Range<usize>,
+        create_event: impl Fn() -> Event,
+    ) -> error::Result<()> {
+        for shard in shards {
+            self.shards[shard].send(create_event())?;
+        }
+        Ok(())
+    }
+
+    fn dispatch_filters(
+        &self,
+        filters: Vec<TopicFilter>,
+        create_event: impl Fn(Vec<TopicFilter>) -> Event,
+    ) -> error::Result<()> {
+        let mut shard_filters: Vec<Vec<TopicFilter>> = vec![Vec::new(); self.shards.len()];
+        for filter in filters.into_iter() {
+            for shard in self.shards_of(&filter) {
+                shard_filters[shard].push(filter.clone());
+            }
+        }
 
-        let publisher_handler = PublisherHandler::new(stream, self.event_sender.clone());
-        self.publisher_handlers.push(publisher_handler);
+        for (shard, filters) in self.shards.iter().zip(shard_filters) {
+            if !filters.is_empty() {
+                shard.send(create_event(filters))?;
+            }
+        }
 
         Ok(())
     }
 
-    fn handle_subscriber_connection(&mut self, stream: TcpStream) -> error::Result<()> {
-        // Generate a unique ID for the subscriber.
-        let subscriber_id = Uuid::new_v4();
-        log::info!(
-            "Generated id [{}] for subscriber [{}]",
-            subscriber_id,
-            stream.peer_addr().unwrap()
-        );
+    fn handle_publisher_connection(
+        &mut self,
+        publisher_id: Uuid,
+        stream: TcpStream,
+    ) -> error::Result<()> {
+        let publisher_handler = PublisherHandler::new(
+            publisher_id,
+            stream,
+            self.event_sender.clone(),
+            self.create_heartbeat(),
+            self.publish_control.clone(),
+            TransactionLimits {
+                max_messages: self.config.max_transaction_messages,
+                max_bytes: self.config.max_transaction_bytes,
+            },
+        )?;
+        self.publisher_handlers
+            .insert(publisher_id, publisher_handler);
+
+        Ok(())
+    }
+
+    fn handle_subscriber_connection(
+        &mut self,
+        subscriber_id: Uuid,
+        stream: TcpStream,
+    ) -> error::Result<()> {
+        // Drop the connection if its IP has too many pending handshakes.
+        let address = stream.peer_addr()?;
+        let pending_handshake = match self.pending_handshakes.admit(address.ip()) {
+            Some(pending_handshake) => pending_handshake,
+            None => {
+                log::warn!(
+                    "Dropping subscriber [{}], too many pending handshakes from its IP",
+                    address
+                );
+                self.admission.release(subscriber_id);
+                return Ok(());
+            }
+        };
 
         // Create a new handler for the subscriber.
-        let subscriber_handler =
-            SubscriberHandler::new(subscriber_id, stream, self.event_sender.clone());
+        let subscriber_handler = SubscriberHandler::new(
+            subscriber_id,
+            stream,
+            self.event_sender.clone(),
+            pending_handshake,
+            self.create_heartbeat(),
+            self.metrics.clone(),
+            self.config.priority_drain_policy,
+            Compression::new(self.config.compression_threshold),
+            self.config.frame_checksums,
+        )?;
 
         // Add the subscriber to the handlers map.
         self.subscriber_to_handler
-            .insert(subscriber_id, subscriber_handler);
+            .write()
+            .unwrap()
+            .insert(subscriber_id, Box::new(subscriber_handler));
 
         Ok(())
     }
 
-    fn publish_message_to_subscribers(&self, message: Message, subscribers: &Vec<Uuid>) {
-        for subscriber in subscribers {
-            match self.subscriber_to_handler.get(subscriber) {
-                Some(handler) => {
-                    self.publish_message_to_subscriber(message.clone(), subscriber, handler)
-                }
-                None => log::error!("No handler for subscriber: [{}]", subscriber),
-            }
-        }
+    fn handle_protocol_connection<H: Subscriber + 'static>(
+        &mut self,
+        client_id: Uuid,
+        stream: TcpStream,
+        create_handler: impl FnOnce(Uuid, TcpStream, Sender<Event>) -> error::Result<H>,
+    ) -> error::Result<()> {
+        // Create a new handler for the client, which may become a subscriber.
+        let handler = create_handler(client_id, stream, self.event_sender.clone())?;
+
+        // Add the client to the handlers map.
+        self.subscriber_to_handler
+            .write()
+            .unwrap()
+            .insert(client_id, Box::new(handler));
+
+        Ok(())
     }
 
-    fn publish_message_to_subscriber(
-        &self,
-        message: Message,
-        id: &Uuid,
-        handler: &SubscriberHandler,
-    ) {
-        if let Err(e) = handler.publish(message) {
-            log::error!("Error publishing message to subscriber [{}]: [{}]", id, e);
+    fn handle_quic_session(&mut self, id: Uuid, ip: IpAddr, subscriber: QuicSubscriber) -> bool {
+        // QUIC sessions outlive their connections, so the listener generates
+        // their IDs and only registers them here. A session counts as a
+        // connection until it ends.
+        if !self.admit(id, &ConnectionKind::Quic, ip) {
+            return false;
         }
+
+        self.subscriber_to_handler
+            .write()
+            .unwrap()
+            .insert(id, Box::new(subscriber));
+
+        true
+    }
+
+    fn create_heartbeat(&self) -> Heartbeat {
+        Heartbeat::new(
+            Duration::from_millis(self.config.heartbeat_min_interval_ms),
+            self.config.heartbeat_max_missed,
+        )
     }
 }
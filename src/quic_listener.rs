@@ -0,0 +1,542 @@
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crossbeam::channel::{self, Sender};
+use quinn::crypto::rustls::QuicServerConfig;
+use quinn::{Connection, Endpoint, Incoming, RecvStream, SendStream};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+use tokio::runtime::Runtime;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::sync::{oneshot, Notify};
+use tokio::task::JoinSet;
+use uuid::Uuid;
+
+use crate::error::{self, Error};
+use crate::event::Event;
+use crate::message::Message;
+use crate::publish_gate::PublishControl;
+use crate::subscriber::Subscriber;
+use crate::subscription_request::SubscriptionRequest;
+
+const ALPN_PROTOCOL: &[u8] = b"pubsub";
+const MAX_FRAME_SIZE: u32 = 64 * 1024 * 1024;
+const MAX_IDLE_TIMEOUT_MS: u32 = 10_000;
+const SHUTDOWN_TIMEOUT_MS: u64 = 1000;
+const READ_CHUNK_SIZE: usize = 64 * 1024;
+// The code publication streams opened as 0-RTT data are stopped with.
+const EARLY_PUBLICATION_ERROR_CODE: u32 = 1;
+
+// How long the subscriptions of a lost connection are kept, and messages
+// for them buffered, waiting for the client to reconnect.
+const SESSION_GRACE_PERIOD_MS: u64 = 30_000;
+
+// Every unit sent on a QUIC stream is a frame: a big-endian u32 length
+// followed by that many bytes.
+//
+// - The client opens one bidirectional control stream and sends the 16 byte
+//   ID of the session it resumes (nil for a new session). The server replies
+//   with the session's ID, then the client sends SubscriptionRequest frames.
+// - The client publishes Message frames on unidirectional streams it opens.
+//   Publications could be replayed when sent as 0-RTT data, so streams
+//   opened before the handshake completed are stopped, and the client must
+//   publish them again. The control stream is idempotent and may be sent as
+//   0-RTT data, so that resuming a session takes a single round trip.
+// - The server opens a unidirectional stream per subscribed topic, sends the
+//   topic's name as its first frame and then Message frames of that topic.
+pub struct QuicListener {
+    endpoint: Endpoint,
+    runtime: Option<Runtime>,
+}
+
+#[derive(Debug)]
+pub struct QuicSubscriber {
+    message_sender: UnboundedSender<QueuedMessage>,
+    queue_len: Arc<AtomicUsize>,
+}
+
+impl Subscriber for QuicSubscriber {
+    fn publish(&self, message: Message) -> error::Result<()> {
+        self.message_sender
+            .send(QueuedMessage::new(message, self.queue_len.clone()))
+            .map_err(|e| channel::SendError(e.0.message.clone()))?;
+        Ok(())
+    }
+
+    fn queue_len(&self) -> usize {
+        self.queue_len.load(Ordering::Relaxed)
+    }
+}
+
+// A message on its way to a QUIC subscriber, which counts towards the
+// subscriber's queue until it's written or dropped.
+#[derive(Debug)]
+struct QueuedMessage {
+    message: Message,
+    queue_len: Arc<AtomicUsize>,
+}
+
+impl QueuedMessage {
+    fn new(message: Message, queue_len: Arc<AtomicUsize>) -> Self {
+        queue_len.fetch_add(1, Ordering::Relaxed);
+        Self { message, queue_len }
+    }
+}
+
+impl Drop for QueuedMessage {
+    fn drop(&mut self) {
+        self.queue_len.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+enum QuicSession {
+    Attached,
+    Detached {
+        message_receiver: UnboundedReceiver<QueuedMessage>,
+        generation: u64,
+    },
+}
+
+type QuicSessions = Arc<Mutex<HashMap<Uuid, QuicSession>>>;
+
+impl QuicListener {
+    pub fn new(
+        port: u16,
+        certificate_path: Option<PathBuf>,
+        private_key_path: Option<PathBuf>,
+        event_sender: Sender<Event>,
+        publish_control: PublishControl,
+    ) -> error::Result<Self> {
+        log::info!("Listening for QUIC connections on port: ({})", port);
+
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .thread_name("quic-listener")
+            .build()?;
+
+        // Create the QUIC endpoint inside the runtime, which drives its I/O.
+        let server_config = Self::server_config(certificate_path, private_key_path)?;
+        let address = SocketAddr::from(([0, 0, 0, 0], port));
+        let endpoint = {
+            let _guard = runtime.enter();
+            Endpoint::server(server_config, address)?
+        };
+
+        // Accept connections in the background.
+        let sessions: QuicSessions = Arc::new(Mutex::new(HashMap::new()));
+        runtime.spawn(Self::accept_connections(
+            endpoint.clone(),
+            event_sender,
+            publish_control,
+            sessions,
+        ));
+
+        Ok(Self {
+            endpoint,
+            runtime: Some(runtime),
+        })
+    }
+
+    fn server_config(
+        certificate_path: Option<PathBuf>,
+        private_key_path: Option<PathBuf>,
+    ) -> error::Result<quinn::ServerConfig> {
+        // Load the certificate, or generate a self-signed one.
+        let (certificates, private_key) = match (certificate_path, private_key_path) {
+            (Some(certificate_path), Some(private_key_path)) => (
+                CertificateDer::pem_file_iter(&certificate_path)?
+                    .collect::<Result<Vec<CertificateDer>, _>>()?,
+                PrivateKeyDer::from_pem_file(&private_key_path)?,
+            ),
+            _ => {
+                log::warn!("No QUIC certificate configured, generating a self-signed one");
+                let certified_key =
+                    rcgen::generate_simple_self_signed(vec!["localhost".to_owned()])?;
+                (
+                    vec![certified_key.cert.der().clone()],
+                    PrivatePkcs8KeyDer::from(certified_key.key_pair.serialize_der()).into(),
+                )
+            }
+        };
+
+        // Configure TLS, accepting 0-RTT data from clients resuming a TLS
+        // session.
+        let mut tls_config = rustls::ServerConfig::builder_with_provider(Arc::new(
+            rustls::crypto::ring::default_provider(),
+        ))
+        .with_protocol_versions(&[&rustls::version::TLS13])?
+        .with_no_client_auth()
+        .with_single_cert(certificates, private_key)?;
+        tls_config.alpn_protocols = vec![ALPN_PROTOCOL.to_vec()];
+        tls_config.max_early_data_size = u32::MAX;
+
+        // Connections survive client address changes by default, drop peers
+        // that went silent.
+        let mut server_config =
+            quinn::ServerConfig::with_crypto(Arc::new(QuicServerConfig::try_from(tls_config)?));
+        let mut transport_config = quinn::TransportConfig::default();
+        transport_config
+            .max_idle_timeout(Some(quinn::VarInt::from_u32(MAX_IDLE_TIMEOUT_MS).into()));
+        server_config.transport_config(Arc::new(transport_config));
+
+        Ok(server_config)
+    }
+
+    async fn accept_connections(
+        endpoint: Endpoint,
+        event_sender: Sender<Event>,
+        publish_control: PublishControl,
+        sessions: QuicSessions,
+    ) {
+        while let Some(incoming) = endpoint.accept().await {
+            let event_sender = event_sender.clone();
+            let publish_control = publish_control.clone();
+            let sessions = sessions.clone();
+            tokio::spawn(async move {
+                let address = incoming.remote_address();
+                if let Err(e) =
+                    Self::handle_connection(incoming, event_sender, publish_control, sessions).await
+                {
+                    log::error!("Error handling QUIC connection [{}]: [{}]", address, e);
+                }
+            });
+        }
+    }
+
+    async fn handle_connection(
+        incoming: Incoming,
+        event_sender: Sender<Event>,
+        publish_control: PublishControl,
+        sessions: QuicSessions,
+    ) -> error::Result<()> {
+        // Serve the connection before the handshake completed, so that the
+        // control stream may arrive as 0-RTT data.
+        let connection = match incoming.accept()?.into_0rtt() {
+            Ok((connection, _)) => connection,
+            Err(connecting) => connecting.await?,
+        };
+        log::info!("QUIC connection: [{}]", connection.remote_address());
+
+        // Attach the connection to the requested session, or to a new one
+        // if the connection is admitted.
+        let (mut control_sender, mut control_receiver) = connection.accept_bi().await?;
+        let mut requested_id = [0; 16];
+        control_receiver.read_exact(&mut requested_id).await?;
+
+        let (id, mut message_receiver) =
+            match Self::resume_session(Uuid::from_bytes(requested_id), &sessions)? {
+                Some(message_receiver) => (Uuid::from_bytes(requested_id), message_receiver),
+                None => match Self::start_session(&connection, &sessions, &event_sender).await? {
+                    Some(session) => session,
+                    None => {
+                        connection.close(0u32.into(), b"server busy");
+                        return Ok(());
+                    }
+                },
+            };
+        control_sender.write_all(id.as_bytes()).await?;
+
+        // Serve the session until the client ends it or the connection is lost.
+        let result = tokio::select! {
+            result = Self::receive_subscription_requests(id, &mut control_receiver, &event_sender) => result,
+            result = Self::receive_publications(&connection, &event_sender, &publish_control) => result,
+            result = Self::deliver_messages(&connection, &mut message_receiver) => result,
+        };
+
+        match result {
+            Ok(()) => {
+                log::info!("QUIC session [{}] ended", id);
+                sessions.lock().unwrap().remove(&id);
+                event_sender.send(Event::Disconnection(id))?;
+            }
+            Err(e) => {
+                log::warn!("QUIC session [{}] lost its connection: [{}]", id, e);
+                Self::detach_session(id, message_receiver, sessions, event_sender);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn resume_session(
+        requested_id: Uuid,
+        sessions: &QuicSessions,
+    ) -> error::Result<Option<UnboundedReceiver<QueuedMessage>>> {
+        let mut sessions = sessions.lock().unwrap();
+
+        // Resume a detached session, keeping its subscriptions and the
+        // messages buffered for it.
+        match sessions.remove(&requested_id) {
+            Some(QuicSession::Detached {
+                message_receiver, ..
+            }) => {
+                log::info!("Resuming QUIC session: [{}]", requested_id);
+                sessions.insert(requested_id, QuicSession::Attached);
+                Ok(Some(message_receiver))
+            }
+            Some(QuicSession::Attached) => {
+                sessions.insert(requested_id, QuicSession::Attached);
+                Err(Error::QuicSessionInUse(requested_id))
+            }
+            None => Ok(None),
+        }
+    }
+
+    async fn start_session(
+        connection: &Connection,
+        sessions: &QuicSessions,
+        event_sender: &Sender<Event>,
+    ) -> error::Result<Option<(Uuid, UnboundedReceiver<QueuedMessage>)>> {
+        // Register a new session as a subscriber, unless the PubSub refuses
+        // it like any other connection.
+        let id = Uuid::new_v4();
+        let (message_sender, message_receiver) = mpsc::unbounded_channel();
+        let (accepted_sender, accepted_receiver) = oneshot::channel();
+        event_sender.send(Event::QuicSession(
+            id,
+            connection.remote_address().ip(),
+            QuicSubscriber {
+                message_sender,
+                queue_len: Arc::new(AtomicUsize::new(0)),
+            },
+            accepted_sender,
+        ))?;
+        if !accepted_receiver.await.unwrap_or_default() {
+            return Ok(None);
+        }
+
+        log::info!("Starting QUIC session: [{}]", id);
+        sessions.lock().unwrap().insert(id, QuicSession::Attached);
+
+        Ok(Some((id, message_receiver)))
+    }
+
+    fn detach_session(
+        id: Uuid,
+        message_receiver: UnboundedReceiver<QueuedMessage>,
+        sessions: QuicSessions,
+        event_sender: Sender<Event>,
+    ) {
+        // Tell detachments apart, so that an expired timer of an earlier
+        // detachment doesn't end a session that was resumed since.
+        let generation: u64 = rand::random();
+        sessions.lock().unwrap().insert(
+            id,
+            QuicSession::Detached {
+                message_receiver,
+                generation,
+            },
+        );
+
+        // End the session if the client doesn't resume it in time.
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(SESSION_GRACE_PERIOD_MS)).await;
+
+            let mut sessions = sessions.lock().unwrap();
+            if let Some(QuicSession::Detached {
+                generation: current_generation,
+                ..
+            }) = sessions.get(&id)
+            {
+                if *current_generation == generation {
+                    log::info!("QUIC session [{}] expired", id);
+                    sessions.remove(&id);
+                    if let Err(e) = event_sender.send(Event::Disconnection(id)) {
+                        log::error!("Failed sending Disconnection event for [{}]: [{}]", id, e);
+                    }
+                }
+            }
+        });
+    }
+
+    async fn receive_subscription_requests(
+        id: Uuid,
+        control_receiver: &mut RecvStream,
+        event_sender: &Sender<Event>,
+    ) -> error::Result<()> {
+        // The client ends the session by finishing its control stream.
+        while let Some(frame) = Self::read_frame(control_receiver).await? {
+            let request = SubscriptionRequest::read(&mut Cursor::new(frame))?;
+            event_sender.send(Event::SubscriptionRequest(id, request))?;
+        }
+
+        Ok(())
+    }
+
+    async fn receive_publications(
+        connection: &Connection,
+        event_sender: &Sender<Event>,
+        publish_control: &PublishControl,
+    ) -> error::Result<()> {
+        let mut publication_tasks: JoinSet<()> = JoinSet::new();
+        loop {
+            let mut publication_receiver = connection.accept_uni().await?;
+            if publication_receiver.is_0rtt() {
+                log::warn!(
+                    "Refusing publications sent as 0-RTT data from [{}]",
+                    connection.remote_address()
+                );
+                let _ = publication_receiver
+                    .stop(quinn::VarInt::from_u32(EARLY_PUBLICATION_ERROR_CODE));
+                continue;
+            }
+
+            let event_sender = event_sender.clone();
+            let address = connection.remote_address();
+            let drained = Arc::new(Notify::new());
+            let mut gate = publish_control.gate(address.ip().to_string(), {
+                let drained = drained.clone();
+                Arc::new(move || drained.notify_one())
+            });
+
+            publication_tasks.spawn(async move {
+                let result: error::Result<()> = async {
+                    loop {
+                        // Stop reading publications while the client is
+                        // held back by them.
+                        while gate.is_paused() {
+                            match gate.timeout(None) {
+                                Some(timeout) => tokio::time::sleep(timeout).await,
+                                None => drained.notified().await,
+                            }
+                        }
+
+                        let frame = match Self::read_frame(&mut publication_receiver).await? {
+                            Some(frame) => frame,
+                            None => break,
+                        };
+                        let message = Message::read(&mut Cursor::new(frame))?;
+                        if let Ok(message) = gate.pass_one(message) {
+                            event_sender.send(Event::Publish(message))?;
+                        }
+                    }
+                    Ok(())
+                }
+                .await;
+
+                if let Err(e) = result {
+                    log::error!("Error receiving messages from [{}]: [{}]", address, e);
+                }
+            });
+        }
+    }
+
+    async fn deliver_messages(
+        connection: &Connection,
+        message_receiver: &mut UnboundedReceiver<QueuedMessage>,
+    ) -> error::Result<()> {
+        // Each topic is written on its own stream, so that a large message
+        // only delays later messages of the same topic.
+        let mut topic_to_sender: HashMap<String, UnboundedSender<QueuedMessage>> = HashMap::new();
+        let mut topic_tasks: JoinSet<()> = JoinSet::new();
+        loop {
+            let message = match message_receiver.recv().await {
+                Some(message) => message,
+                None => return Ok(()),
+            };
+
+            // Stop if the connection was lost.
+            if let Some(reason) = connection.close_reason() {
+                return Err(reason.into());
+            }
+
+            let topic_sender = topic_to_sender
+                .entry(message.message.topic.to_string())
+                .or_insert_with(|| {
+                    Self::start_topic_stream(connection, &message.message.topic, &mut topic_tasks)
+                });
+
+            // Restart the topic's stream if it failed.
+            if let Err(e) = topic_sender.send(message) {
+                let message = e.0;
+                let topic = message.message.topic.clone();
+                let topic_sender = Self::start_topic_stream(connection, &topic, &mut topic_tasks);
+                topic_sender
+                    .send(message)
+                    .map_err(|e| channel::SendError(e.0.message.clone()))?;
+                topic_to_sender.insert(topic.to_string(), topic_sender);
+            }
+        }
+    }
+
+    fn start_topic_stream(
+        connection: &Connection,
+        topic: &str,
+        topic_tasks: &mut JoinSet<()>,
+    ) -> UnboundedSender<QueuedMessage> {
+        let (topic_sender, mut topic_receiver) = mpsc::unbounded_channel::<QueuedMessage>();
+        let connection = connection.clone();
+        let topic = topic.to_owned();
+
+        topic_tasks.spawn(async move {
+            let result: error::Result<()> = async {
+                let mut stream = connection.open_uni().await?;
+                Self::write_frame(&mut stream, topic.as_bytes()).await?;
+
+                while let Some(message) = topic_receiver.recv().await {
+                    let mut frame: Vec<u8> = Vec::new();
+                    message.message.write(&mut frame)?;
+                    Self::write_frame(&mut stream, &frame).await?;
+                }
+
+                Ok(())
+            }
+            .await;
+
+            if let Err(e) = result {
+                log::error!("Error writing topic [{}] stream: [{}]", topic, e);
+            }
+        });
+
+        topic_sender
+    }
+
+    async fn read_frame(stream: &mut RecvStream) -> error::Result<Option<Vec<u8>>> {
+        // A finished stream has no more frames.
+        let mut size = [0; 4];
+        match stream.read_exact(&mut size).await {
+            Ok(()) => {}
+            Err(quinn::ReadExactError::FinishedEarly(0)) => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+
+        let size = u32::from_be_bytes(size);
+        if size > MAX_FRAME_SIZE {
+            return Err(Error::QuicFrameTooLarge(size));
+        }
+
+        // Let the frame grow with the bytes actually received rather than
+        // trusting the size for the allocation.
+        let mut frame: Vec<u8> = Vec::new();
+        while frame.len() < size as usize {
+            let start = frame.len();
+            frame.resize((size as usize).min(start + READ_CHUNK_SIZE), 0);
+            stream.read_exact(&mut frame[start..]).await?;
+        }
+
+        Ok(Some(frame))
+    }
+
+    async fn write_frame(stream: &mut SendStream, frame: &[u8]) -> error::Result<()> {
+        stream
+            .write_all(&(frame.len() as u32).to_be_bytes())
+            .await?;
+        stream.write_all(frame).await?;
+        Ok(())
+    }
+}
+
+impl Drop for QuicListener {
+    fn drop(&mut self) {
+        if let Some(runtime) = self.runtime.take() {
+            // Close all connections and stop the background tasks.
+            self.endpoint.close(0u32.into(), b"shutdown");
+            runtime.shutdown_timeout(Duration::from_millis(SHUTDOWN_TIMEOUT_MS));
+        }
+    }
+}
@@ -0,0 +1,266 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::metrics::Metrics;
+use crate::topic_filter::TopicFilter;
+
+// Publishers without a limit of their own get the limit of this identity.
+pub const ANY_PUBLISHER: &str = "*";
+
+// How often the buckets of idle publishers are forgotten.
+const EVICTION_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RateLimitPolicy {
+    // Stop reading from the publisher until it's back within its limits.
+    Throttle,
+    // Drop the messages exceeding the limits and report them to the publisher.
+    Reject,
+}
+
+impl FromStr for RateLimitPolicy {
+    type Err = String;
+
+    fn from_str(policy: &str) -> Result<Self, Self::Err> {
+        match policy {
+            "throttle" => Ok(Self::Throttle),
+            "reject" => Ok(Self::Reject),
+            _ => Err(format!("unknown rate limit policy: {}", policy)),
+        }
+    }
+}
+
+// Written as "MESSAGES:BYTES" per second, either of them may be left empty
+// to not limit it.
+#[derive(Clone, Copy, Debug)]
+pub struct RateLimit {
+    pub messages_per_second: Option<f64>,
+    pub bytes_per_second: Option<f64>,
+}
+
+impl FromStr for RateLimit {
+    type Err = String;
+
+    fn from_str(limit: &str) -> Result<Self, Self::Err> {
+        let (messages, bytes) = limit
+            .split_once(':')
+            .ok_or_else(|| format!("expected MESSAGES:BYTES, got: {}", limit))?;
+
+        let parse_rate = |rate: &str| -> Result<Option<f64>, String> {
+            if rate.is_empty() {
+                return Ok(None);
+            }
+            match rate.parse::<f64>() {
+                Ok(rate) if rate > 0.0 => Ok(Some(rate)),
+                _ => Err(format!("invalid rate: {}", rate)),
+            }
+        };
+
+        Ok(Self {
+            messages_per_second: parse_rate(messages)?,
+            bytes_per_second: parse_rate(bytes)?,
+        })
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum RateDecision {
+    Allowed,
+    Delayed(Duration),
+    Rejected,
+}
+
+// Holds a second's worth of tokens, so that short bursts are allowed.
+#[derive(Debug)]
+struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: f64) -> Self {
+        Self {
+            rate,
+            tokens: rate,
+            last_refill: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.last_refill = now;
+    }
+
+    fn is_full(&self, now: Instant) -> bool {
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens + elapsed * self.rate >= self.rate
+    }
+
+    fn has(&self, amount: f64) -> bool {
+        self.tokens >= amount
+    }
+
+    fn take(&mut self, amount: f64) -> Duration {
+        // Tokens may go negative, the debt is paid by waiting.
+        self.tokens -= amount;
+        if self.tokens < 0.0 {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        } else {
+            Duration::ZERO
+        }
+    }
+}
+
+#[derive(Debug)]
+struct RateBuckets {
+    messages: Option<TokenBucket>,
+    bytes: Option<TokenBucket>,
+}
+
+impl RateBuckets {
+    fn new(limit: &RateLimit) -> Self {
+        Self {
+            messages: limit.messages_per_second.map(TokenBucket::new),
+            bytes: limit.bytes_per_second.map(TokenBucket::new),
+        }
+    }
+
+    fn is_full(&self, now: Instant) -> bool {
+        self.messages
+            .iter()
+            .chain(self.bytes.iter())
+            .all(|bucket| bucket.is_full(now))
+    }
+
+    fn buckets(&mut self, size: usize) -> impl Iterator<Item = (&mut TokenBucket, f64)> {
+        self.messages.iter_mut().map(|bucket| (bucket, 1.0)).chain(
+            self.bytes
+                .iter_mut()
+                .map(move |bucket| (bucket, size as f64)),
+        )
+    }
+}
+
+#[derive(Debug)]
+struct RateLimiterState {
+    publisher_limits: HashMap<String, RateLimit>,
+    topic_limits: Vec<(TopicFilter, RateLimit)>,
+    publisher_buckets: HashMap<String, RateBuckets>,
+    topic_buckets: Vec<RateBuckets>,
+    last_eviction: Instant,
+}
+
+// Shared by the handlers of all protocols, so that the limits of an identity
+// or of a topic pattern hold across connections.
+#[derive(Clone, Debug)]
+pub struct RateLimiter {
+    policy: RateLimitPolicy,
+    state: Arc<Mutex<RateLimiterState>>,
+    metrics: Arc<Metrics>,
+}
+
+impl RateLimiter {
+    pub fn new(
+        policy: RateLimitPolicy,
+        publisher_limits: &[(String, RateLimit)],
+        topic_limits: &[(String, RateLimit)],
+        metrics: Arc<Metrics>,
+    ) -> Self {
+        let topic_limits: Vec<(TopicFilter, RateLimit)> = topic_limits
+            .iter()
+            .map(|(pattern, limit)| (TopicFilter::glob(pattern), *limit))
+            .collect();
+        let topic_buckets = topic_limits
+            .iter()
+            .map(|(_, limit)| RateBuckets::new(limit))
+            .collect();
+
+        Self {
+            policy,
+            state: Arc::new(Mutex::new(RateLimiterState {
+                publisher_limits: publisher_limits.iter().cloned().collect(),
+                topic_limits,
+                publisher_buckets: HashMap::new(),
+                topic_buckets,
+                last_eviction: Instant::now(),
+            })),
+            metrics,
+        }
+    }
+
+    pub fn acquire(&self, identity: &str, topic: &str, size: usize) -> RateDecision {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+
+        // Forget the buckets of publishers that went idle. They refilled, so
+        // they would be created again just the same.
+        let now = Instant::now();
+        if now.duration_since(state.last_eviction) >= EVICTION_INTERVAL {
+            state
+                .publisher_buckets
+                .retain(|_, buckets| !buckets.is_full(now));
+            state.last_eviction = now;
+        }
+
+        // Each identity gets its own buckets, even when it shares the limit
+        // of any publisher.
+        let publisher_limit = state
+            .publisher_limits
+            .get(identity)
+            .or_else(|| state.publisher_limits.get(ANY_PUBLISHER));
+        let publisher_buckets = match publisher_limit {
+            Some(limit) => Some(
+                state
+                    .publisher_buckets
+                    .entry(identity.to_owned())
+                    .or_insert_with(|| RateBuckets::new(limit)),
+            ),
+            None => None,
+        };
+
+        let mut buckets: Vec<(&mut TokenBucket, f64)> = Vec::new();
+        if let Some(publisher_buckets) = publisher_buckets {
+            buckets.extend(publisher_buckets.buckets(size));
+        }
+        for ((filter, _), topic_buckets) in state
+            .topic_limits
+            .iter()
+            .zip(state.topic_buckets.iter_mut())
+        {
+            if filter.matches(topic) {
+                buckets.extend(topic_buckets.buckets(size));
+            }
+        }
+
+        for (bucket, _) in buckets.iter_mut() {
+            bucket.refill();
+        }
+
+        // Rejected messages don't consume any tokens.
+        if RateLimitPolicy::Reject == self.policy
+            && !buckets.iter().all(|(bucket, amount)| bucket.has(*amount))
+        {
+            self.metrics
+                .increment("rate_limited_messages_total{action=\"rejected\"}");
+            return RateDecision::Rejected;
+        }
+
+        let delay = buckets
+            .into_iter()
+            .map(|(bucket, amount)| bucket.take(amount))
+            .max()
+            .unwrap_or_default();
+        if delay.is_zero() {
+            RateDecision::Allowed
+        } else {
+            self.metrics
+                .increment("rate_limited_messages_total{action=\"throttled\"}");
+            RateDecision::Delayed(delay)
+        }
+    }
+}
@@ -0,0 +1,498 @@
+use std::collections::BTreeSet;
+use std::io::{BufReader, Write};
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crossbeam::channel::{self, Receiver, Sender};
+use polling::Poller;
+use uuid::Uuid;
+
+use crate::error;
+use crate::event::Event;
+use crate::message::Message;
+use crate::publish_gate::{self, PublishControl, PublishGate};
+use crate::resp_command::RespCommand;
+use crate::resp_reply::RespReply;
+use crate::shutdown::{self, ShutdownReceiver, ShutdownSender};
+use crate::subscriber::Subscriber;
+use crate::topic_filter::TopicFilter;
+
+const REDIS_STREAM_POLL_KEY: usize = 0;
+
+pub struct RedisHandler {
+    message_sender: Sender<Message>,
+    poller: Arc<Poller>,
+    handler_thread: Option<JoinHandle<()>>,
+    shutdown: ShutdownSender,
+}
+
+impl RedisHandler {
+    pub fn new(
+        id: Uuid,
+        stream: TcpStream,
+        event_sender: Sender<Event>,
+        publish_control: PublishControl,
+    ) -> error::Result<Self> {
+        let (message_sender, message_receiver): (Sender<Message>, Receiver<Message>) =
+            channel::unbounded();
+
+        let poller = Arc::new(Poller::new()?);
+        poller.add(&stream, polling::Event::readable(REDIS_STREAM_POLL_KEY))?;
+        let gate = publish_control.gate(
+            stream.peer_addr()?.ip().to_string(),
+            publish_gate::poller_waker(poller.clone()),
+        );
+
+        let (shutdown, shutdown_receiver) = shutdown::signal(poller.clone());
+        let shutdown = shutdown.with_stream(stream.try_clone()?);
+
+        Ok(Self {
+            message_sender,
+            poller: poller.clone(),
+            handler_thread: Some(Self::start_handler_thread(
+                RedisSession::new(id, stream, event_sender, shutdown_receiver.clone(), gate),
+                message_receiver,
+                poller,
+                shutdown_receiver,
+            )),
+            shutdown,
+        })
+    }
+
+    fn start_handler_thread(
+        session: RedisSession,
+        message_receiver: Receiver<Message>,
+        poller: Arc<Poller>,
+        shutdown: ShutdownReceiver,
+    ) -> JoinHandle<()> {
+        thread::spawn(move || {
+            let id = session.id;
+            let event_sender = session.event_sender.clone();
+
+            Self::handle_client(session, message_receiver, poller, shutdown);
+
+            // Let the PubSub forget about this client.
+            if let Err(e) = event_sender.send(Event::Disconnection(id)) {
+                log::error!("Failed sending Disconnection event for [{}]: [{}]", id, e);
+            }
+        })
+    }
+
+    fn handle_client(
+        mut session: RedisSession,
+        message_receiver: Receiver<Message>,
+        poller: Arc<Poller>,
+        shutdown: ShutdownReceiver,
+    ) {
+        // Connections reset right after being accepted have no peer address.
+        let address = match session.stream.peer_addr() {
+            Ok(address) => address,
+            Err(e) => {
+                log::error!("Failed handling Redis client [{}]: [{}]", session.id, e);
+                return;
+            }
+        };
+        log::info!(
+            "Handling Redis client: id=[{}], address=[{}]",
+            session.id,
+            address
+        );
+
+        let stream = session.stream.try_clone().unwrap();
+        let mut reader = BufReader::new(&stream);
+        let mut poll_events: Vec<polling::Event> = Vec::new();
+        while !shutdown.is_requested() {
+            // Stop reading commands while the client is held back by its
+            // publications.
+            let paused = session.gate.is_paused();
+            let interest = if paused {
+                polling::Event::none(REDIS_STREAM_POLL_KEY)
+            } else {
+                polling::Event::readable(REDIS_STREAM_POLL_KEY)
+            };
+
+            // Modify the poller's interest in the client's stream.
+            // This is required to receive multiple read events on macOS.
+            if let Err(e) = poller.modify(&session.stream, interest) {
+                log::error!(
+                    "Failed to modify the poller's interest in Redis client [{}]: [{}]",
+                    session.id,
+                    e
+                );
+                break;
+            }
+
+            // Clear all previous poll events.
+            poll_events.clear();
+
+            // Wait for an I/O event, a published message or a shutdown
+            // request. Commands already buffered are handled without waiting.
+            let timeout = if reader.buffer().is_empty() || paused {
+                None
+            } else {
+                Some(Duration::ZERO)
+            };
+            if let Err(e) = poller.wait(&mut poll_events, session.gate.timeout(timeout)) {
+                log::error!("Failed polling for events from [{}]: [{}]", session.id, e);
+                continue;
+            }
+
+            // Forward published messages to the client.
+            if let Err(e) = session.write_messages(&message_receiver) {
+                log::error!("Error writing messages to [{}]: [{}]", session.id, e);
+                break;
+            }
+
+            // Check if the client sent anything.
+            if paused || (poll_events.is_empty() && reader.buffer().is_empty()) {
+                continue;
+            }
+
+            // Receive a command from the client.
+            let command = match RespCommand::read(&mut reader) {
+                Ok(Some(command)) => command,
+                Ok(None) => break,
+                Err(e) => {
+                    log::error!(
+                        "Error receiving Redis command from [{}]: [{}]",
+                        session.id,
+                        e
+                    );
+                    let _ = RespReply::Error(format!("ERR Protocol error: {}", e))
+                        .write(&mut session.stream);
+                    break;
+                }
+            };
+
+            // Handle the command.
+            match session.handle_command(command) {
+                Ok(true) => {}
+                Ok(false) => break,
+                Err(e) => {
+                    log::error!(
+                        "Error handling Redis command from [{}]: [{}]",
+                        session.id,
+                        e
+                    );
+                    break;
+                }
+            }
+        }
+    }
+}
+
+impl Subscriber for RedisHandler {
+    fn publish(&self, message: Message) -> error::Result<()> {
+        self.message_sender.send(message)?;
+        self.poller.notify()?;
+        Ok(())
+    }
+
+    fn queue_len(&self) -> usize {
+        self.message_sender.len()
+    }
+}
+
+impl Drop for RedisHandler {
+    fn drop(&mut self) {
+        if let Some(thread) = self.handler_thread.take() {
+            // Indicate the handler thread that it should terminate, waking
+            // it up.
+            self.shutdown.request();
+
+            // Join the handler thread.
+            thread.join().unwrap();
+        }
+    }
+}
+
+struct RedisSession {
+    id: Uuid,
+    stream: TcpStream,
+    event_sender: Sender<Event>,
+    shutdown: ShutdownReceiver,
+    channels: BTreeSet<String>,
+    patterns: BTreeSet<String>,
+    gate: PublishGate,
+}
+
+impl RedisSession {
+    fn new(
+        id: Uuid,
+        stream: TcpStream,
+        event_sender: Sender<Event>,
+        shutdown: ShutdownReceiver,
+        gate: PublishGate,
+    ) -> Self {
+        Self {
+            id,
+            stream,
+            event_sender,
+            shutdown,
+            channels: BTreeSet::new(),
+            patterns: BTreeSet::new(),
+            gate,
+        }
+    }
+
+    // Returns whether the session should keep running.
+    fn handle_command(&mut self, command: RespCommand) -> error::Result<bool> {
+        log::debug!("Redis command from [{}]: [{}]", self.id, command.name);
+
+        // Only subscription commands are allowed once the client subscribed.
+        let subscribed = !self.channels.is_empty() || !self.patterns.is_empty();
+        let allowed_when_subscribed = matches!(
+            command.name.as_str(),
+            "SUBSCRIBE" | "UNSUBSCRIBE" | "PSUBSCRIBE" | "PUNSUBSCRIBE" | "PING" | "QUIT"
+        );
+        if subscribed && !allowed_when_subscribed {
+            return self.reply(RespReply::Error(format!(
+                "ERR Can't execute '{}': only (P)SUBSCRIBE / (P)UNSUBSCRIBE / PING / QUIT are \
+                 allowed in this context",
+                command.name.to_ascii_lowercase()
+            )));
+        }
+
+        // The published payload may be arbitrary bytes, other arguments are
+        // names of channels and patterns.
+        if "PUBLISH" == command.name {
+            return self.handle_publish(command.arguments);
+        }
+
+        let arguments = match command
+            .arguments
+            .into_iter()
+            .map(String::from_utf8)
+            .collect::<Result<Vec<String>, _>>()
+        {
+            Ok(arguments) => arguments,
+            Err(_) => {
+                return self.reply(RespReply::Error(
+                    "ERR arguments must be valid UTF-8".to_owned(),
+                ))
+            }
+        };
+
+        match (command.name.as_str(), arguments.as_slice()) {
+            ("PING", []) if subscribed => self.reply(RespReply::Array(vec![
+                RespReply::bulk("pong"),
+                RespReply::bulk(""),
+            ])),
+            ("PING", [argument]) if subscribed => self.reply(RespReply::Array(vec![
+                RespReply::bulk("pong"),
+                RespReply::bulk(argument.as_str()),
+            ])),
+            ("PING", []) => self.reply(RespReply::Simple("PONG".to_owned())),
+            ("PING", [argument]) => self.reply(RespReply::bulk(argument.as_str())),
+            ("QUIT", []) => {
+                self.reply(RespReply::Simple("OK".to_owned()))?;
+                Ok(false)
+            }
+            ("SUBSCRIBE", channels) if !channels.is_empty() => {
+                self.handle_subscribe(channels.to_vec(), false)
+            }
+            ("PSUBSCRIBE", patterns) if !patterns.is_empty() => {
+                self.handle_subscribe(patterns.to_vec(), true)
+            }
+            ("UNSUBSCRIBE", channels) => self.handle_unsubscribe(channels.to_vec(), false),
+            ("PUNSUBSCRIBE", patterns) => self.handle_unsubscribe(patterns.to_vec(), true),
+            ("PING" | "QUIT" | "SUBSCRIBE" | "PSUBSCRIBE", _) => {
+                self.reply(RespReply::Error(format!(
+                    "ERR wrong number of arguments for '{}' command",
+                    command.name.to_ascii_lowercase()
+                )))
+            }
+            (name, _) => self.reply(RespReply::Error(format!(
+                "ERR unknown command '{}'",
+                name.to_ascii_lowercase()
+            ))),
+        }
+    }
+
+    fn handle_publish(&mut self, mut arguments: Vec<Vec<u8>>) -> error::Result<bool> {
+        if 2 != arguments.len() {
+            return self.reply(RespReply::Error(
+                "ERR wrong number of arguments for 'publish' command".to_owned(),
+            ));
+        }
+
+        let data = arguments.pop().unwrap();
+        let channel = match String::from_utf8(arguments.pop().unwrap()) {
+            Ok(channel) => channel,
+            Err(_) => {
+                return self.reply(RespReply::Error(
+                    "ERR channel must be valid UTF-8".to_owned(),
+                ))
+            }
+        };
+
+        let message = match self.gate.pass_one(Message::new(channel, data)) {
+            Ok(message) => message,
+            Err(e) => return self.reply(RespReply::Error(format!("ERR {}", e))),
+        };
+
+        // Publish the message and wait for the number of subscribers it reached.
+        let (receivers_sender, receivers_receiver): (Sender<usize>, Receiver<usize>) =
+            channel::bounded(1);
+        self.event_sender
+            .send(Event::CountedPublish(message, receivers_sender))?;
+        let receivers = self.shutdown.recv(&receivers_receiver)?;
+
+        self.reply(RespReply::Integer(receivers as i64))
+    }
+
+    fn handle_subscribe(&mut self, names: Vec<String>, pattern: bool) -> error::Result<bool> {
+        let kind = if pattern { "psubscribe" } else { "subscribe" };
+
+        let mut filters: Vec<TopicFilter> = Vec::with_capacity(names.len());
+        let mut replies: Vec<RespReply> = Vec::with_capacity(names.len());
+        for name in names.into_iter() {
+            let subscriptions = if pattern {
+                &mut self.patterns
+            } else {
+                &mut self.channels
+            };
+
+            if subscriptions.insert(name.clone()) {
+                filters.push(Self::topic_filter(&name, pattern));
+            }
+
+            replies.push(RespReply::Array(vec![
+                RespReply::bulk(kind),
+                RespReply::bulk(name),
+                RespReply::Integer(self.subscriptions_number()),
+            ]));
+        }
+
+        // Register the subscriptions before acknowledging them.
+        self.event_sender.send(Event::Subscribe(self.id, filters))?;
+
+        for reply in replies.into_iter() {
+            reply.write(&mut self.stream)?;
+        }
+
+        Ok(true)
+    }
+
+    fn handle_unsubscribe(&mut self, names: Vec<String>, pattern: bool) -> error::Result<bool> {
+        let kind = if pattern {
+            "punsubscribe"
+        } else {
+            "unsubscribe"
+        };
+
+        // Unsubscribing without arguments unsubscribes from everything.
+        let names = if names.is_empty() {
+            let subscriptions = if pattern {
+                &self.patterns
+            } else {
+                &self.channels
+            };
+            subscriptions.iter().cloned().collect()
+        } else {
+            names
+        };
+
+        let mut filters: Vec<TopicFilter> = Vec::with_capacity(names.len());
+        let mut replies: Vec<RespReply> = Vec::with_capacity(names.len());
+        for name in names.iter() {
+            let subscriptions = if pattern {
+                &mut self.patterns
+            } else {
+                &mut self.channels
+            };
+
+            if subscriptions.remove(name) {
+                let filter = Self::topic_filter(name, pattern);
+
+                // A channel and a pattern without wildcards share the same
+                // filter, which must stay registered while either remains.
+                if !self.is_subscribed_to(&filter) {
+                    filters.push(filter);
+                }
+            }
+
+            replies.push(RespReply::Array(vec![
+                RespReply::bulk(kind),
+                RespReply::bulk(name.as_str()),
+                RespReply::Integer(self.subscriptions_number()),
+            ]));
+        }
+
+        if replies.is_empty() {
+            replies.push(RespReply::Array(vec![
+                RespReply::bulk(kind),
+                RespReply::Bulk(None),
+                RespReply::Integer(self.subscriptions_number()),
+            ]));
+        }
+
+        self.event_sender
+            .send(Event::Unsubscribe(self.id, filters))?;
+
+        for reply in replies.into_iter() {
+            reply.write(&mut self.stream)?;
+        }
+
+        Ok(true)
+    }
+
+    fn write_messages(&mut self, message_receiver: &Receiver<Message>) -> error::Result<()> {
+        for message in message_receiver.try_iter() {
+            // Deliver the message once per matching subscription, like Redis.
+            if self.channels.contains(&*message.topic) {
+                RespReply::Array(vec![
+                    RespReply::bulk("message"),
+                    RespReply::bulk(&*message.topic),
+                    RespReply::bulk(message.data.clone()),
+                ])
+                .write(&mut self.stream)?;
+            }
+
+            for pattern in self.patterns.iter() {
+                if TopicFilter::glob(pattern).matches(&message.topic) {
+                    RespReply::Array(vec![
+                        RespReply::bulk("pmessage"),
+                        RespReply::bulk(pattern.as_str()),
+                        RespReply::bulk(&*message.topic),
+                        RespReply::bulk(message.data.clone()),
+                    ])
+                    .write(&mut self.stream)?;
+                }
+            }
+        }
+
+        self.stream.flush()?;
+
+        Ok(())
+    }
+
+    fn reply(&mut self, reply: RespReply) -> error::Result<bool> {
+        reply.write(&mut self.stream)?;
+        Ok(true)
+    }
+
+    fn topic_filter(name: &str, pattern: bool) -> TopicFilter {
+        if pattern {
+            TopicFilter::glob(name)
+        } else {
+            TopicFilter::Exact(name.to_owned())
+        }
+    }
+
+    fn is_subscribed_to(&self, filter: &TopicFilter) -> bool {
+        self.channels
+            .iter()
+            .any(|channel| Self::topic_filter(channel, false) == *filter)
+            || self
+                .patterns
+                .iter()
+                .any(|pattern| Self::topic_filter(pattern, true) == *filter)
+    }
+
+    fn subscriptions_number(&self) -> i64 {
+        (self.channels.len() + self.patterns.len()) as i64
+    }
+}
@@ -0,0 +1,116 @@
+use std::io::{BufRead, Read};
+
+use crate::error::{self, Error};
+use crate::message::MAX_FRAME_SIZE;
+
+const MAX_ARGUMENTS_NUMBER: usize = 64 * 1024;
+const MAX_LINE_SIZE: usize = 64 * 1024;
+
+#[derive(Debug)]
+pub struct RespCommand {
+    pub name: String,
+    pub arguments: Vec<Vec<u8>>,
+}
+
+impl RespCommand {
+    pub fn read(reader: &mut impl BufRead) -> error::Result<Option<Self>> {
+        // Read the first line, stopping if the peer closed the connection.
+        let line = match Self::read_line(reader)? {
+            Some(line) => line,
+            None => return Ok(None),
+        };
+
+        // Read the command's parts, either as an array of bulk strings or as
+        // an inline command.
+        let mut parts = match line.strip_prefix('*') {
+            Some(parts_number) => {
+                let parts_number = Self::parse_size(parts_number, MAX_ARGUMENTS_NUMBER)?;
+
+                // Don't trust the announced number for the allocation, and
+                // bound the total size of the bulk strings.
+                let mut remaining = MAX_FRAME_SIZE;
+                let mut parts: Vec<Vec<u8>> = Vec::new();
+                for _ in 0..parts_number {
+                    parts.push(Self::read_bulk_string(reader, &mut remaining)?);
+                }
+
+                parts
+            }
+            None => line
+                .split_whitespace()
+                .map(|part| part.as_bytes().to_vec())
+                .collect(),
+        };
+
+        if parts.is_empty() {
+            return Err(Error::MalformedRespCommand("empty command".to_owned()));
+        }
+
+        let name = String::from_utf8(parts.remove(0))?.to_ascii_uppercase();
+
+        Ok(Some(Self {
+            name,
+            arguments: parts,
+        }))
+    }
+
+    fn read_bulk_string(
+        reader: &mut impl BufRead,
+        remaining: &mut usize,
+    ) -> error::Result<Vec<u8>> {
+        // Read the bulk string's size.
+        let line = Self::read_line(reader)?
+            .ok_or_else(|| Error::MalformedRespCommand("truncated command".to_owned()))?;
+        let size = match line.strip_prefix('$') {
+            Some(size) => Self::parse_size(size, usize::MAX)?,
+            None => return Err(Error::MalformedRespCommand(line)),
+        };
+        if size > *remaining {
+            return Err(Error::FrameTooLarge(format!(
+                "command exceeds {} bytes",
+                MAX_FRAME_SIZE
+            )));
+        }
+        *remaining -= size;
+
+        // Read the bulk string and its terminating CRLF.
+        let expected = size as u64 + 2;
+        let mut bytes: Vec<u8> = Vec::new();
+        if reader.take(expected).read_to_end(&mut bytes)? as u64 != expected {
+            return Err(Error::MalformedRespCommand("truncated command".to_owned()));
+        }
+        if !bytes.ends_with(b"\r\n") {
+            return Err(Error::MalformedRespCommand(
+                "bulk string is not terminated by CRLF".to_owned(),
+            ));
+        }
+        bytes.truncate(size);
+
+        Ok(bytes)
+    }
+
+    fn read_line(reader: &mut impl BufRead) -> error::Result<Option<String>> {
+        let mut line = String::new();
+        if 0 == reader.take(MAX_LINE_SIZE as u64 + 1).read_line(&mut line)? {
+            return Ok(None);
+        }
+        if line.len() > MAX_LINE_SIZE {
+            return Err(Error::FrameTooLarge(format!(
+                "line exceeds {} bytes",
+                MAX_LINE_SIZE
+            )));
+        }
+
+        Ok(Some(line.trim_end_matches(['\r', '\n']).to_owned()))
+    }
+
+    fn parse_size(size: &str, max: usize) -> error::Result<usize> {
+        match size.parse::<usize>() {
+            Ok(size) if size <= max => Ok(size),
+            _ => Err(Error::MalformedRespCommand(format!(
+                "invalid size: {}",
+                size
+            ))),
+        }
+    }
+}
@@ -0,0 +1,40 @@
+use std::io::Write;
+
+use crate::error;
+
+#[derive(Debug)]
+pub enum RespReply {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<RespReply>),
+}
+
+impl RespReply {
+    pub fn bulk(bytes: impl Into<Vec<u8>>) -> Self {
+        Self::Bulk(Some(bytes.into()))
+    }
+
+    pub fn write(&self, writer: &mut impl Write) -> error::Result<()> {
+        match self {
+            Self::Simple(string) => write!(writer, "+{}\r\n", string)?,
+            Self::Error(string) => write!(writer, "-{}\r\n", string)?,
+            Self::Integer(integer) => write!(writer, ":{}\r\n", integer)?,
+            Self::Bulk(Some(bytes)) => {
+                write!(writer, "${}\r\n", bytes.len())?;
+                writer.write_all(bytes)?;
+                writer.write_all(b"\r\n")?;
+            }
+            Self::Bulk(None) => writer.write_all(b"$-1\r\n")?,
+            Self::Array(replies) => {
+                write!(writer, "*{}\r\n", replies.len())?;
+                for reply in replies.iter() {
+                    reply.write(writer)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,488 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Barrier};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crossbeam::channel::{self, Receiver, RecvTimeoutError, Sender};
+use uuid::Uuid;
+
+use crate::backpressure::Backpressure;
+use crate::config::Config;
+use crate::consumer_group::{ConsumerGroup, GROUP_HEADER, ORDERING_KEY_HEADER};
+use crate::dead_letter::{DeadLetterReason, DeadLetters};
+use crate::dedup::Dedup;
+use crate::error;
+use crate::event::Event;
+use crate::expiry::Expiry;
+use crate::message::Message;
+use crate::metrics::Metrics;
+use crate::scheduler::{ScheduleLimits, Scheduler};
+use crate::subscriber::{Subscriber, SubscriberHandlers};
+use crate::topic_filter::TopicFilter;
+
+// How often the congested topics of a shard are checked for having drained.
+const CONGESTION_CHECK_INTERVAL_MS: u64 = 50;
+
+// The shard that routes the messages of the topic.
+pub fn shard_of(topic: &str, shards: usize) -> usize {
+    let mut hasher = DefaultHasher::new();
+    topic.hash(&mut hasher);
+    (hasher.finish() % shards as u64) as usize
+}
+
+// Routes the messages of the topics that hash to one shard of the topic
+// space, on a thread of its own. A shard only knows the subscribers of its
+// own topics, but every shard knows the subscribers of the filters and of
+// the consumer groups, since those may match topics of any shard.
+pub struct RouterShard {
+    event_sender: Sender<Event>,
+    router_thread: Option<JoinHandle<()>>,
+}
+
+struct Router {
+    index: usize,
+    subscriber_to_handler: SubscriberHandlers,
+    topic_to_subscribers: HashMap<String, Vec<Uuid>>,
+    filter_to_subscribers: HashMap<TopicFilter, Vec<Uuid>>,
+    consumer_groups: HashMap<(String, TopicFilter), ConsumerGroup>,
+    metrics: Arc<Metrics>,
+    expiry: Expiry,
+    scheduler: Scheduler,
+    dedup: Dedup,
+    batch_deliveries: Option<HashMap<Uuid, Vec<Message>>>,
+    dead_letters: Arc<DeadLetters>,
+    backpressure: Arc<Backpressure>,
+    // The congested lossless topics of the shard.
+    congested_topics: HashSet<String>,
+    last_congestion_check: Instant,
+    event_receiver: Receiver<Event>,
+}
+
+impl RouterShard {
+    pub fn new(
+        index: usize,
+        config: &Config,
+        subscriber_to_handler: SubscriberHandlers,
+        metrics: Arc<Metrics>,
+        dead_letters: Arc<DeadLetters>,
+        backpressure: Arc<Backpressure>,
+        schedule_limits: Arc<ScheduleLimits>,
+    ) -> Self {
+        let (event_sender, event_receiver): (Sender<Event>, Receiver<Event>) = channel::unbounded();
+
+        let router = Router {
+            index,
+            subscriber_to_handler,
+            topic_to_subscribers: HashMap::new(),
+            filter_to_subscribers: HashMap::new(),
+            consumer_groups: HashMap::new(),
+            metrics,
+            expiry: Expiry::new(&config.topic_ttls),
+            scheduler: Scheduler::new(schedule_limits),
+            dedup: Dedup::new(Duration::from_millis(config.dedup_window_ms)),
+            batch_deliveries: None,
+            dead_letters,
+            backpressure,
+            congested_topics: HashSet::new(),
+            last_congestion_check: Instant::now(),
+            event_receiver,
+        };
+
+        Self {
+            event_sender,
+            router_thread: Some(thread::spawn(move || router.process_events())),
+        }
+    }
+
+    pub fn send(&self, event: Event) -> error::Result<()> {
+        self.event_sender.send(event)?;
+        Ok(())
+    }
+}
+
+impl Drop for RouterShard {
+    fn drop(&mut self) {
+        if let Some(thread) = self.router_thread.take() {
+            // Indicate the router thread that it should terminate.
+            if let Err(e) = self.event_sender.send(Event::Termination) {
+                log::error!("Failed sending Termination event to a shard: [{}]", e);
+            }
+
+            // Join the router thread.
+            thread.join().unwrap();
+        }
+    }
+}
+
+impl Router {
+    fn process_events(mut self) {
+        log::info!("Shard [{}] starting to route events", self.index);
+
+        loop {
+            // Publish the scheduled messages that are due.
+            self.publish_due_messages();
+
+            // Resume the publishers of the topics that drained.
+            self.check_congestion();
+
+            // Receive an event from the channel, waking up in time to publish
+            // the next scheduled message or to check the congested topics.
+            let timeout = match (self.scheduler.next_due(), self.next_congestion_check()) {
+                (Some(due), Some(check)) => Some(due.min(check)),
+                (due, check) => due.or(check),
+            };
+            let event = match timeout {
+                Some(timeout) => match self.event_receiver.recv_timeout(timeout) {
+                    Ok(event) => event,
+                    Err(RecvTimeoutError::Timeout) => continue,
+                    Err(RecvTimeoutError::Disconnected) => break,
+                },
+                None => match self.event_receiver.recv() {
+                    Ok(event) => event,
+                    Err(_) => break,
+                },
+            };
+            log::info!("Shard [{}] received event: [{}]", self.index, event);
+
+            // Handle the event.
+            match event {
+                Event::CountedPublish(message, receivers_sender) => {
+                    let receivers = self.handle_publish(message);
+                    if let Err(e) = receivers_sender.send(receivers) {
+                        log::error!("Failed sending the number of receivers: [{}]", e);
+                    }
+                }
+                Event::Disconnection(id) => self.handle_disconnection(id),
+                Event::GroupSubscribe(id, group, filter) => {
+                    self.handle_group_subscribe(id, group, filter)
+                }
+                Event::GroupUnsubscribe(id, group, filter) => {
+                    self.handle_group_unsubscribe(id, group, filter)
+                }
+                Event::Publish(message) => {
+                    self.handle_publish(message);
+                }
+                Event::BatchPart(messages, barrier) => self.handle_batch_part(messages, &barrier),
+                Event::Subscribe(id, filters) => self.handle_subscribe(id, filters),
+                Event::Unsubscribe(id, filters) => self.handle_unsubscribe(id, filters),
+                Event::Termination => break,
+                event => log::error!("Shard [{}] can't route event: [{}]", self.index, event),
+            }
+        }
+
+        log::info!("Shard [{}] stopped routing events", self.index);
+    }
+
+    fn handle_disconnection(&mut self, id: Uuid) {
+        // Unregister the subscriber from all topics and filters.
+        self.topic_to_subscribers.retain(|_, subscribers| {
+            subscribers.retain(|subscriber| *subscriber != id);
+            !subscribers.is_empty()
+        });
+        self.filter_to_subscribers.retain(|_, subscribers| {
+            subscribers.retain(|subscriber| *subscriber != id);
+            !subscribers.is_empty()
+        });
+        self.consumer_groups.retain(|_, group| {
+            group.leave(id);
+            !group.is_empty()
+        });
+    }
+
+    fn publish_due_messages(&mut self) {
+        for message in self.scheduler.take_due() {
+            self.route(message);
+        }
+    }
+
+    fn handle_publish(&mut self, message: Message) -> usize {
+        // Acknowledge retried messages without routing them again.
+        if self.dedup.is_duplicate(&message) {
+            log::info!("Dropping duplicate message to topic: [{}]", message.topic);
+            self.metrics.increment("duplicate_messages_total");
+            return 0;
+        }
+
+        // Hold messages scheduled for later.
+        match self.scheduler.schedule(message) {
+            Ok(Some(message)) => self.route(message),
+            Ok(None) => 0,
+            Err(e) => {
+                log::warn!("Dropping message: [{}]", e);
+                0
+            }
+        }
+    }
+
+    fn handle_batch_part(&mut self, messages: Vec<Message>, barrier: &Barrier) {
+        // No other event is handled by the shard in between, so its part of
+        // batches and of committed transactions is routed together.
+        log::info!("Publishing a batch of [{}] messages", messages.len());
+        self.batch_deliveries = Some(HashMap::new());
+        for message in messages.into_iter() {
+            self.handle_publish(message);
+        }
+
+        // Wait for the shards of the other parts, so that no shard delivers
+        // its part before all parts were routed.
+        barrier.wait();
+
+        // Hand each subscriber all of its messages of the batch at once.
+        let subscriber_to_handler = self.subscriber_to_handler.read().unwrap();
+        for (subscriber, messages) in self.batch_deliveries.take().unwrap_or_default() {
+            match subscriber_to_handler.get(&subscriber) {
+                Some(handler) => {
+                    if let Err(e) = handler.publish_batch(messages) {
+                        log::error!(
+                            "Error publishing batch to subscriber [{}]: [{}]",
+                            subscriber,
+                            e
+                        );
+                    }
+                }
+                None => log::error!("No handler for subscriber: [{}]", subscriber),
+            }
+        }
+    }
+
+    fn route(&mut self, mut message: Message) -> usize {
+        if let Err(e) = self.expiry.stamp(&mut message) {
+            log::warn!("Dropping message to topic [{}]: [{}]", message.topic, e);
+            return 0;
+        }
+
+        // Dead-letter expired messages before fanning them out, so that each
+        // of them is dead-lettered once.
+        if Expiry::is_expired(&message) {
+            self.metrics
+                .increment("expired_messages_total{stage=\"route\"}");
+            self.dead_letters.route(message, DeadLetterReason::Expired);
+            return 0;
+        }
+
+        let topic = message.topic.clone();
+        let receivers = self.publish(message);
+
+        // Pause the publishers of lossless topics that subscribers can't keep
+        // up with.
+        if self.backpressure.is_lossless(&topic) && self.update_backpressure(&topic) {
+            self.congested_topics.insert(topic.to_string());
+        }
+
+        receivers
+    }
+
+    fn next_congestion_check(&self) -> Option<Duration> {
+        if self.congested_topics.is_empty() {
+            return None;
+        }
+
+        Some(
+            Duration::from_millis(CONGESTION_CHECK_INTERVAL_MS)
+                .saturating_sub(self.last_congestion_check.elapsed()),
+        )
+    }
+
+    fn check_congestion(&mut self) {
+        // Subscribers drain their queues on their own threads, so the shard
+        // of a congested topic watches it until it drained.
+        if !matches!(self.next_congestion_check(), Some(Duration::ZERO)) {
+            return;
+        }
+        self.last_congestion_check = Instant::now();
+
+        let topics: Vec<String> = self.congested_topics.iter().cloned().collect();
+        for topic in topics.into_iter() {
+            if !self.update_backpressure(&topic) {
+                self.congested_topics.remove(&topic);
+            }
+        }
+    }
+
+    // Returns whether the topic is congested.
+    fn update_backpressure(&self, topic: &str) -> bool {
+        // Every member of a group may be picked, so all of them count.
+        let mut subscribers: Vec<&Uuid> = self
+            .topic_to_subscribers
+            .get(topic)
+            .into_iter()
+            .flatten()
+            .collect();
+        for (filter, filter_subscribers) in self.filter_to_subscribers.iter() {
+            if filter.matches(topic) {
+                subscribers.extend(filter_subscribers.iter());
+            }
+        }
+        for ((_, filter), consumer_group) in self.consumer_groups.iter() {
+            if filter.matches(topic) {
+                subscribers.extend(consumer_group.members());
+            }
+        }
+
+        let subscriber_to_handler = self.subscriber_to_handler.read().unwrap();
+        let longest_queue_len = subscribers
+            .into_iter()
+            .filter_map(|subscriber| subscriber_to_handler.get(subscriber))
+            .map(|handler| handler.queue_len())
+            .max()
+            .unwrap_or_default();
+        self.backpressure.update(topic, longest_queue_len)
+    }
+
+    fn publish(&mut self, mut message: Message) -> usize {
+        log::info!("Publishing message to topic: [{}]", message.topic);
+
+        // Collect the subscribers of the topic and of all matching filters,
+        // delivering to each subscriber at most once.
+        let mut subscribers: Vec<Uuid> = self
+            .topic_to_subscribers
+            .get(&*message.topic)
+            .cloned()
+            .unwrap_or_default();
+        for (filter, filter_subscribers) in self.filter_to_subscribers.iter() {
+            if filter.matches(&message.topic) {
+                for subscriber in filter_subscribers.iter() {
+                    if !subscribers.contains(subscriber) {
+                        subscribers.push(*subscriber);
+                    }
+                }
+            }
+        }
+
+        // Pick a single member of each matching consumer group.
+        let ordering_key = message.headers.get(ORDERING_KEY_HEADER).map(String::as_str);
+        let mut group_members: Vec<(String, Uuid)> = Vec::new();
+        for ((group, filter), consumer_group) in self.consumer_groups.iter_mut() {
+            if filter.matches(&message.topic) {
+                if let Some(member) = consumer_group.next_member(ordering_key) {
+                    group_members.push((group.clone(), member));
+                }
+            }
+        }
+
+        if subscribers.is_empty() && group_members.is_empty() {
+            log::warn!("No subscribers registered to topic: [{}]", message.topic);
+            self.dead_letters
+                .route(message, DeadLetterReason::NoSubscribers);
+            return 0;
+        }
+
+        // Only the broker may mark copies as delivered on behalf of a group.
+        message.headers_mut().remove(GROUP_HEADER);
+
+        for (group, member) in group_members.iter() {
+            let mut group_message = message.clone();
+            group_message
+                .headers_mut()
+                .insert(GROUP_HEADER.to_owned(), group.clone());
+            self.publish_message_to_subscribers(group_message, &[*member]);
+        }
+
+        self.publish_message_to_subscribers(message, &subscribers);
+        subscribers.len() + group_members.len()
+    }
+
+    fn handle_subscribe(&mut self, id: Uuid, filters: Vec<TopicFilter>) {
+        log::info!("Subscribe from: [{}]", id);
+
+        // Register the subscriber to all requested filters, keeping exact
+        // topics in the topic map for direct lookup.
+        for filter in filters.into_iter() {
+            let subscribers = match filter {
+                TopicFilter::Exact(topic) => self.topic_to_subscribers.entry(topic).or_default(),
+                filter => self.filter_to_subscribers.entry(filter).or_default(),
+            };
+
+            if !subscribers.contains(&id) {
+                subscribers.push(id);
+            }
+        }
+    }
+
+    fn handle_group_subscribe(&mut self, id: Uuid, group: String, filter: TopicFilter) {
+        log::info!("Subscribe from [{}] as a member of group: [{}]", id, group);
+
+        self.consumer_groups
+            .entry((group, filter))
+            .or_default()
+            .join(id);
+    }
+
+    fn handle_group_unsubscribe(&mut self, id: Uuid, group: String, filter: TopicFilter) {
+        log::info!(
+            "Unsubscribe from [{}] as a member of group: [{}]",
+            id,
+            group
+        );
+
+        let key = (group, filter);
+        if let Some(consumer_group) = self.consumer_groups.get_mut(&key) {
+            consumer_group.leave(id);
+
+            // Forget groups that no longer have members.
+            if consumer_group.is_empty() {
+                self.consumer_groups.remove(&key);
+            }
+        }
+    }
+
+    fn handle_unsubscribe(&mut self, id: Uuid, filters: Vec<TopicFilter>) {
+        log::info!("Unsubscribe from: [{}]", id);
+
+        // Unregister the subscriber from all requested filters.
+        for filter in filters.into_iter() {
+            match filter {
+                TopicFilter::Exact(topic) => {
+                    Self::unregister_subscriber(&mut self.topic_to_subscribers, topic, id)
+                }
+                filter => Self::unregister_subscriber(&mut self.filter_to_subscribers, filter, id),
+            }
+        }
+    }
+
+    fn unregister_subscriber<K: Hash + Eq>(
+        key_to_subscribers: &mut HashMap<K, Vec<Uuid>>,
+        key: K,
+        id: Uuid,
+    ) {
+        if let Some(subscribers) = key_to_subscribers.get_mut(&key) {
+            subscribers.retain(|subscriber| *subscriber != id);
+
+            // Forget keys that no longer have subscribers.
+            if subscribers.is_empty() {
+                key_to_subscribers.remove(&key);
+            }
+        }
+    }
+
+    fn publish_message_to_subscribers(&mut self, message: Message, subscribers: &[Uuid]) {
+        let subscriber_to_handler = self.subscriber_to_handler.read().unwrap();
+        for subscriber in subscribers {
+            // Collect the deliveries of a batch until it's routed.
+            if let Some(batch_deliveries) = self.batch_deliveries.as_mut() {
+                batch_deliveries
+                    .entry(*subscriber)
+                    .or_default()
+                    .push(message.clone());
+                continue;
+            }
+
+            match subscriber_to_handler.get(subscriber) {
+                Some(handler) => Self::publish_message_to_subscriber(
+                    message.clone(),
+                    subscriber,
+                    handler.as_ref(),
+                ),
+                None => log::error!("No handler for subscriber: [{}]", subscriber),
+            }
+        }
+    }
+
+    fn publish_message_to_subscriber(message: Message, id: &Uuid, handler: &dyn Subscriber) {
+        if let Err(e) = handler.publish(message) {
+            log::error!("Error publishing message to subscriber [{}]: [{}]", id, e);
+        }
+    }
+}
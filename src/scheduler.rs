@@ -0,0 +1,370 @@
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+use std::sync::atomic::{self, AtomicUsize};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::error::{self, Error};
+use crate::message::Message;
+
+// The time to deliver a message at, in milliseconds since the UNIX epoch.
+pub const DELIVER_AT_HEADER: &str = "deliver-at";
+// The delay to deliver a message after, in milliseconds.
+pub const DELAY_HEADER: &str = "delay-ms";
+
+#[derive(Debug)]
+struct ScheduledMessage {
+    deliver_at: u64,
+    // Keeps messages that are due at the same time in publishing order.
+    sequence: u64,
+    message: Message,
+}
+
+impl PartialEq for ScheduledMessage {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for ScheduledMessage {}
+
+impl PartialOrd for ScheduledMessage {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for ScheduledMessage {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.deliver_at, self.sequence).cmp(&(other.deliver_at, other.sequence))
+    }
+}
+
+// Bounds the messages held by the schedulers of all shards, in how far ahead
+// they may be scheduled and in their total size. Messages reserve their part
+// of the budget when they're accepted from their publisher, so that the
+// publisher can be told about rejected messages, and give it back once they're
+// due or dropped.
+#[derive(Debug)]
+pub struct ScheduleLimits {
+    max_delay: Duration,
+    max_bytes: usize,
+    scheduled_bytes: AtomicUsize,
+}
+
+impl ScheduleLimits {
+    pub fn new(max_delay: Duration, max_bytes: usize) -> Self {
+        Self {
+            max_delay,
+            max_bytes,
+            scheduled_bytes: AtomicUsize::new(0),
+        }
+    }
+
+    // Reserves the budget of the message if it's scheduled for later and
+    // doesn't hold its part yet.
+    pub fn reserve(self: &Arc<Self>, message: &mut Message) -> error::Result<()> {
+        let now = Scheduler::now();
+        let delay = match Scheduler::deliver_at(message, now)? {
+            Some(deliver_at) if deliver_at > now => deliver_at - now,
+            _ => return Ok(()),
+        };
+        if message.schedule_reservation.is_some() {
+            return Ok(());
+        }
+
+        if delay > self.max_delay.as_millis() as u64 {
+            return Err(Error::ScheduleRejected(format!(
+                "scheduled {}ms ahead, at most {}ms are allowed",
+                delay,
+                self.max_delay.as_millis()
+            )));
+        }
+
+        let size = message.data.len();
+        self.scheduled_bytes
+            .fetch_update(
+                atomic::Ordering::Relaxed,
+                atomic::Ordering::Relaxed,
+                |bytes| Some(bytes + size).filter(|bytes| *bytes <= self.max_bytes),
+            )
+            .map_err(|_| Error::ScheduleRejected("too many scheduled messages".to_owned()))?;
+        message.schedule_reservation = Some(Arc::new(ScheduleReservation {
+            limits: self.clone(),
+            size,
+        }));
+
+        Ok(())
+    }
+}
+
+// A scheduled message's part of the budget, shared by its copies and given
+// back when the last of them is dropped.
+#[derive(Debug)]
+pub struct ScheduleReservation {
+    limits: Arc<ScheduleLimits>,
+    size: usize,
+}
+
+impl Drop for ScheduleReservation {
+    fn drop(&mut self) {
+        self.limits
+            .scheduled_bytes
+            .fetch_sub(self.size, atomic::Ordering::Relaxed);
+    }
+}
+
+// Holds the messages published with a delivery time or a delay until they
+// are due. Scheduled messages are kept in memory only, and are lost when the
+// server stops.
+#[derive(Debug)]
+pub struct Scheduler {
+    scheduled: BinaryHeap<Reverse<ScheduledMessage>>,
+    next_sequence: u64,
+    limits: Arc<ScheduleLimits>,
+}
+
+impl Scheduler {
+    pub fn new(limits: Arc<ScheduleLimits>) -> Self {
+        Self {
+            scheduled: BinaryHeap::new(),
+            next_sequence: 0,
+            limits,
+        }
+    }
+
+    // Holds the message if it's scheduled for later, returning it otherwise.
+    pub fn schedule(&mut self, mut message: Message) -> error::Result<Option<Message>> {
+        let now = Self::now();
+        let deliver_at = Self::deliver_at(&message, now)?;
+
+        // Messages that weren't checked on their way here reserve their part
+        // of the budget now.
+        if deliver_at.is_some_and(|deliver_at| deliver_at > now) {
+            self.limits.reserve(&mut message)?;
+        }
+
+        // The broker consumes the scheduling headers.
+        if deliver_at.is_some() {
+            let headers = message.headers_mut();
+            headers.remove(DELIVER_AT_HEADER);
+            headers.remove(DELAY_HEADER);
+        }
+
+        let deliver_at = match deliver_at {
+            Some(deliver_at) if deliver_at > now => deliver_at,
+            _ => {
+                message.schedule_reservation = None;
+                return Ok(Some(message));
+            }
+        };
+
+        log::info!(
+            "Scheduling message to topic [{}] in [{}ms]",
+            message.topic,
+            deliver_at - now
+        );
+        self.scheduled.push(Reverse(ScheduledMessage {
+            deliver_at,
+            sequence: self.next_sequence,
+            message,
+        }));
+        self.next_sequence = self.next_sequence.wrapping_add(1);
+
+        Ok(None)
+    }
+
+    // The time left until the next scheduled message is due.
+    pub fn next_due(&self) -> Option<Duration> {
+        self.scheduled.peek().map(|Reverse(scheduled)| {
+            Duration::from_millis(scheduled.deliver_at.saturating_sub(Self::now()))
+        })
+    }
+
+    pub fn take_due(&mut self) -> Vec<Message> {
+        let now = Self::now();
+
+        let mut messages: Vec<Message> = Vec::new();
+        while let Some(Reverse(scheduled)) = self.scheduled.peek() {
+            if scheduled.deliver_at > now {
+                break;
+            }
+            if let Some(Reverse(mut scheduled)) = self.scheduled.pop() {
+                scheduled.message.schedule_reservation = None;
+                messages.push(scheduled.message);
+            }
+        }
+
+        messages
+    }
+
+    // The time the message is scheduled for, if it's scheduled at all.
+    fn deliver_at(message: &Message, now: u64) -> error::Result<Option<u64>> {
+        let deliver_at = Self::parse_header(message, DELIVER_AT_HEADER)?;
+        let delay = Self::parse_header(message, DELAY_HEADER)?;
+
+        Ok(match (deliver_at, delay) {
+            (Some(deliver_at), _) => Some(deliver_at),
+            (None, Some(delay)) => Some(now.saturating_add(delay)),
+            (None, None) => None,
+        })
+    }
+
+    fn parse_header(message: &Message, header: &str) -> error::Result<Option<u64>> {
+        message
+            .headers
+            .get(header)
+            .map(|value| {
+                value.parse().map_err(|_| {
+                    Error::MalformedSchedule(format!("invalid {} header: {}", header, value))
+                })
+            })
+            .transpose()
+    }
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::thread;
+
+    use super::*;
+
+    fn scheduler(max_delay_ms: u64, max_bytes: usize) -> (Scheduler, Arc<ScheduleLimits>) {
+        let limits = Arc::new(ScheduleLimits::new(
+            Duration::from_millis(max_delay_ms),
+            max_bytes,
+        ));
+        (Scheduler::new(limits.clone()), limits)
+    }
+
+    fn message(data: &str, header: &str, value: u64) -> Message {
+        let headers = HashMap::from([(header.to_owned(), value.to_string())]);
+        Message::with_headers("topic", headers, data.as_bytes().to_vec())
+    }
+
+    fn wait_for_due(scheduler: &Scheduler) {
+        if let Some(due) = scheduler.next_due() {
+            thread::sleep(due + Duration::from_millis(5));
+        }
+    }
+
+    #[test]
+    fn messages_are_delivered_in_order() {
+        let (mut scheduler, _) = scheduler(60_000, 1024);
+        let deliver_at = Scheduler::now() + 40;
+        for (data, deliver_at) in [("c", deliver_at), ("a", deliver_at - 20), ("d", deliver_at)] {
+            let message = message(data, DELIVER_AT_HEADER, deliver_at);
+            assert!(scheduler.schedule(message).unwrap().is_none());
+        }
+        let message = message("b", DELAY_HEADER, 30);
+        assert!(scheduler.schedule(message).unwrap().is_none());
+        assert!(scheduler.take_due().is_empty());
+
+        thread::sleep(Duration::from_millis(50));
+        let messages = scheduler.take_due();
+        let data: Vec<&[u8]> = messages.iter().map(|message| &message.data[..]).collect();
+        assert_eq!(vec![&b"a"[..], b"b", b"c", b"d"], data);
+
+        // The scheduling headers are consumed.
+        assert!(messages.iter().all(|message| message.headers.is_empty()));
+        assert_eq!(None, scheduler.next_due());
+    }
+
+    #[test]
+    fn messages_that_are_due_pass_through() {
+        let (mut scheduler, _) = scheduler(60_000, 1024);
+        let message = message("a", DELIVER_AT_HEADER, Scheduler::now() - 1000);
+        let message = scheduler.schedule(message).unwrap().unwrap();
+        assert!(message.headers.is_empty());
+        assert!(message.schedule_reservation.is_none());
+    }
+
+    #[test]
+    fn messages_beyond_the_maximum_delay_are_rejected() {
+        let (mut scheduler, _) = scheduler(1000, 1024);
+        assert!(matches!(
+            scheduler.schedule(message("a", DELAY_HEADER, 2000)),
+            Err(Error::ScheduleRejected(_))
+        ));
+        assert!(matches!(
+            scheduler.schedule(message("a", DELIVER_AT_HEADER, Scheduler::now() + 2000)),
+            Err(Error::ScheduleRejected(_))
+        ));
+        assert!(scheduler
+            .schedule(message("a", DELAY_HEADER, 500))
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn malformed_schedules_are_rejected() {
+        let (mut scheduler, _) = scheduler(1000, 1024);
+        let headers = HashMap::from([(DELAY_HEADER.to_owned(), "soon".to_owned())]);
+        let message = Message::with_headers("topic", headers, Vec::new());
+        assert!(matches!(
+            scheduler.schedule(message),
+            Err(Error::MalformedSchedule(_))
+        ));
+    }
+
+    #[test]
+    fn budget_is_released_when_messages_fire() {
+        let (mut scheduler, _) = scheduler(60_000, 10);
+        assert!(scheduler
+            .schedule(message("12345678", DELAY_HEADER, 20))
+            .unwrap()
+            .is_none());
+        assert!(matches!(
+            scheduler.schedule(message("12345678", DELAY_HEADER, 20)),
+            Err(Error::ScheduleRejected(_))
+        ));
+
+        wait_for_due(&scheduler);
+        let messages = scheduler.take_due();
+        assert_eq!(1, messages.len());
+        assert!(messages[0].schedule_reservation.is_none());
+
+        assert!(scheduler
+            .schedule(message("12345678", DELAY_HEADER, 20))
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn budget_is_released_when_messages_are_dropped() {
+        let (_, limits) = scheduler(60_000, 10);
+        let mut first = message("12345678", DELAY_HEADER, 1000);
+        limits.reserve(&mut first).unwrap();
+
+        // Copies share the reservation, which is released with the last one.
+        let copy = first.clone();
+        drop(first);
+        let mut second = message("12345678", DELAY_HEADER, 1000);
+        assert!(matches!(
+            limits.reserve(&mut second),
+            Err(Error::ScheduleRejected(_))
+        ));
+
+        drop(copy);
+        limits.reserve(&mut second).unwrap();
+        assert!(second.schedule_reservation.is_some());
+    }
+
+    #[test]
+    fn reserved_messages_are_not_charged_twice() {
+        let (mut scheduler, limits) = scheduler(60_000, 10);
+        let mut message = message("12345678", DELAY_HEADER, 1000);
+        limits.reserve(&mut message).unwrap();
+
+        assert!(scheduler.schedule(message).unwrap().is_none());
+        assert_eq!(8, limits.scheduled_bytes.load(atomic::Ordering::Relaxed));
+    }
+}
@@ -0,0 +1,68 @@
+use std::net::{Shutdown, TcpStream};
+use std::sync::Arc;
+
+use crossbeam::channel::{self, Receiver, RecvError, Sender, TryRecvError};
+use polling::Poller;
+
+// Creates the two ends of a thread's shutdown signal. Nothing is ever sent
+// through the channel, requesting the shutdown closes it and wakes the poller
+// the thread waits on, so that the thread stops right away.
+pub fn signal(poller: Arc<Poller>) -> (ShutdownSender, ShutdownReceiver) {
+    let (sender, receiver): (Sender<()>, Receiver<()>) = channel::bounded(0);
+    (
+        ShutdownSender {
+            sender: Some(sender),
+            poller,
+            stream: None,
+        },
+        ShutdownReceiver { receiver },
+    )
+}
+
+pub struct ShutdownSender {
+    sender: Option<Sender<()>>,
+    poller: Arc<Poller>,
+    stream: Option<TcpStream>,
+}
+
+impl ShutdownSender {
+    // Also shuts the stream down when the shutdown is requested, waking the
+    // thread up if it's blocked reading or writing the rest of a frame.
+    pub fn with_stream(mut self, stream: TcpStream) -> Self {
+        self.stream = Some(stream);
+        self
+    }
+
+    pub fn request(&mut self) {
+        self.sender.take();
+        if let Err(e) = self.poller.notify() {
+            log::error!("Failed waking a thread to shut it down: [{}]", e);
+        }
+        if let Some(stream) = self.stream.take() {
+            // The peer may have closed the connection already.
+            if let Err(e) = stream.shutdown(Shutdown::Both) {
+                log::debug!("Failed shutting a stream down: [{}]", e);
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct ShutdownReceiver {
+    receiver: Receiver<()>,
+}
+
+impl ShutdownReceiver {
+    pub fn is_requested(&self) -> bool {
+        matches!(self.receiver.try_recv(), Err(TryRecvError::Disconnected))
+    }
+
+    // Waits for a reply, giving up if the shutdown is requested, since the
+    // reply may never be sent once the PubSub stopped handling events.
+    pub fn recv<T>(&self, reply_receiver: &Receiver<T>) -> Result<T, RecvError> {
+        channel::select! {
+            recv(reply_receiver) -> reply => reply,
+            recv(self.receiver) -> _ => Err(RecvError),
+        }
+    }
+}
@@ -0,0 +1,226 @@
+use std::io::{BufRead, Read, Write};
+
+use crate::error::{self, Error};
+use crate::message::MAX_FRAME_SIZE;
+
+const MAX_HEADERS_NUMBER: usize = 100;
+const MAX_LINE_SIZE: usize = 8 * 1024;
+
+#[derive(Debug)]
+pub struct StompFrame {
+    pub command: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl StompFrame {
+    pub fn new(command: &str) -> Self {
+        Self {
+            command: command.to_owned(),
+            headers: Vec::new(),
+            body: Vec::new(),
+        }
+    }
+
+    pub fn with_header(mut self, key: &str, value: &str) -> Self {
+        self.headers.push((key.to_owned(), value.to_owned()));
+        self
+    }
+
+    pub fn header(&self, key: &str) -> Option<&str> {
+        // Repeated headers are allowed, only the first one is significant.
+        self.headers
+            .iter()
+            .find(|(header_key, _)| header_key == key)
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn read(reader: &mut impl BufRead) -> error::Result<Option<Self>> {
+        // Read the command, skipping heart-beat EOLs between frames and
+        // stopping if the peer closed the connection.
+        let command = loop {
+            match Self::read_line(reader)? {
+                Some(line) if line.is_empty() => continue,
+                Some(line) => break line,
+                None => return Ok(None),
+            }
+        };
+
+        // CONNECT frames predate header escaping and are read verbatim.
+        let escaped = "CONNECT" != command;
+
+        // Read the headers.
+        let mut headers: Vec<(String, String)> = Vec::new();
+        loop {
+            let line = Self::read_line(reader)?
+                .ok_or_else(|| Error::MalformedStompFrame("truncated headers".to_owned()))?;
+
+            if line.is_empty() {
+                break;
+            }
+
+            if headers.len() == MAX_HEADERS_NUMBER {
+                return Err(Error::MalformedStompFrame("too many headers".to_owned()));
+            }
+
+            let (key, value) = line
+                .split_once(':')
+                .ok_or_else(|| Error::MalformedStompFrame(line.clone()))?;
+            if escaped {
+                headers.push((Self::unescape(key)?, Self::unescape(value)?));
+            } else {
+                headers.push((key.to_owned(), value.to_owned()));
+            }
+        }
+
+        let mut frame = Self {
+            command,
+            headers,
+            body: Vec::new(),
+        };
+
+        // Read the body, which is terminated by a NULL octet.
+        match frame.header("content-length") {
+            Some(content_length) => {
+                let content_length: usize = content_length.parse().map_err(|_| {
+                    Error::MalformedStompFrame(format!(
+                        "invalid content-length: {}",
+                        content_length
+                    ))
+                })?;
+
+                if content_length > MAX_FRAME_SIZE {
+                    return Err(Error::FrameTooLarge(format!(
+                        "content-length {} exceeds {} bytes",
+                        content_length, MAX_FRAME_SIZE
+                    )));
+                }
+
+                // Read through a limit rather than allocating the announced
+                // length up front.
+                let expected = content_length as u64 + 1;
+                if reader.take(expected).read_to_end(&mut frame.body)? as u64 != expected {
+                    return Err(Error::MalformedStompFrame("truncated body".to_owned()));
+                }
+                if Some(0) != frame.body.pop() {
+                    return Err(Error::MalformedStompFrame(
+                        "body is not terminated by NULL".to_owned(),
+                    ));
+                }
+            }
+            None => {
+                reader
+                    .take(MAX_FRAME_SIZE as u64 + 1)
+                    .read_until(0, &mut frame.body)?;
+                if frame.body.len() > MAX_FRAME_SIZE {
+                    return Err(Error::FrameTooLarge(format!(
+                        "body exceeds {} bytes",
+                        MAX_FRAME_SIZE
+                    )));
+                }
+                if Some(0) != frame.body.pop() {
+                    return Err(Error::MalformedStompFrame("truncated body".to_owned()));
+                }
+            }
+        }
+
+        Ok(Some(frame))
+    }
+
+    pub fn write(&self, writer: &mut impl Write) -> error::Result<()> {
+        // CONNECTED frames predate header escaping and are written verbatim.
+        let escaped = "CONNECTED" != self.command;
+
+        let mut frame: Vec<u8> = Vec::new();
+        frame.extend_from_slice(self.command.as_bytes());
+        frame.push(b'\n');
+
+        for (key, value) in self.headers.iter() {
+            if escaped {
+                frame.extend_from_slice(Self::escape(key).as_bytes());
+                frame.push(b':');
+                frame.extend_from_slice(Self::escape(value).as_bytes());
+            } else {
+                frame.extend_from_slice(key.as_bytes());
+                frame.push(b':');
+                frame.extend_from_slice(value.as_bytes());
+            }
+            frame.push(b'\n');
+        }
+
+        frame.push(b'\n');
+        frame.extend_from_slice(&self.body);
+        frame.push(0);
+
+        writer.write_all(&frame)?;
+
+        Ok(())
+    }
+
+    fn read_line(reader: &mut impl BufRead) -> error::Result<Option<String>> {
+        let mut line: Vec<u8> = Vec::new();
+        if 0 == reader
+            .take(MAX_LINE_SIZE as u64 + 1)
+            .read_until(b'\n', &mut line)?
+        {
+            return Ok(None);
+        }
+        if line.len() > MAX_LINE_SIZE {
+            return Err(Error::FrameTooLarge(format!(
+                "line exceeds {} bytes",
+                MAX_LINE_SIZE
+            )));
+        }
+
+        // Lines end with either LF or CRLF.
+        if line.ends_with(b"\n") {
+            line.pop();
+        }
+        if line.ends_with(b"\r") {
+            line.pop();
+        }
+
+        Ok(Some(String::from_utf8(line)?))
+    }
+
+    fn escape(string: &str) -> String {
+        let mut escaped = String::with_capacity(string.len());
+        for character in string.chars() {
+            match character {
+                '\r' => escaped.push_str("\\r"),
+                '\n' => escaped.push_str("\\n"),
+                ':' => escaped.push_str("\\c"),
+                '\\' => escaped.push_str("\\\\"),
+                character => escaped.push(character),
+            }
+        }
+
+        escaped
+    }
+
+    fn unescape(string: &str) -> error::Result<String> {
+        let mut unescaped = String::with_capacity(string.len());
+        let mut characters = string.chars();
+        while let Some(character) = characters.next() {
+            if '\\' != character {
+                unescaped.push(character);
+                continue;
+            }
+
+            match characters.next() {
+                Some('r') => unescaped.push('\r'),
+                Some('n') => unescaped.push('\n'),
+                Some('c') => unescaped.push(':'),
+                Some('\\') => unescaped.push('\\'),
+                _ => {
+                    return Err(Error::MalformedStompFrame(format!(
+                        "undefined escape sequence in: {}",
+                        string
+                    )))
+                }
+            }
+        }
+
+        Ok(unescaped)
+    }
+}
@@ -0,0 +1,727 @@
+use std::collections::HashMap;
+use std::io::{BufReader, Write};
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crossbeam::channel::{self, Receiver, Sender};
+use polling::Poller;
+use uuid::Uuid;
+
+use crate::consumer_group::GROUP_HEADER;
+use crate::dead_letter::{DeadLetterReason, DeadLetters};
+use crate::error::{self, Error};
+use crate::event::Event;
+use crate::message::Message;
+use crate::publish_gate::{self, PublishControl, PublishGate};
+use crate::shutdown::{self, ShutdownReceiver, ShutdownSender};
+use crate::stomp_frame::StompFrame;
+use crate::subscriber::Subscriber;
+use crate::topic_filter::TopicFilter;
+
+const STOMP_STREAM_POLL_KEY: usize = 0;
+
+const PROTOCOL_VERSION: &str = "1.2";
+
+// The number of messages a subscription may have awaiting acknowledgement,
+// further messages are held until the client acknowledges some.
+const MAX_UNACKED_MESSAGES: usize = 1000;
+
+// Destinations under these prefixes are broadcast to every subscriber or
+// load-balanced between subscribers, other destinations are broadcast.
+const TOPIC_PREFIX: &str = "/topic/";
+const QUEUE_PREFIX: &str = "/queue/";
+
+// STOMP headers that describe the frame rather than the published message.
+const FRAME_HEADERS: [&str; 8] = [
+    "ack",
+    "content-length",
+    "destination",
+    "message-id",
+    "receipt",
+    "subscription",
+    "transaction",
+    "id",
+];
+
+pub struct StompHandler {
+    message_sender: Sender<Message>,
+    poller: Arc<Poller>,
+    handler_thread: Option<JoinHandle<()>>,
+    shutdown: ShutdownSender,
+}
+
+impl StompHandler {
+    pub fn new(
+        id: Uuid,
+        stream: TcpStream,
+        event_sender: Sender<Event>,
+        publish_control: PublishControl,
+        dead_letters: Arc<DeadLetters>,
+    ) -> error::Result<Self> {
+        let (message_sender, message_receiver): (Sender<Message>, Receiver<Message>) =
+            channel::unbounded();
+
+        let poller = Arc::new(Poller::new()?);
+        poller.add(&stream, polling::Event::readable(STOMP_STREAM_POLL_KEY))?;
+        let gate = publish_control.gate(
+            stream.peer_addr()?.ip().to_string(),
+            publish_gate::poller_waker(poller.clone()),
+        );
+
+        let (shutdown, shutdown_receiver) = shutdown::signal(poller.clone());
+        let shutdown = shutdown.with_stream(stream.try_clone()?);
+
+        Ok(Self {
+            message_sender,
+            poller: poller.clone(),
+            handler_thread: Some(Self::start_handler_thread(
+                StompSession::new(
+                    id,
+                    stream,
+                    event_sender,
+                    shutdown_receiver.clone(),
+                    dead_letters,
+                    gate,
+                ),
+                message_receiver,
+                poller,
+                shutdown_receiver,
+            )),
+            shutdown,
+        })
+    }
+
+    fn start_handler_thread(
+        session: StompSession,
+        message_receiver: Receiver<Message>,
+        poller: Arc<Poller>,
+        shutdown: ShutdownReceiver,
+    ) -> JoinHandle<()> {
+        thread::spawn(move || {
+            let id = session.id;
+            let event_sender = session.event_sender.clone();
+
+            let mut session = Self::handle_client(session, message_receiver, poller, shutdown);
+
+            // Messages the client didn't acknowledge won't be acknowledged.
+            session.dead_letter_unacknowledged();
+
+            // Let the PubSub forget about this client.
+            if let Err(e) = event_sender.send(Event::Disconnection(id)) {
+                log::error!("Failed sending Disconnection event for [{}]: [{}]", id, e);
+            }
+        })
+    }
+
+    fn handle_client(
+        mut session: StompSession,
+        message_receiver: Receiver<Message>,
+        poller: Arc<Poller>,
+        shutdown: ShutdownReceiver,
+    ) -> StompSession {
+        // Connections reset right after being accepted have no peer address.
+        let address = match session.stream.peer_addr() {
+            Ok(address) => address,
+            Err(e) => {
+                log::error!("Failed handling STOMP client [{}]: [{}]", session.id, e);
+                return session;
+            }
+        };
+        log::info!(
+            "Handling STOMP client: id=[{}], address=[{}]",
+            session.id,
+            address
+        );
+
+        let stream = session.stream.try_clone().unwrap();
+        let mut reader = BufReader::new(&stream);
+        let mut poll_events: Vec<polling::Event> = Vec::new();
+        while !shutdown.is_requested() {
+            // Stop reading frames while the client is held back by its
+            // publications.
+            let paused = session.gate.is_paused();
+            let interest = if paused {
+                polling::Event::none(STOMP_STREAM_POLL_KEY)
+            } else {
+                polling::Event::readable(STOMP_STREAM_POLL_KEY)
+            };
+
+            // Modify the poller's interest in the client's stream.
+            // This is required to receive multiple read events on macOS.
+            if let Err(e) = poller.modify(&session.stream, interest) {
+                log::error!(
+                    "Failed to modify the poller's interest in STOMP client [{}]: [{}]",
+                    session.id,
+                    e
+                );
+                break;
+            }
+
+            // Clear all previous poll events.
+            poll_events.clear();
+
+            // Wait for an I/O event, a published message or a shutdown
+            // request. Frames already buffered are handled without waiting.
+            let timeout = if reader.buffer().is_empty() || paused {
+                None
+            } else {
+                Some(Duration::ZERO)
+            };
+            if let Err(e) = poller.wait(&mut poll_events, session.gate.timeout(timeout)) {
+                log::error!("Failed polling for events from [{}]: [{}]", session.id, e);
+                continue;
+            }
+
+            // Forward published messages to the client.
+            if let Err(e) = session.write_messages(&message_receiver) {
+                log::error!("Error writing messages to [{}]: [{}]", session.id, e);
+                break;
+            }
+
+            // Check if the client sent anything.
+            if paused || (poll_events.is_empty() && reader.buffer().is_empty()) {
+                continue;
+            }
+
+            // Receive a frame from the client.
+            let frame = match StompFrame::read(&mut reader) {
+                Ok(Some(frame)) => frame,
+                Ok(None) => break,
+                Err(e) => {
+                    log::error!("Error receiving STOMP frame from [{}]: [{}]", session.id, e);
+                    let _ = session.write_error(&e.to_string(), None);
+                    break;
+                }
+            };
+
+            // Handle the frame.
+            match session.handle_frame(frame) {
+                Ok(true) => {}
+                Ok(false) => break,
+                Err(e) => {
+                    log::error!("Error handling STOMP frame from [{}]: [{}]", session.id, e);
+                    let _ = session.write_error(&e.to_string(), None);
+                    break;
+                }
+            }
+
+            // Forward the messages held until the client acknowledged some.
+            if let Err(e) = session.write_messages(&message_receiver) {
+                log::error!("Error writing messages to [{}]: [{}]", session.id, e);
+                break;
+            }
+        }
+
+        session
+    }
+}
+
+impl Subscriber for StompHandler {
+    fn publish(&self, message: Message) -> error::Result<()> {
+        self.message_sender.send(message)?;
+        self.poller.notify()?;
+        Ok(())
+    }
+
+    fn queue_len(&self) -> usize {
+        self.message_sender.len()
+    }
+}
+
+impl Drop for StompHandler {
+    fn drop(&mut self) {
+        if let Some(thread) = self.handler_thread.take() {
+            // Indicate the handler thread that it should terminate, waking
+            // it up.
+            self.shutdown.request();
+
+            // Join the handler thread.
+            thread.join().unwrap();
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum StompAckMode {
+    Auto,
+    Client,
+    ClientIndividual,
+}
+
+struct StompSubscription {
+    destination: String,
+    filter: TopicFilter,
+    group: Option<String>,
+    ack_mode: StompAckMode,
+}
+
+struct PendingAck {
+    ack_id: String,
+    subscription_id: String,
+    message: Message,
+}
+
+struct StompSession {
+    id: Uuid,
+    stream: TcpStream,
+    event_sender: Sender<Event>,
+    shutdown: ShutdownReceiver,
+    dead_letters: Arc<DeadLetters>,
+    connected: bool,
+    subscriptions: HashMap<String, StompSubscription>,
+    // Messages awaiting an ACK or NACK, in delivery order.
+    pending_acks: Vec<PendingAck>,
+    // A message, with its group, held while its subscriptions have too many
+    // messages awaiting acknowledgement.
+    held_message: Option<(Option<String>, Message)>,
+    next_message_id: u64,
+    gate: PublishGate,
+}
+
+impl StompSession {
+    fn new(
+        id: Uuid,
+        stream: TcpStream,
+        event_sender: Sender<Event>,
+        shutdown: ShutdownReceiver,
+        dead_letters: Arc<DeadLetters>,
+        gate: PublishGate,
+    ) -> Self {
+        Self {
+            id,
+            stream,
+            event_sender,
+            shutdown,
+            dead_letters,
+            connected: false,
+            subscriptions: HashMap::new(),
+            pending_acks: Vec::new(),
+            held_message: None,
+            next_message_id: 0,
+            gate,
+        }
+    }
+
+    // Returns whether the session should keep running.
+    fn handle_frame(&mut self, frame: StompFrame) -> error::Result<bool> {
+        log::debug!("STOMP frame from [{}]: [{}]", self.id, frame.command);
+
+        // The first frame must be a CONNECT, and it may only be sent once.
+        let connect = matches!(frame.command.as_str(), "CONNECT" | "STOMP");
+        if connect == self.connected {
+            return Err(Error::MalformedStompFrame(format!(
+                "unexpected frame: [{}]",
+                frame.command
+            )));
+        }
+
+        let keep_running = match frame.command.as_str() {
+            "CONNECT" | "STOMP" => self.handle_connect(&frame)?,
+            "SEND" => self.handle_send(&frame)?,
+            "SUBSCRIBE" => self.handle_subscribe(&frame)?,
+            "UNSUBSCRIBE" => self.handle_unsubscribe(&frame)?,
+            "ACK" => self.handle_ack(&frame, true)?,
+            "NACK" => self.handle_ack(&frame, false)?,
+            "DISCONNECT" => false,
+            "BEGIN" | "COMMIT" | "ABORT" => {
+                self.write_error("transactions are not supported", Some(&frame))?;
+                false
+            }
+            command => {
+                return Err(Error::MalformedStompFrame(format!(
+                    "unknown command: [{}]",
+                    command
+                )))
+            }
+        };
+
+        // Acknowledge the frame if the client asked for a receipt.
+        if let Some(receipt) = frame.header("receipt") {
+            StompFrame::new("RECEIPT")
+                .with_header("receipt-id", receipt)
+                .write(&mut self.stream)?;
+        }
+
+        Ok(keep_running)
+    }
+
+    fn handle_connect(&mut self, frame: &StompFrame) -> error::Result<bool> {
+        // Clients that don't declare their versions only speak STOMP 1.0.
+        let versions = frame.header("accept-version").unwrap_or("1.0");
+        if !versions
+            .split(',')
+            .any(|version| PROTOCOL_VERSION == version)
+        {
+            self.write_error(
+                &format!("Supported protocol versions are {}", PROTOCOL_VERSION),
+                Some(frame),
+            )?;
+            return Ok(false);
+        }
+
+        log::info!(
+            "STOMP CONNECT from [{}]: host=[{:?}], login=[{:?}]",
+            self.id,
+            frame.header("host"),
+            frame.header("login")
+        );
+
+        // Refuse principals that reached their connection limit.
+        if let Some(login) = frame.header("login") {
+            if !self.authenticate(login.to_owned())? {
+                self.write_error("server busy", Some(frame))?;
+                return Ok(false);
+            }
+            self.gate.authenticate(login);
+        }
+
+        self.connected = true;
+        StompFrame::new("CONNECTED")
+            .with_header("version", PROTOCOL_VERSION)
+            .with_header("heart-beat", "0,0")
+            .with_header("server", concat!("pubsub/", env!("CARGO_PKG_VERSION")))
+            .with_header("session", &self.id.to_string())
+            .write(&mut self.stream)?;
+
+        Ok(true)
+    }
+
+    fn authenticate(&self, principal: String) -> error::Result<bool> {
+        let (accepted_sender, accepted_receiver): (Sender<bool>, Receiver<bool>) =
+            channel::bounded(1);
+        self.event_sender
+            .send(Event::Authentication(self.id, principal, accepted_sender))?;
+
+        Ok(self.shutdown.recv(&accepted_receiver)?)
+    }
+
+    fn handle_send(&mut self, frame: &StompFrame) -> error::Result<bool> {
+        let destination = Self::required_header(frame, "destination")?;
+        let (topic, _) = Self::parse_destination(destination);
+
+        // Keep the headers describing the message itself.
+        let mut headers: HashMap<String, String> = HashMap::new();
+        for (key, value) in frame.headers.iter() {
+            if !FRAME_HEADERS.contains(&key.as_str()) && !key.starts_with('$') {
+                headers.entry(key.clone()).or_insert_with(|| value.clone());
+            }
+        }
+
+        let message = Message::with_headers(topic, headers, frame.body.clone());
+        match self.gate.pass_one(message) {
+            Ok(message) => self.event_sender.send(Event::Publish(message))?,
+            Err(e) => {
+                // STOMP errors end the session.
+                self.write_error(&e.to_string(), Some(frame))?;
+                return Ok(false);
+            }
+        }
+
+        Ok(true)
+    }
+
+    fn handle_subscribe(&mut self, frame: &StompFrame) -> error::Result<bool> {
+        let subscription_id = Self::required_header(frame, "id")?.to_owned();
+        let destination = Self::required_header(frame, "destination")?.to_owned();
+        let ack_mode = match frame.header("ack").unwrap_or("auto") {
+            "auto" => StompAckMode::Auto,
+            "client" => StompAckMode::Client,
+            "client-individual" => StompAckMode::ClientIndividual,
+            ack_mode => {
+                return Err(Error::MalformedStompFrame(format!(
+                    "invalid ack mode: [{}]",
+                    ack_mode
+                )))
+            }
+        };
+
+        if self.subscriptions.contains_key(&subscription_id) {
+            return Err(Error::MalformedStompFrame(format!(
+                "duplicate subscription id: [{}]",
+                subscription_id
+            )));
+        }
+
+        // Register the subscription, either directly or as a member of the
+        // queue's group.
+        let (topic, queue) = Self::parse_destination(&destination);
+        let filter = TopicFilter::Exact(topic);
+        let group = if queue {
+            Some(destination.clone())
+        } else {
+            None
+        };
+        match &group {
+            Some(group) => self.event_sender.send(Event::GroupSubscribe(
+                self.id,
+                group.clone(),
+                filter.clone(),
+            ))?,
+            None => self
+                .event_sender
+                .send(Event::Subscribe(self.id, vec![filter.clone()]))?,
+        }
+
+        self.subscriptions.insert(
+            subscription_id,
+            StompSubscription {
+                destination,
+                filter,
+                group,
+                ack_mode,
+            },
+        );
+
+        Ok(true)
+    }
+
+    fn handle_unsubscribe(&mut self, frame: &StompFrame) -> error::Result<bool> {
+        let subscription_id = Self::required_header(frame, "id")?;
+        let subscription = match self.subscriptions.remove(subscription_id) {
+            Some(subscription) => subscription,
+            None => {
+                return Err(Error::MalformedStompFrame(format!(
+                    "unknown subscription id: [{}]",
+                    subscription_id
+                )))
+            }
+        };
+
+        // Messages awaiting acknowledgement from the subscription won't be
+        // acknowledged.
+        let (unacknowledged, pending_acks) = std::mem::take(&mut self.pending_acks)
+            .into_iter()
+            .partition(|pending_ack| pending_ack.subscription_id == subscription_id);
+        self.pending_acks = pending_acks;
+        self.dead_letter(unacknowledged, DeadLetterReason::Unacknowledged);
+
+        // Keep the registration while other subscriptions depend on it.
+        let shared = self
+            .subscriptions
+            .values()
+            .any(|other| other.group == subscription.group && other.filter == subscription.filter);
+        if !shared {
+            match subscription.group {
+                Some(group) => self.event_sender.send(Event::GroupUnsubscribe(
+                    self.id,
+                    group,
+                    subscription.filter,
+                ))?,
+                None => self
+                    .event_sender
+                    .send(Event::Unsubscribe(self.id, vec![subscription.filter]))?,
+            }
+        }
+
+        Ok(true)
+    }
+
+    fn handle_ack(&mut self, frame: &StompFrame, positive: bool) -> error::Result<bool> {
+        let ack_id = Self::required_header(frame, "id")?;
+        let position = match self
+            .pending_acks
+            .iter()
+            .position(|pending_ack| pending_ack.ack_id == ack_id)
+        {
+            Some(position) => position,
+            None => {
+                return Err(Error::MalformedStompFrame(format!(
+                    "unknown ack id: [{}]",
+                    ack_id
+                )))
+            }
+        };
+
+        // In client mode, acknowledging a message also acknowledges all
+        // earlier messages of the same subscription.
+        let subscription_id = self.pending_acks[position].subscription_id.clone();
+        let cumulative = self
+            .subscriptions
+            .get(&subscription_id)
+            .is_some_and(|subscription| StompAckMode::Client == subscription.ack_mode);
+
+        let mut acknowledged: Vec<PendingAck> = Vec::new();
+        let mut pending_acks: Vec<PendingAck> = Vec::new();
+        for (index, pending_ack) in std::mem::take(&mut self.pending_acks)
+            .into_iter()
+            .enumerate()
+        {
+            let matching = if cumulative {
+                index <= position && pending_ack.subscription_id == subscription_id
+            } else {
+                index == position
+            };
+
+            if matching {
+                acknowledged.push(pending_ack);
+            } else {
+                pending_acks.push(pending_ack);
+            }
+        }
+        self.pending_acks = pending_acks;
+
+        // Rejected messages are dead-lettered rather than redelivered to the
+        // client that just rejected them.
+        if !positive {
+            log::warn!(
+                "STOMP client [{}] rejected [{}] messages of subscription: [{}]",
+                self.id,
+                acknowledged.len(),
+                subscription_id
+            );
+            self.dead_letter(acknowledged, DeadLetterReason::Rejected);
+        }
+
+        Ok(true)
+    }
+
+    fn dead_letter_unacknowledged(&mut self) {
+        let unacknowledged = std::mem::take(&mut self.pending_acks);
+        if !unacknowledged.is_empty() {
+            log::warn!(
+                "STOMP client [{}] left [{}] messages unacknowledged",
+                self.id,
+                unacknowledged.len()
+            );
+        }
+        self.dead_letter(unacknowledged, DeadLetterReason::Unacknowledged);
+
+        if let Some((_, message)) = self.held_message.take() {
+            self.dead_letters
+                .route(message, DeadLetterReason::Unacknowledged);
+        }
+    }
+
+    fn dead_letter(&self, pending_acks: Vec<PendingAck>, reason: DeadLetterReason) {
+        for pending_ack in pending_acks {
+            self.dead_letters.route(pending_ack.message, reason);
+        }
+    }
+
+    fn write_messages(&mut self, message_receiver: &Receiver<Message>) -> error::Result<()> {
+        loop {
+            let (group, message) = match self.held_message.take() {
+                Some(held_message) => held_message,
+                None => match message_receiver.try_recv() {
+                    Ok(mut message) => (message.headers_mut().remove(GROUP_HEADER), message),
+                    Err(_) => break,
+                },
+            };
+
+            // Copies sent on behalf of a queue go to a single subscription of
+            // that queue, other copies go to all topic subscriptions.
+            let subscription_ids: Vec<String> = self
+                .subscriptions
+                .iter()
+                .filter(|(_, subscription)| {
+                    subscription.group == group && subscription.filter.matches(&message.topic)
+                })
+                .map(|(subscription_id, _)| subscription_id.clone())
+                .collect();
+            let subscription_ids: Vec<String> = if group.is_some() {
+                // Prefer a subscription of the queue that can take the message.
+                subscription_ids
+                    .iter()
+                    .find(|subscription_id| self.can_deliver(subscription_id))
+                    .or(subscription_ids.first())
+                    .into_iter()
+                    .cloned()
+                    .collect()
+            } else {
+                subscription_ids
+            };
+
+            // Hold the message until the client acknowledges earlier ones.
+            if !subscription_ids
+                .iter()
+                .all(|subscription_id| self.can_deliver(subscription_id))
+            {
+                self.held_message = Some((group, message));
+                break;
+            }
+
+            for subscription_id in subscription_ids.iter() {
+                self.write_message(subscription_id, &message)?;
+            }
+        }
+
+        self.stream.flush()?;
+
+        Ok(())
+    }
+
+    fn can_deliver(&self, subscription_id: &str) -> bool {
+        StompAckMode::Auto == self.subscriptions[subscription_id].ack_mode
+            || self
+                .pending_acks
+                .iter()
+                .filter(|pending_ack| pending_ack.subscription_id == subscription_id)
+                .count()
+                < MAX_UNACKED_MESSAGES
+    }
+
+    fn write_message(&mut self, subscription_id: &str, message: &Message) -> error::Result<()> {
+        let subscription = &self.subscriptions[subscription_id];
+
+        let message_id = self.next_message_id.to_string();
+        self.next_message_id += 1;
+
+        let mut frame = StompFrame::new("MESSAGE")
+            .with_header("subscription", subscription_id)
+            .with_header("message-id", &message_id)
+            .with_header("destination", &subscription.destination)
+            .with_header("content-length", &message.data.len().to_string());
+
+        // Track messages that must be acknowledged by the client.
+        if StompAckMode::Auto != subscription.ack_mode {
+            frame = frame.with_header("ack", &message_id);
+            self.pending_acks.push(PendingAck {
+                ack_id: message_id.clone(),
+                subscription_id: subscription_id.to_owned(),
+                message: message.clone(),
+            });
+        }
+
+        for (key, value) in message.headers.iter() {
+            if !FRAME_HEADERS.contains(&key.as_str()) && !key.starts_with('$') {
+                frame = frame.with_header(key, value);
+            }
+        }
+
+        frame.body = message.data.to_vec();
+        frame.write(&mut self.stream)?;
+
+        Ok(())
+    }
+
+    fn write_error(&mut self, error: &str, frame: Option<&StompFrame>) -> error::Result<()> {
+        let mut error_frame = StompFrame::new("ERROR").with_header("message", error);
+        if let Some(receipt) = frame.and_then(|frame| frame.header("receipt")) {
+            error_frame = error_frame.with_header("receipt-id", receipt);
+        }
+
+        error_frame.write(&mut self.stream)
+    }
+
+    fn required_header<'a>(frame: &'a StompFrame, key: &str) -> error::Result<&'a str> {
+        frame.header(key).ok_or_else(|| {
+            Error::MalformedStompFrame(format!(
+                "{} frame is missing the [{}] header",
+                frame.command, key
+            ))
+        })
+    }
+
+    // Returns the topic a destination maps to and whether it is a queue.
+    fn parse_destination(destination: &str) -> (String, bool) {
+        if let Some(topic) = destination.strip_prefix(TOPIC_PREFIX) {
+            (topic.to_owned(), false)
+        } else if let Some(topic) = destination.strip_prefix(QUEUE_PREFIX) {
+            (topic.to_owned(), true)
+        } else {
+            (destination.to_owned(), false)
+        }
+    }
+}
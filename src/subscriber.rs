@@ -0,0 +1,28 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use uuid::Uuid;
+
+use crate::error;
+use crate::message::Message;
+
+// The handlers of the connected subscribers, shared with the router shards.
+pub type SubscriberHandlers = Arc<RwLock<HashMap<Uuid, Box<dyn Subscriber>>>>;
+
+pub trait Subscriber: Send + Sync {
+    fn publish(&self, message: Message) -> error::Result<()>;
+
+    // Publishes the messages of a batch in order.
+    fn publish_batch(&self, messages: Vec<Message>) -> error::Result<()> {
+        for message in messages.into_iter() {
+            self.publish(message)?;
+        }
+        Ok(())
+    }
+
+    // The number of published messages not yet written to the subscriber.
+    // Subscribers that can't tell report an empty queue.
+    fn queue_len(&self) -> usize {
+        0
+    }
+}
@@ -1,89 +1,168 @@
 use std::net::TcpStream;
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
 use std::thread::{self, JoinHandle};
 use std::time::Duration;
 
-use crossbeam::channel::{self, Receiver, Sender};
+use bytes::Bytes;
+use crossbeam::channel::Sender;
+use polling::Poller;
 use uuid::Uuid;
 
+use crate::checksum::ChecksumPolicy;
+use crate::compression::Compression;
+use crate::credit::{Credit, CREDIT_TOPIC};
 use crate::error;
 use crate::event::Event;
-use crate::message::Message;
+use crate::expiry::Expiry;
+use crate::handshake::PendingHandshake;
+use crate::heartbeat::Heartbeat;
+use crate::message::{self, Message};
+use crate::metrics::Metrics;
+use crate::priority::{self, DrainPolicy, LaneReceiver, LaneSender};
+use crate::shutdown::{self, ShutdownReceiver, ShutdownSender};
+use crate::subscriber::Subscriber;
 use crate::subscription_request::SubscriptionRequest;
 
-const RECEIVE_MESSAGE_TIMEOUT_MS: u64 = 300;
+const SUBSCRIBER_STREAM_POLL_KEY: usize = 0;
+const POLL_TIMEOUT_MS: u64 = 300;
+// Queued messages are written in vectored writes of up to about this size.
+const WRITE_BUFFER_SIZE: usize = 64 * 1024;
 
 pub struct SubscriberHandler {
-    message_sender: Sender<Message>,
+    message_sender: LaneSender,
+    poller: Arc<Poller>,
     handler_thread: Option<JoinHandle<()>>,
-    terminate: Arc<Mutex<bool>>,
+    shutdown: ShutdownSender,
+}
+
+struct SubscriberSession {
+    id: Uuid,
+    stream: TcpStream,
+    event_sender: Sender<Event>,
+    heartbeat: Heartbeat,
+    credit: Credit,
+    compression: Compression,
+    frame_checksums: bool,
+    checksum_policy: ChecksumPolicy,
+    metrics: Arc<Metrics>,
+    // A message waiting for the subscriber to grant enough credit.
+    held_message: Option<Message>,
 }
 
 impl SubscriberHandler {
-    pub fn new(id: Uuid, stream: TcpStream, event_sender: Sender<Event>) -> Self {
-        let (message_sender, message_receiver): (Sender<Message>, Receiver<Message>) =
-            channel::unbounded();
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        id: Uuid,
+        stream: TcpStream,
+        event_sender: Sender<Event>,
+        pending_handshake: PendingHandshake,
+        heartbeat: Heartbeat,
+        metrics: Arc<Metrics>,
+        drain_policy: DrainPolicy,
+        compression: Compression,
+        frame_checksums: bool,
+    ) -> error::Result<Self> {
+        // Urgent messages overtake the queued bulk messages.
+        let (message_sender, message_receiver) = priority::lanes(drain_policy);
+
+        let poller = Arc::new(Poller::new()?);
+        poller.add(
+            &stream,
+            polling::Event::readable(SUBSCRIBER_STREAM_POLL_KEY),
+        )?;
 
-        let terminate = Arc::new(Mutex::new(false));
+        let (shutdown, shutdown_receiver) = shutdown::signal(poller.clone());
+        let shutdown = shutdown.with_stream(stream.try_clone()?);
 
-        Self {
+        Ok(Self {
             message_sender,
+            poller: poller.clone(),
             handler_thread: Some(Self::start_handler_thread(
-                id,
-                stream,
-                event_sender,
+                SubscriberSession {
+                    id,
+                    stream,
+                    event_sender,
+                    heartbeat,
+                    credit: Credit::default(),
+                    compression,
+                    frame_checksums,
+                    checksum_policy: ChecksumPolicy::default(),
+                    metrics,
+                    held_message: None,
+                },
                 message_receiver,
-                terminate.clone(),
+                pending_handshake,
+                poller,
+                shutdown_receiver,
             )),
-            terminate,
-        }
-    }
-
-    pub fn publish(&self, message: Message) -> error::Result<()> {
-        self.message_sender.send(message)?;
-        Ok(())
+            shutdown,
+        })
     }
 
     fn start_handler_thread(
-        id: Uuid,
-        stream: TcpStream,
-        event_sender: Sender<Event>,
-        message_receiver: Receiver<Message>,
-        terminate: Arc<Mutex<bool>>,
+        session: SubscriberSession,
+        message_receiver: LaneReceiver,
+        pending_handshake: PendingHandshake,
+        poller: Arc<Poller>,
+        shutdown: ShutdownReceiver,
     ) -> JoinHandle<()> {
         thread::spawn(move || {
-            Self::handle_subscriber(id, stream, event_sender, message_receiver, terminate)
+            let id = session.id;
+            let event_sender = session.event_sender.clone();
+
+            Self::handle_subscriber(
+                session,
+                message_receiver,
+                pending_handshake,
+                poller,
+                shutdown,
+            );
+
+            // Let the PubSub forget about this subscriber.
+            if let Err(e) = event_sender.send(Event::Disconnection(id)) {
+                log::error!("Failed sending Disconnection event for [{}]: [{}]", id, e);
+            }
         })
     }
 
     fn handle_subscriber(
-        id: Uuid,
-        mut stream: TcpStream,
-        event_sender: Sender<Event>,
-        message_receiver: Receiver<Message>,
-        terminate: Arc<Mutex<bool>>,
+        mut session: SubscriberSession,
+        mut message_receiver: LaneReceiver,
+        pending_handshake: PendingHandshake,
+        poller: Arc<Poller>,
+        shutdown: ShutdownReceiver,
     ) {
-        log::info!(
-            "Handling subscriber: id=[{}], address=[{}]",
-            id,
-            stream.peer_addr().unwrap()
-        );
-
-        // Receive the subscriber's subscription request.
-        let subscription_request = match SubscriptionRequest::read(&mut stream) {
-            Ok(request) => request,
+        let id = session.id;
+        // Connections reset right after being accepted have no peer address.
+        let address = match session.stream.peer_addr() {
+            Ok(address) => address,
             Err(e) => {
-                log::error!(
-                    "Failed receiving subscription request from [{}]: [{}]",
-                    id,
-                    e,
-                );
+                log::error!("Failed handling subscriber [{}]: [{}]", id, e);
                 return;
             }
         };
+        log::info!("Handling subscriber: id=[{}], address=[{}]", id, address);
+
+        // Receive the subscriber's subscription request, dropping clients
+        // that don't send it in time.
+        let subscription_request =
+            match SubscriptionRequest::read(&mut pending_handshake.reader(&session.stream)) {
+                Ok(request) => request,
+                Err(e) => {
+                    log::error!(
+                        "Failed receiving subscription request from [{}]: [{}]",
+                        id,
+                        e,
+                    );
+                    return;
+                }
+            };
 
         // Send a SubscriptionRequest event.
-        if let Err(e) = event_sender.send(Event::SubscriptionRequest(id, subscription_request)) {
+        if let Err(e) = session
+            .event_sender
+            .send(Event::SubscriptionRequest(id, subscription_request))
+        {
             log::error!(
                 "Failed sending SubscriptionRequest event from [{}]: [{}]",
                 id,
@@ -91,34 +170,208 @@ impl SubscriberHandler {
             );
             return;
         }
+        drop(pending_handshake);
 
-        // Send incoming messages to the subscriber.
+        // Send incoming messages to the subscriber, answering its heartbeats.
         log::info!("Publishing incoming messages to: [{}]", id);
-        while !(*terminate.lock().unwrap()) {
-            // Receive a message from the messages channel.
-            let message = match message_receiver
-                .recv_timeout(Duration::from_millis(RECEIVE_MESSAGE_TIMEOUT_MS))
-            {
+        let mut poll_events: Vec<polling::Event> = Vec::new();
+        while !shutdown.is_requested() {
+            // Modify the poller's interest in the subscriber's stream.
+            // This is required to receive multiple read events on macOS.
+            if let Err(e) = poller.modify(
+                &session.stream,
+                polling::Event::readable(SUBSCRIBER_STREAM_POLL_KEY),
+            ) {
+                log::error!(
+                    "Failed to modify the poller's interest in subscriber stream [{}]: [{}]",
+                    id,
+                    e
+                );
+                break;
+            }
+
+            // Wait for the subscriber to send a frame, for a message to be
+            // published, for a shutdown request or for a timeout.
+            poll_events.clear();
+            if let Err(e) = poller.wait(
+                &mut poll_events,
+                Some(Duration::from_millis(POLL_TIMEOUT_MS)),
+            ) {
+                log::error!("Failed polling for events from [{}]: [{}]", id, e);
+                continue;
+            }
+
+            // Send the published messages to the subscriber.
+            if let Err(e) = Self::write_messages(&mut session, &mut message_receiver) {
+                log::error!("Error writing message to [{}]: [{}]", id, e);
+                break;
+            }
+
+            // Disconnect subscribers that stopped sending heartbeats.
+            if poll_events.is_empty() {
+                if session.heartbeat.is_expired() {
+                    log::warn!("Subscriber [{}] missed too many heartbeats", id);
+                    break;
+                }
+
+                continue;
+            }
+
+            // Receive a frame from the subscriber, who may only send
+            // heartbeat, credit, compression and checksums frames after its
+            // subscription request.
+            let message = match session.checksum_policy.read(&mut session.stream) {
                 Ok(message) => message,
-                Err(_) => {
-                    continue;
+                Err(e) => {
+                    log::error!("Error receiving frame from [{}]: [{}]", id, e);
+                    break;
                 }
             };
+            session.heartbeat.received();
 
-            // Send the message to the subscriber.
-            if let Err(e) = message.write(&mut stream) {
-                log::error!("Error writing message to [{}]: [{}]", id, e);
+            // Write the messages that the granted credit allows right away.
+            if CREDIT_TOPIC == &*message.topic {
+                if let Err(e) = session.credit.grant(&message) {
+                    log::error!("Error handling credit from [{}]: [{}]", id, e);
+                    break;
+                }
+                if let Err(e) = Self::write_messages(&mut session, &mut message_receiver) {
+                    log::error!("Error writing message to [{}]: [{}]", id, e);
+                    break;
+                }
+                continue;
+            }
+
+            match session
+                .compression
+                .handle_control_message(&message, &mut session.stream)
+            {
+                Ok(true) => continue,
+                Ok(false) => {}
+                Err(e) => {
+                    log::error!("Error handling compression from [{}]: [{}]", id, e);
+                    break;
+                }
+            }
+
+            match session
+                .checksum_policy
+                .handle_control_message(&message, &mut session.stream)
+            {
+                Ok(true) => continue,
+                Ok(false) => {}
+                Err(e) => {
+                    log::error!("Error handling checksums from [{}]: [{}]", id, e);
+                    break;
+                }
+            }
+
+            match session
+                .heartbeat
+                .handle_control_message(&message, &mut session.stream)
+            {
+                Ok(true) => {}
+                Ok(false) => log::warn!(
+                    "Ignoring message to topic [{}] from subscriber [{}]",
+                    message.topic,
+                    id
+                ),
+                Err(e) => {
+                    log::error!("Error handling heartbeat from [{}]: [{}]", id, e);
+                    break;
+                }
+            }
+        }
+    }
+
+    fn write_messages(
+        session: &mut SubscriberSession,
+        message_receiver: &mut LaneReceiver,
+    ) -> error::Result<()> {
+        // Coalesce the queued messages into as few vectored writes as
+        // possible, pointing at their shared data rather than copying it, and
+        // leave them queued while the subscriber lacks credit for them.
+        let mut chunks: Vec<Bytes> = Vec::new();
+        let mut chunks_size: usize = 0;
+        loop {
+            let mut message = match session.held_message.take() {
+                Some(message) => message,
+                None => match message_receiver.try_recv() {
+                    Some(message) => message,
+                    None => break,
+                },
+            };
+
+            // Discard the copies that expired while queued. The router
+            // dead-letters the messages that expired before they were routed,
+            // once rather than for each subscriber.
+            if Expiry::is_expired(&message) {
+                session
+                    .metrics
+                    .increment("expired_messages_total{stage=\"dequeue\"}");
+                continue;
+            }
+
+            if !session.credit.allows(&message) {
+                session.held_message = Some(message);
                 break;
             }
+
+            // Credit is granted in uncompressed bytes.
+            session.credit.consume(&message);
+            session.compression.apply(&mut message)?;
+            if session.frame_checksums {
+                message.add_checksum();
+            }
+            let first_chunk = chunks.len();
+            message.encode(&mut chunks)?;
+            chunks_size += chunks[first_chunk..].iter().map(Bytes::len).sum::<usize>();
+            if chunks_size >= WRITE_BUFFER_SIZE {
+                message::write_all_vectored(&mut session.stream, std::mem::take(&mut chunks))?;
+                chunks_size = 0;
+            }
+        }
+
+        if !chunks.is_empty() {
+            message::write_all_vectored(&mut session.stream, chunks)?;
+        }
+
+        Ok(())
+    }
+
+    fn enqueue(&self, message: Message) -> error::Result<()> {
+        self.message_sender.send(message)?;
+        Ok(())
+    }
+}
+
+impl Subscriber for SubscriberHandler {
+    fn publish(&self, message: Message) -> error::Result<()> {
+        self.enqueue(message)?;
+        self.poller.notify()?;
+        Ok(())
+    }
+
+    fn publish_batch(&self, messages: Vec<Message>) -> error::Result<()> {
+        // Wake the handler thread once for the whole batch.
+        for message in messages.into_iter() {
+            self.enqueue(message)?;
         }
+        self.poller.notify()?;
+        Ok(())
+    }
+
+    fn queue_len(&self) -> usize {
+        self.message_sender.queue_len()
     }
 }
 
 impl Drop for SubscriberHandler {
     fn drop(&mut self) {
         if let Some(thread) = self.handler_thread.take() {
-            // Indicate the handler thread that it should terminate.
-            *self.terminate.lock().unwrap() = true;
+            // Indicate the handler thread that it should terminate, waking
+            // it up.
+            self.shutdown.request();
 
             // Join the handler thread.
             thread.join().unwrap();
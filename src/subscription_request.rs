@@ -1,8 +1,11 @@
-use std::io::{Read, Write};
+use std::io::{self, Read, Write};
 
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 
-use crate::error;
+use crate::error::{self, Error};
+
+pub const MAX_TOPICS: u32 = 1024;
+pub const MAX_TOPIC_SIZE: usize = 64 * 1024;
 
 #[derive(Debug)]
 pub struct SubscriptionRequest {
@@ -17,16 +20,32 @@ impl SubscriptionRequest {
     pub fn read(reader: &mut impl Read) -> error::Result<Self> {
         // Read the number of topics.
         let topics_number = reader.read_u32::<BigEndian>()?;
+        if topics_number > MAX_TOPICS {
+            return Err(Error::FrameTooLarge(format!(
+                "{} topics, at most {} are allowed",
+                topics_number, MAX_TOPICS
+            )));
+        }
 
         // Read the topics.
-        let mut topics: Vec<String> = Vec::with_capacity(topics_number as usize);
+        let mut topics: Vec<String> = Vec::new();
         for _ in 0..topics_number {
             // Read the topics's size.
-            let size = reader.read_u32::<BigEndian>()?;
+            let size = reader.read_u32::<BigEndian>()? as usize;
+            if size > MAX_TOPIC_SIZE {
+                return Err(Error::FrameTooLarge(format!(
+                    "topic of {} bytes, at most {} are allowed",
+                    size, MAX_TOPIC_SIZE
+                )));
+            }
 
-            // Read the topic.
-            let mut bytes: Vec<u8> = vec![0; size as usize];
-            reader.read_exact(&mut bytes)?;
+            // Read the topic, letting the buffer grow with the bytes actually
+            // received.
+            let mut bytes: Vec<u8> = Vec::new();
+            reader.take(size as u64).read_to_end(&mut bytes)?;
+            if bytes.len() != size {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            }
 
             // Add the topic to the topics vector.
             topics.push(String::from_utf8(bytes)?);
@@ -0,0 +1,313 @@
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum TopicFilter {
+    Exact(String),
+    Mqtt(String),
+    Glob(String),
+    Nats(String),
+}
+
+impl TopicFilter {
+    pub fn mqtt(filter: &str) -> Option<Self> {
+        let levels: Vec<&str> = filter.split('/').collect();
+
+        // '+' must occupy a whole level, '#' must occupy the whole last level.
+        for (index, level) in levels.iter().enumerate() {
+            let is_last = index == levels.len() - 1;
+            match *level {
+                "+" => {}
+                "#" if is_last => {}
+                level if level.contains(['+', '#']) => return None,
+                _ => {}
+            }
+        }
+
+        if filter.is_empty() {
+            None
+        } else if filter.contains(['+', '#']) {
+            Some(Self::Mqtt(filter.to_owned()))
+        } else {
+            Some(Self::Exact(filter.to_owned()))
+        }
+    }
+
+    pub fn nats(subject: &str) -> Option<Self> {
+        let tokens: Vec<&str> = subject.split('.').collect();
+
+        // Tokens must not be empty, '*' must occupy a whole token and '>'
+        // must occupy the whole last token.
+        for (index, token) in tokens.iter().enumerate() {
+            let is_last = index == tokens.len() - 1;
+            match *token {
+                "" => return None,
+                "*" => {}
+                ">" if is_last => {}
+                token if token.contains(['*', '>']) || token.contains(char::is_whitespace) => {
+                    return None
+                }
+                _ => {}
+            }
+        }
+
+        if tokens.iter().any(|token| "*" == *token || ">" == *token) {
+            Some(Self::Nats(subject.to_owned()))
+        } else {
+            Some(Self::Exact(subject.to_owned()))
+        }
+    }
+
+    pub fn glob(pattern: &str) -> Self {
+        if pattern.contains(['*', '?', '[', '\\']) {
+            Self::Glob(pattern.to_owned())
+        } else {
+            Self::Exact(pattern.to_owned())
+        }
+    }
+
+    pub fn matches(&self, topic: &str) -> bool {
+        match self {
+            Self::Exact(filter) => filter == topic,
+            Self::Mqtt(filter) => Self::matches_mqtt(filter, topic),
+            Self::Nats(subject) => Self::matches_nats(subject, topic),
+            Self::Glob(pattern) => {
+                let pattern: Vec<char> = pattern.chars().collect();
+                let topic: Vec<char> = topic.chars().collect();
+                Self::matches_glob(&pattern, &topic)
+            }
+        }
+    }
+
+    fn matches_mqtt(filter: &str, topic: &str) -> bool {
+        // Wildcards at the first level never match system topics.
+        if topic.starts_with('$') && filter.starts_with(['+', '#']) {
+            return false;
+        }
+
+        let mut topic_levels = topic.split('/');
+        for filter_level in filter.split('/') {
+            match (filter_level, topic_levels.next()) {
+                ("#", _) => return true,
+                ("+", Some(_)) => {}
+                (filter_level, Some(topic_level)) if filter_level == topic_level => {}
+                _ => return false,
+            }
+        }
+
+        topic_levels.next().is_none()
+    }
+
+    fn matches_nats(subject: &str, topic: &str) -> bool {
+        let mut topic_tokens = topic.split('.');
+        for subject_token in subject.split('.') {
+            match (subject_token, topic_tokens.next()) {
+                // '>' matches one or more remaining tokens.
+                (">", Some(_)) => return true,
+                ("*", Some(_)) => {}
+                (subject_token, Some(topic_token)) if subject_token == topic_token => {}
+                _ => return false,
+            }
+        }
+
+        topic_tokens.next().is_none()
+    }
+
+    // Matches Redis-style glob patterns: '*', '?', '[...]' classes with
+    // ranges and '^' negation, and '\\' escapes. Every other element matches
+    // a single character, so when an element fails it's enough to let the
+    // last '*' absorb one more character, which keeps matching linear in
+    // the pattern for each topic character.
+    fn matches_glob(pattern: &[char], topic: &[char]) -> bool {
+        let (mut pattern_index, mut topic_index) = (0, 0);
+        // The pattern index following the last '*', and the topic index it
+        // absorbs characters up to.
+        let mut star: Option<(usize, usize)> = None;
+        while topic_index < topic.len() {
+            if Some(&'*') == pattern.get(pattern_index) {
+                pattern_index += 1;
+                star = Some((pattern_index, topic_index));
+                continue;
+            }
+
+            match Self::match_element(&pattern[pattern_index..], topic[topic_index]) {
+                Some(length) => {
+                    pattern_index += length;
+                    topic_index += 1;
+                }
+                None => match star {
+                    Some((star_pattern_index, star_topic_index)) => {
+                        pattern_index = star_pattern_index;
+                        topic_index = star_topic_index + 1;
+                        star = Some((star_pattern_index, topic_index));
+                    }
+                    None => return false,
+                },
+            }
+        }
+
+        pattern[pattern_index..]
+            .iter()
+            .all(|character| '*' == *character)
+    }
+
+    // Returns the length of the pattern's first element if it matches the
+    // character.
+    fn match_element(pattern: &[char], character: char) -> Option<usize> {
+        match pattern.first()? {
+            '?' => Some(1),
+            '[' => match Self::match_class(&pattern[1..]) {
+                Some((class, rest)) => {
+                    let length = pattern.len() - rest.len();
+                    class(character).then_some(length)
+                }
+                // An unterminated class matches a literal '['.
+                None => ('[' == character).then_some(1),
+            },
+            '\\' if pattern.len() > 1 => (pattern[1] == character).then_some(2),
+            literal => (*literal == character).then_some(1),
+        }
+    }
+
+    // Parses a character class following a '[', returning a predicate for
+    // the class and the remaining pattern.
+    fn match_class(pattern: &[char]) -> Option<(impl Fn(char) -> bool + '_, &[char])> {
+        let (negated, start) = match pattern.first() {
+            Some('^') => (true, 1),
+            _ => (false, 0),
+        };
+
+        let end = start
+            + pattern[start..]
+                .iter()
+                .position(|character| ']' == *character)?;
+        let class = &pattern[start..end];
+
+        let predicate = move |character: char| {
+            let mut index = 0;
+            let mut matched = false;
+            while index < class.len() {
+                if index + 2 < class.len() && '-' == class[index + 1] {
+                    let (low, high) = (class[index], class[index + 2]);
+                    matched |= low.min(high) <= character && character <= low.max(high);
+                    index += 3;
+                } else {
+                    matched |= class[index] == character;
+                    index += 1;
+                }
+            }
+
+            matched != negated
+        };
+
+        Some((predicate, &pattern[end + 1..]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob_star_matches_across_levels() {
+        let filter = TopicFilter::glob("sensors/*");
+        assert!(filter.matches("sensors/a"));
+        assert!(filter.matches("sensors/a/b"));
+        assert!(filter.matches("sensors/"));
+        assert!(!filter.matches("sensors"));
+    }
+
+    #[test]
+    fn glob_elements_match_single_characters() {
+        assert!(TopicFilter::glob("h?llo").matches("hello"));
+        assert!(!TopicFilter::glob("h?llo").matches("hllo"));
+        assert!(TopicFilter::glob("h[a-e]llo").matches("hello"));
+        assert!(!TopicFilter::glob("h[^e]llo").matches("hello"));
+        assert!(TopicFilter::glob("a\\*").matches("a*"));
+        assert!(!TopicFilter::glob("a\\*").matches("ab"));
+        assert!(TopicFilter::glob("a[b").matches("a[b"));
+    }
+
+    #[test]
+    fn glob_backtracks_to_the_last_star() {
+        let filter = TopicFilter::glob("*a*b");
+        assert!(filter.matches("xaxxb"));
+        assert!(filter.matches("ab"));
+        assert!(!filter.matches("xaxxbx"));
+        assert!(TopicFilter::glob("**").matches(""));
+    }
+
+    #[test]
+    fn glob_without_wildcards_is_exact() {
+        assert_eq!(
+            TopicFilter::glob("a/b"),
+            TopicFilter::Exact("a/b".to_owned())
+        );
+    }
+
+    #[test]
+    fn mqtt_single_level_wildcard_matches_one_level() {
+        let filter = TopicFilter::mqtt("a/+/c").unwrap();
+        assert!(filter.matches("a/b/c"));
+        assert!(filter.matches("a//c"));
+        assert!(!filter.matches("a/b/x/c"));
+        assert!(!filter.matches("a/c"));
+    }
+
+    #[test]
+    fn mqtt_multi_level_wildcard_matches_the_parent_and_below() {
+        let filter = TopicFilter::mqtt("a/#").unwrap();
+        assert!(filter.matches("a"));
+        assert!(filter.matches("a/b"));
+        assert!(filter.matches("a/b/c"));
+        assert!(!filter.matches("ab"));
+    }
+
+    #[test]
+    fn mqtt_empty_levels_are_levels() {
+        let filter = TopicFilter::mqtt("+/+").unwrap();
+        assert!(filter.matches("/"));
+        assert!(filter.matches("a/"));
+        assert!(!filter.matches("a"));
+        assert!(TopicFilter::mqtt("/a").unwrap().matches("/a"));
+        assert!(!TopicFilter::mqtt("/a").unwrap().matches("a"));
+    }
+
+    #[test]
+    fn mqtt_rejects_misplaced_wildcards() {
+        assert_eq!(None, TopicFilter::mqtt(""));
+        assert_eq!(None, TopicFilter::mqtt("a/#/b"));
+        assert_eq!(None, TopicFilter::mqtt("a+/b"));
+        assert_eq!(None, TopicFilter::mqtt("a/b#"));
+    }
+
+    #[test]
+    fn mqtt_leading_wildcards_skip_reserved_topics() {
+        assert!(!TopicFilter::mqtt("#").unwrap().matches("$SYS/uptime"));
+        assert!(!TopicFilter::mqtt("+/uptime")
+            .unwrap()
+            .matches("$SYS/uptime"));
+        assert!(TopicFilter::mqtt("$SYS/#").unwrap().matches("$SYS/uptime"));
+        assert!(TopicFilter::mqtt("$SYS/+").unwrap().matches("$SYS/uptime"));
+    }
+
+    #[test]
+    fn nats_wildcards_match_tokens() {
+        let filter = TopicFilter::nats("a.*.c").unwrap();
+        assert!(filter.matches("a.b.c"));
+        assert!(!filter.matches("a.b.b.c"));
+
+        // '>' needs at least one token, unlike MQTT's '#'.
+        let filter = TopicFilter::nats("a.>").unwrap();
+        assert!(filter.matches("a.b"));
+        assert!(filter.matches("a.b.c"));
+        assert!(!filter.matches("a"));
+    }
+
+    #[test]
+    fn nats_rejects_empty_tokens_and_misplaced_wildcards() {
+        assert_eq!(None, TopicFilter::nats(""));
+        assert_eq!(None, TopicFilter::nats("a..b"));
+        assert_eq!(None, TopicFilter::nats("a."));
+        assert_eq!(None, TopicFilter::nats("a.>.b"));
+        assert_eq!(None, TopicFilter::nats("a.b*"));
+        assert_eq!(None, TopicFilter::nats("a b"));
+    }
+}
@@ -0,0 +1,123 @@
+use std::io::Write;
+
+use crossbeam::channel::Sender;
+
+use crate::admission::ERROR_TOPIC;
+use crate::error::{self, Error};
+use crate::event::Event;
+use crate::message::Message;
+
+pub const BEGIN_TOPIC: &str = "$begin";
+pub const COMMIT_TOPIC: &str = "$commit";
+pub const ABORT_TOPIC: &str = "$abort";
+
+// Bounds the messages a transaction may buffer until it's committed.
+#[derive(Clone, Copy, Debug)]
+pub struct TransactionLimits {
+    pub max_messages: usize,
+    pub max_bytes: usize,
+}
+
+// Buffers the messages a publisher sends between a BEGIN and a COMMIT, so
+// that the PubSub routes all of them at once, or none of them if the
+// publisher aborts or disconnects.
+#[derive(Debug)]
+pub struct Transaction {
+    messages: Option<Vec<Message>>,
+    buffered_bytes: usize,
+    limits: TransactionLimits,
+    // Set once a message of the transaction was rejected.
+    rolled_back: bool,
+}
+
+impl Transaction {
+    pub fn new(limits: TransactionLimits) -> Self {
+        Self {
+            messages: None,
+            buffered_bytes: 0,
+            limits,
+            rolled_back: false,
+        }
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.messages.is_some()
+    }
+
+    // Buffers the message until the commit, rolling the transaction back if
+    // it would exceed its limits.
+    pub fn buffer(&mut self, message: Message) -> error::Result<()> {
+        // Rolled back transactions only wait for their end.
+        if self.rolled_back {
+            return Ok(());
+        }
+
+        if let Some(messages) = self.messages.as_mut() {
+            if messages.len() >= self.limits.max_messages
+                || self.buffered_bytes + message.data.len() > self.limits.max_bytes
+            {
+                self.roll_back();
+                return Err(Error::TransactionTooLarge(format!(
+                    "at most {} messages and {} bytes are allowed",
+                    self.limits.max_messages, self.limits.max_bytes
+                )));
+            }
+            self.buffered_bytes += message.data.len();
+            messages.push(message);
+        }
+
+        Ok(())
+    }
+
+    // Discards the messages buffered so far, and makes the commit fail.
+    pub fn roll_back(&mut self) {
+        self.rolled_back = true;
+        self.buffered_bytes = 0;
+        if let Some(messages) = self.messages.as_mut() {
+            messages.clear();
+        }
+    }
+
+    // Returns whether the message was a transaction control frame.
+    pub fn handle_control_message(
+        &mut self,
+        message: &Message,
+        writer: &mut impl Write,
+        event_sender: &Sender<Event>,
+    ) -> error::Result<bool> {
+        match &*message.topic {
+            BEGIN_TOPIC => {
+                if self.is_open() {
+                    Self::write_error(writer, "transaction already in progress")?;
+                } else {
+                    self.messages = Some(Vec::new());
+                    self.buffered_bytes = 0;
+                    self.rolled_back = false;
+                }
+            }
+            COMMIT_TOPIC => match self.messages.take() {
+                Some(_) if self.rolled_back => {
+                    Self::write_error(writer, "transaction rolled back")?;
+                }
+                Some(messages) => {
+                    if !messages.is_empty() {
+                        event_sender.send(Event::PublishBatch(messages))?;
+                    }
+                }
+                None => Self::write_error(writer, "no transaction in progress")?,
+            },
+            ABORT_TOPIC => {
+                if self.messages.take().is_none() {
+                    Self::write_error(writer, "no transaction in progress")?;
+                }
+            }
+            _ => return Ok(false),
+        }
+
+        Ok(true)
+    }
+
+    fn write_error(writer: &mut impl Write, error: &str) -> error::Result<()> {
+        Message::new(ERROR_TOPIC.to_owned(), error.as_bytes().to_vec()).write(writer)
+    }
+}